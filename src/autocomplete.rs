@@ -0,0 +1,144 @@
+use poise::serenity_prelude as serenity;
+use crate::world::Permissions;
+use crate::dnd5e::{DND5EItem, CHECKS};
+use crate::gm::has_gm_role;
+use crate::store::own_actor;
+use crate::tokens::{active_scene, token_label};
+use crate::{get_world, Context};
+
+/// Discord refuses to display more choices than this
+const MAX_CHOICES: usize = 25;
+
+/// Scores how well a candidate matches what the user has typed so far. Lower is better, None is no match.
+/// Prefixes beat substrings, which beat scattered subsequences
+pub fn fuzzy_score(candidate: &str, partial: &str) -> Option<usize> {
+    let candidate = candidate.to_lowercase();
+    let partial = partial.to_lowercase();
+    if candidate.starts_with(&partial) {
+        return Some(0);
+    }
+    if let Some(index) = candidate.find(&partial) {
+        return Some(1 + index);
+    }
+
+    // Every character of partial must appear in order. Penalize by how spread out they are
+    let mut gaps = 0;
+    let mut chars = candidate.chars();
+    for wanted in partial.chars() {
+        loop {
+            match chars.next() {
+                Some(c) if c == wanted => break,
+                Some(_) => gaps += 1,
+                None => return None,
+            }
+        }
+    }
+    Some(candidate.len() + gaps)
+}
+
+/// Sorts candidates by how well they match partial, dropping those that don't match at all
+pub fn fuzzy_filter<T, F>(candidates: impl IntoIterator<Item = T>, partial: &str, key: F) -> Vec<T>
+where
+    F: Fn(&T) -> &str,
+{
+    let mut scored: Vec<(usize, T)> = candidates.into_iter()
+        .filter_map(|candidate| fuzzy_score(key(&candidate), partial).map(|score| (score, candidate)))
+        .collect();
+    scored.sort_by_key(|(score, _)| *score);
+    scored.into_iter().take(MAX_CHOICES).map(|(_, candidate)| candidate).collect()
+}
+
+/// Suggests the names of actors that our foundry user owns
+pub async fn autocomplete_actor(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let foundry = &ctx.data().foundry;
+    let Ok(world) = get_world(foundry).await else { return vec![] };
+    let Some(user) = world.user(foundry.user_id()) else { return vec![] };
+
+    let names = world.actors.iter()
+        .filter_map(|actor| actor.base())
+        .filter(|base| base.document.ownership.level(user) >= Permissions::Owner)
+        .map(|base| base.document.name.clone());
+    fuzzy_filter(names, partial, |name| name.as_str())
+}
+
+/// Suggests the abilities, skills and tools that can be rolled, and passive scores for skills. Homebrew skills and tools
+/// come from the actor the command acts as, and only the standard checks are offered without one
+pub async fn autocomplete_stat(ctx: Context<'_>, partial: &str) -> Vec<serenity::AutocompleteChoice> {
+    let mut checks: Vec<(String, String)> = CHECKS.iter().map(|(key, label)| (key.to_string(), label.to_string())).collect();
+    if let Ok(world) = get_world(&ctx.data().foundry).await {
+        if let Ok((actor, _)) = own_actor(ctx, &world, as_actor(ctx).as_deref()).await {
+            checks = actor.checks();
+        }
+    }
+    // Abilities have no passive score, nor do tools
+    let passives: Vec<(String, String)> = checks.iter()
+        .filter(|(key, _)| !key.starts_with("tool:") && !["str", "dex", "con", "int", "wis", "cha"].contains(&key.as_str()))
+        .map(|(key, label)| (format!("passive:{}", key), format!("Passive {}", label)))
+        .collect();
+    checks.extend(passives);
+
+    fuzzy_filter(checks, partial, |check| check.1.as_str())
+        .into_iter()
+        .map(|(key, label)| serenity::AutocompleteChoice::new(label, key))
+        .collect()
+}
+
+/// Suggests scene names
+pub async fn autocomplete_scene(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let Ok(world) = get_world(&ctx.data().foundry).await else { return vec![] };
+    let names = world.scenes.iter().map(|scene| scene.document.name.clone());
+    fuzzy_filter(names, partial, |name| name.as_str())
+}
+
+/// Suggests tokens on the active scene, leaving out hidden ones for anyone but GMs. Choices carry the token's id, since several tokens may share a name
+pub async fn autocomplete_token(ctx: Context<'_>, partial: &str) -> Vec<serenity::AutocompleteChoice> {
+    let Ok(world) = get_world(&ctx.data().foundry).await else { return vec![] };
+    let Ok(scene) = active_scene(&world) else { return vec![] };
+    let show_hidden = has_gm_role(ctx).await;
+    let tokens = scene.tokens.iter()
+        .filter(|token| show_hidden || !token.base.hidden)
+        .filter_map(|token| Some((token_label(scene, token), token.base.id.clone()?)));
+    fuzzy_filter(tokens, partial, |(label, _)| label.as_str())
+        .into_iter()
+        .map(|(label, id)| serenity::AutocompleteChoice::new(label, id))
+        .collect()
+}
+
+/// The nickname entered for the command's `as` argument so far, if any, so suggestions come from the actor it acts as
+fn as_actor(ctx: Context<'_>) -> Option<String> {
+    match ctx {
+        poise::Context::Application(app) => app.args.iter()
+            .find(|arg| arg.name == "as")
+            .and_then(|arg| match arg.value {
+                serenity::ResolvedValue::String(nickname) => Some(nickname.to_owned()),
+                _ => None,
+            }),
+        _ => None,
+    }
+}
+
+/// Suggests the names of the items that pass a filter, of the actor the command acts as
+async fn autocomplete_owned_item(ctx: Context<'_>, partial: &str, filter: fn(&DND5EItem) -> bool) -> Vec<String> {
+    let Ok(world) = get_world(&ctx.data().foundry).await else { return vec![] };
+    let Ok((actor, _)) = own_actor(ctx, &world, as_actor(ctx).as_deref()).await else { return vec![] };
+    let names = actor.base().into_iter()
+        .flat_map(|base| &base.items)
+        .filter(|item| filter(item))
+        .filter_map(|item| item.base().map(|base| base.document.name.clone()));
+    fuzzy_filter(names, partial, |name| name.as_str())
+}
+
+/// Suggests the weapons of the actor the command acts as
+pub async fn autocomplete_weapon(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    autocomplete_owned_item(ctx, partial, |item| matches!(item, DND5EItem::weapon { .. })).await
+}
+
+/// Suggests the items the actor the command acts as carries
+pub async fn autocomplete_carried(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    autocomplete_owned_item(ctx, partial, |item| item.physical().is_some()).await
+}
+
+/// Suggests the consumables of the actor the command acts as
+pub async fn autocomplete_consumable(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    autocomplete_owned_item(ctx, partial, |item| matches!(item, DND5EItem::consumable { .. })).await
+}
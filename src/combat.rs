@@ -0,0 +1,239 @@
+use poise::serenity_prelude as serenity;
+use serde_json::{json, Value};
+use crate::autocomplete::{autocomplete_token, autocomplete_weapon};
+use crate::dice::{Roll, Term};
+use crate::dnd5e::{properties, DND5EActor, DND5EItem};
+use crate::error::{CommandError, DiceError};
+use crate::gm::{audit, has_gm_role};
+use crate::rolls::{add_bonus, formula_at, render_roll, RollMode};
+use crate::saves::concentration_prompt;
+use crate::store::{own_actor, Settings};
+use crate::tokens::{active_scene, damage_changes, find_token, token_actor, token_label};
+use crate::{autocomplete_nickname, get_world, Context, DiscordError};
+
+fn ability_mod(data: &Value, ability: &str) -> i64 {
+    data.pointer(&format!("/abilities/{}/mod", ability)).and_then(Value::as_i64).unwrap_or(0)
+}
+
+/// Whether a weapon is used at range. 3.x and older say so in the action type, 4.x only through the kind of weapon
+fn ranged(system: &Value) -> bool {
+    let kind = system.pointer("/type/value")
+        .or(system.get("weaponType"))
+        .and_then(Value::as_str)
+        .unwrap_or("");
+    system.get("actionType").and_then(Value::as_str) == Some("rwak") || kind.ends_with('R')
+}
+
+/// The key actor bonuses to a weapon attack are kept under
+fn attack_kind(system: &Value) -> &'static str {
+    if ranged(system) { "rwak" } else { "mwak" }
+}
+
+/// The ability a weapon attacks with. Unless the weapon names one, finesse weapons use the better of strength and
+/// dexterity, ranged weapons dexterity and everything else strength
+fn attack_ability(data: &Value, system: &Value) -> String {
+    if let Some(ability) = system.get("ability").and_then(Value::as_str).filter(|ability| !ability.is_empty()) {
+        return ability.to_owned();
+    }
+    let ability = if properties(system).iter().any(|property| property == "fin") {
+        if ability_mod(data, "dex") > ability_mod(data, "str") { "dex" } else { "str" }
+    } else if ranged(system) {
+        "dex"
+    } else {
+        "str"
+    };
+    ability.to_owned()
+}
+
+/// Whether the attacker adds their proficiency bonus. Foundry works unset proficiency out from the actor's weapon
+/// proficiencies, which we don't read, so that counts as proficient
+fn proficient(system: &Value) -> bool {
+    match system.get("proficient") {
+        Some(Value::Bool(proficient)) => *proficient,
+        Some(Value::Number(level)) => level.as_f64() != Some(0.0),
+        _ => true,
+    }
+}
+
+/// Roll to hit with a weapon. Data is the attacker's roll data with @mod set to the attack ability's modifier
+fn attack_roll(actor: &DND5EActor, data: &Value, system: &Value, mode: RollMode) -> Result<Roll, DiceError> {
+    let mut roll = Roll::default()
+        .dice(mode.d20())
+        .modifier("Ability modifier", data["mod"].as_i64().unwrap_or(0));
+    if proficient(system) {
+        roll = roll.modifier("Proficiency", actor.proficiency() as i64);
+    }
+    if let Some(bonus) = system.get("magicalBonus").and_then(Value::as_i64).filter(|bonus| *bonus != 0) {
+        roll = roll.modifier("Magical bonus", bonus);
+    }
+    add_bonus(&mut roll, formula_at(system, "/attackBonus"), data)?;
+    add_bonus(&mut roll, formula_at(data, &format!("/bonuses/{}/attack", attack_kind(system))), data)?;
+    Ok(roll.evaluate())
+}
+
+/// A weapon's damage as (formula, damage type) parts, from either 3.x damage parts or 4.x base damage
+fn damage_parts(system: &Value) -> Vec<(String, String)> {
+    if let Some(parts) = system.pointer("/damage/parts").and_then(Value::as_array) {
+        return parts.iter()
+            .filter_map(|part| {
+                let formula = part.get(0)?.as_str()?.to_owned();
+                let kind = part.get(1).and_then(Value::as_str).unwrap_or_default().to_owned();
+                Some((formula, kind))
+            })
+            .collect();
+    }
+
+    let Some(base) = system.pointer("/damage/base") else { return vec![] };
+    let kind = base.pointer("/types/0").and_then(Value::as_str).unwrap_or_default().to_owned();
+    if base.pointer("/custom/enabled").and_then(Value::as_bool) == Some(true) {
+        return formula_at(base, "/custom/formula").map(|formula| (formula, kind)).into_iter().collect();
+    }
+    let (Some(number), Some(denomination)) = (base.get("number").and_then(Value::as_u64), base.get("denomination").and_then(Value::as_u64)) else {
+        return vec![];
+    };
+    // 4.x adds the ability modifier to base damage itself
+    let mut formula = format!("{}d{} + @mod", number, denomination);
+    if let Some(bonus) = formula_at(base, "/bonus") {
+        formula = format!("{} + {}", formula, bonus);
+    }
+    vec![(formula, kind)]
+}
+
+/// Adjust damage of a type for the target's immunities, resistances and vulnerabilities. Returns the damage taken and how it was adjusted
+fn adjust_damage(target: &Value, kind: &str, amount: i64) -> (i64, Option<&'static str>) {
+    let has = |trait_key: &str| target.pointer(&format!("/traits/{}/value", trait_key))
+        .and_then(Value::as_array)
+        .is_some_and(|types| types.iter().any(|candidate| candidate.as_str() == Some(kind)));
+    if kind.is_empty() {
+        return (amount, None);
+    }
+    if has("di") {
+        return (0, Some("immune"));
+    }
+    // Resistance and vulnerability to the same type cancel out
+    match (has("dr"), has("dv")) {
+        (true, false) => (amount / 2, Some("resisted")),
+        (false, true) => (amount * 2, Some("vulnerable")),
+        _ => (amount, None),
+    }
+}
+
+/// Roll damage, doubling the dice of a critical hit. Returns every term rolled, for display, the damage the target
+/// takes once its resistances are applied, and notes on what they changed
+fn roll_damage(parts: &[(String, String)], data: &Value, target: &Value, critical: bool) -> Result<(Roll, i64, Vec<String>), DiceError> {
+    let mut rolled = Roll::default();
+    let mut dealt = 0;
+    let mut notes = vec![];
+    for (formula, kind) in parts {
+        let mut part = Roll::parse(formula, Some(data))?;
+        for term in &mut part.terms {
+            if let Term::Dice(dice) = term {
+                if critical {
+                    dice.count *= 2;
+                }
+                if !kind.is_empty() {
+                    dice.flavor = Some(kind.clone());
+                }
+            }
+        }
+        let part = part.evaluate();
+        let (amount, adjustment) = adjust_damage(target, kind, part.total().max(0));
+        if let Some(adjustment) = adjustment {
+            notes.push(format!("{} {}", kind, adjustment));
+        }
+        dealt += amount;
+        rolled.terms.extend(part.terms);
+    }
+    Ok((rolled, dealt, notes))
+}
+
+/// Attacks a token on the active scene with one of your weapons
+#[poise::command(slash_command)]
+pub async fn attack(
+    ctx: Context<'_>,
+    #[description = "Weapon"]
+    #[autocomplete = "autocomplete_weapon"]
+    weapon: String,
+    #[description = "Token to attack"]
+    #[autocomplete = "autocomplete_token"]
+    target: String,
+    #[description = "Roll with advantage or disadvantage"] mode: Option<RollMode>,
+    #[description = "Nickname of the actor to attack as, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let (actor, _) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    let settings = Settings::load(&*ctx.data().store.lock().await);
+    let actor_name = actor.base().map_or("", |base| base.document.name.as_str());
+    let (weapon_name, system) = actor.base().into_iter()
+        .flat_map(|base| &base.items)
+        .find_map(|item| match item {
+            DND5EItem::weapon { base, system } if base.document.name.eq_ignore_ascii_case(&weapon) => Some((base.document.name.as_str(), &system.other)),
+            _ => None,
+        })
+        .ok_or(CommandError::WeaponNotFound(weapon.clone()))?;
+
+    let scene = active_scene(&world)?;
+    let found = find_token(scene, &target)?;
+    // Players can't aim at what they can't see
+    if found.base.hidden && !has_gm_role(ctx).await {
+        Err(CommandError::TokenNotFound(target.clone()))?;
+    }
+    let label = token_label(scene, found);
+    let target_actor = token_actor(&world, found).ok_or(CommandError::TokenWithoutActor(label.clone()))?;
+
+    let mut data = actor.roll_data();
+    data["mod"] = json!(ability_mod(&data, &attack_ability(&data, system)));
+    let attack = attack_roll(actor, &data, system, mode.unwrap_or_default())?;
+
+    // Natural 20s always hit and natural 1s always miss, whatever the armor class
+    let armor_class = target_actor.armor_class();
+    let natural = attack.natural_d20();
+    let hit = match natural {
+        Some(20) => Some(true),
+        Some(1) => Some(false),
+        _ => armor_class.map(|ac| attack.total() >= ac),
+    };
+    let outcome = match (hit, armor_class) {
+        (Some(true), _) if natural == Some(20) => "Critical hit!".to_owned(),
+        (Some(true), Some(ac)) => format!("Hit against AC {}", ac),
+        (Some(false), Some(ac)) => format!("Miss against AC {}", ac),
+        (Some(true), None) => "Hit".to_owned(),
+        (Some(false), None) => "Miss".to_owned(),
+        (None, _) => format!("{}'s armor class couldn't be worked out", label),
+    };
+    let title = format!("{} attacks {} with {}", actor_name, label, weapon_name);
+    let mut reply = poise::CreateReply::default().embed(render_roll(&title, &attack).field("Result", outcome, false));
+
+    // Damage is still rolled when we can't tell whether the attack hit, so the table can decide
+    let mut parts = damage_parts(system);
+    if let (Some(bonus), Some((_, kind))) = (formula_at(&data, &format!("/bonuses/{}/damage", attack_kind(system))), parts.first()) {
+        parts.push((bonus, kind.clone()));
+    }
+    let mut applied = None;
+    if hit != Some(false) && !parts.is_empty() {
+        let (damage, dealt, notes) = roll_damage(&parts, &data, &target_actor.roll_data(), natural == Some(20))?;
+        let mut embed = render_roll(&format!("{} damage", weapon_name), &damage);
+        if !notes.is_empty() {
+            embed = embed.field("Dealt", format!("**{}** ({})", dealt, notes.join(", ")), false);
+        }
+
+        if hit == Some(true) && settings.auto_apply_damage && dealt > 0 {
+            let (changes, hp) = damage_changes(target_actor, dealt);
+            let scene_id = scene.document.id.as_deref().unwrap_or_default();
+            foundry.update_token_actor(scene_id, &found.base, changes).await?;
+            audit(ctx, format!("hit {} ({}) with {} for {} damage", label, found.base.id.as_deref().unwrap_or_default(), weapon_name, dealt)).await;
+            embed = embed.footer(serenity::CreateEmbedFooter::new(format!("{} damage applied to {}", dealt, label)));
+            applied = Some((dealt, hp));
+        }
+        reply = reply.embed(embed);
+    }
+    ctx.send(reply).await?;
+    match applied {
+        Some((dealt, hp)) => concentration_prompt(ctx, &label, target_actor, dealt, hp).await,
+        None => Ok(()),
+    }
+}
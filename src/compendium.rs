@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use poise::serenity_prelude as serenity;
+use poise::ChoiceParameter;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use crate::autocomplete::fuzzy_filter;
+use crate::connection::FoundryClient;
+use crate::dnd5e::{describe, Description, DND5EWorld};
+use crate::error::{CommandError, FoundryClientError};
+use crate::markdown::html_to_markdown;
+use crate::world::CompendiumPack;
+use crate::{get_world, Context, DiscordError};
+
+/// How long a pack's index is trusted before being fetched again
+const INDEX_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Embed descriptions are cut off after this many characters
+const DESCRIPTION_LENGTH: usize = 3500;
+
+/// One document listed in a pack's index
+#[derive(Clone)]
+pub struct IndexEntry {
+    pub id: String,
+    pub name: String,
+    /// The document's subtype, e.g. spell or npc
+    pub kind: String,
+    pub pack: String,
+    pub document_type: String,
+}
+
+/// Indexes of compendium packs, fetched as needed
+#[derive(Default)]
+pub struct CompendiumCache {
+    indexes: Mutex<HashMap<String, (Instant, Vec<IndexEntry>)>>,
+}
+
+impl CompendiumCache {
+    /// The index of a pack, from the cache if it is fresh enough
+    pub async fn index(&self, foundry: &FoundryClient, pack: &CompendiumPack) -> Result<Vec<IndexEntry>, FoundryClientError> {
+        let collection = pack.collection();
+        if let Some((fetched, entries)) = self.indexes.lock().await.get(&collection) {
+            if fetched.elapsed() < INDEX_LIFETIME {
+                return Ok(entries.clone());
+            }
+        }
+
+        let raw = foundry.get_documents(&pack.document_type, Some(&collection), json!({}), Some(&["name", "type"])).await?;
+        let entries: Vec<IndexEntry> = raw.iter().filter_map(|entry| Some(IndexEntry {
+            id: entry.get("_id")?.as_str()?.to_owned(),
+            name: entry.get("name")?.as_str()?.to_owned(),
+            kind: entry.get("type").and_then(Value::as_str).unwrap_or("").to_owned(),
+            pack: collection.clone(),
+            document_type: pack.document_type.clone(),
+        })).collect();
+        self.indexes.lock().await.insert(collection, (Instant::now(), entries.clone()));
+        Ok(entries)
+    }
+}
+
+/// The kinds of thing that can be looked up
+#[derive(poise::ChoiceParameter, Clone, Copy, PartialEq)]
+pub enum LookupKind {
+    Spell,
+    Item,
+    Monster,
+    Feat,
+}
+
+impl LookupKind {
+    fn document_type(self) -> &'static str {
+        match self {
+            LookupKind::Monster => "Actor",
+            _ => "Item",
+        }
+    }
+
+    /// Whether a document subtype counts as this kind
+    fn matches(self, kind: &str) -> bool {
+        match self {
+            LookupKind::Spell => kind == "spell",
+            LookupKind::Monster => kind == "npc",
+            LookupKind::Feat => kind == "feat",
+            LookupKind::Item => matches!(kind,
+                "weapon" | "equipment" | "consumable" | "tool" | "loot" | "container" | "backpack"),
+        }
+    }
+}
+
+/// Every index entry of a kind, across all packs of the world
+async fn entries_of_kind(ctx: Context<'_>, world: &DND5EWorld, kind: LookupKind) -> Result<Vec<IndexEntry>, DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let mut entries = vec![];
+    for pack in world.packs.iter().filter(|pack| pack.document_type == kind.document_type()) {
+        let index = ctx.data().compendium.index(foundry, pack).await?;
+        entries.extend(index.into_iter().filter(|entry| kind.matches(&entry.kind)));
+    }
+    Ok(entries)
+}
+
+/// Suggests names of the kind being looked up
+async fn autocomplete_lookup(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    // The kind argument may not have been filled in yet, in which case everything is fair game
+    let kind = match ctx {
+        poise::Context::Application(app) => app.args.iter()
+            .find(|arg| arg.name == "kind")
+            .and_then(|arg| match arg.value {
+                serenity::ResolvedValue::Integer(index) => LookupKind::from_index(index as usize),
+                _ => None,
+            }),
+        _ => None,
+    };
+    let Ok(world) = get_world(&ctx.data().foundry).await else {
+        return vec![];
+    };
+    let kinds = [LookupKind::Spell, LookupKind::Item, LookupKind::Monster, LookupKind::Feat];
+    let mut names = vec![];
+    for kind in kinds.into_iter().filter(|candidate| kind.is_none_or(|kind| kind == *candidate)) {
+        if let Ok(entries) = entries_of_kind(ctx, &world, kind).await {
+            names.extend(entries.into_iter().map(|entry| entry.name));
+        }
+    }
+    names.sort();
+    names.dedup();
+    fuzzy_filter(names, partial, |name| name.as_str())
+}
+
+/// Lay out a description as an embed
+pub fn description_embed(foundry: &FoundryClient, description: &Description, source: &str) -> serenity::CreateEmbed {
+    let mut embed = serenity::CreateEmbed::new()
+        .title(&description.title)
+        .footer(serenity::CreateEmbedFooter::new(source));
+    let mut text = String::new();
+    if let Some(subtitle) = &description.subtitle {
+        text.push_str(&format!("*{}*\n\n", subtitle));
+    }
+    if let Some(body) = &description.body {
+        text.push_str(&html_to_markdown(body));
+    }
+    if text.chars().count() > DESCRIPTION_LENGTH {
+        text = text.chars().take(DESCRIPTION_LENGTH).collect::<String>() + "…";
+    }
+    embed = embed.description(text);
+    for (name, value) in &description.fields {
+        embed = embed.field(name, value, true);
+    }
+    if let Some(image) = &description.image {
+        embed = embed.thumbnail(foundry.url_for(image));
+    }
+    embed
+}
+
+/// Looks something up in the world's compendiums
+#[poise::command(slash_command)]
+pub async fn lookup(
+    ctx: Context<'_>,
+    #[description = "What kind of thing to look for"] kind: LookupKind,
+    #[description = "Name"]
+    #[autocomplete = "autocomplete_lookup"]
+    name: String,
+) -> Result<(), DiscordError> {
+    ctx.defer().await?;
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let entries = entries_of_kind(ctx, &world, kind).await?;
+    let entry = entries.iter().find(|entry| entry.name.eq_ignore_ascii_case(&name))
+        .or_else(|| fuzzy_filter(entries.iter(), &name, |entry| entry.name.as_str()).into_iter().next())
+        .ok_or(CommandError::LookupNotFound(name.clone()))?;
+
+    let documents = foundry.get_documents(&entry.document_type, Some(&entry.pack), json!({"_id": entry.id}), None).await?;
+    let document = documents.first().ok_or(CommandError::LookupNotFound(name.clone()))?;
+    let embed = description_embed(foundry, &describe(document), &entry.pack);
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
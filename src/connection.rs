@@ -7,11 +7,24 @@ use rust_socketio::{
     Event, Payload, TransportType,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tokio::sync::{mpsc};
-use tokio::time::{sleep, Duration};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, Semaphore};
+use tokio::time::{sleep, timeout, Duration, Instant};
 use url::Url;
 use crate::error::FoundryClientError::FailedInit;
+use crate::version::{self, Versions};
+
+/// How long to wait on plain http requests to the server
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the socket to connect
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a response to a socket request, unless the caller asks otherwise
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many socket requests may be awaiting a response at once. The rest queue up behind them
+const MAX_CONCURRENT_REQUESTS: usize = 8;
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(default)]
@@ -26,63 +39,139 @@ pub struct JoinDataUser {
     pub name: String,
 }
 
-async fn promise_socket_emit(socket: &Client, event: &str, payload: Payload, timeout: Duration) -> Option<Payload> {
-    // Would prefer to use oneshot, but cannot - emit_with_ack takes a FnMut, not a FnOnce, and is therefore incompatible
-    let (tx, mut rx) = mpsc::channel::<Payload>(1);
+async fn promise_socket_emit(socket: &Client, event: &str, payload: Payload, timeout_after: Duration) -> Result<Payload, FoundryClientError> {
+    // emit_with_ack takes a FnMut, not a FnOnce, so the oneshot sender sits in a slot that the first ack takes it from
+    let (tx, rx) = oneshot::channel::<Payload>();
+    let slot = AckSlot(Arc::new(Mutex::new(Some(tx))));
+    let callback_slot = slot.0.clone();
 
     // Send the message
-    // Timeout doesn't work. At least, it doesn't immediately
     socket
         .emit_with_ack(
             event,
             payload,
-            timeout,
+            timeout_after,
             move |payload: Payload, _| {
-                let listener_tx = tx.clone(); // This is the only way I could get this code to compile
+                let tx = callback_slot.lock().ok().and_then(|mut slot| slot.take());
                 async move {
-                    listener_tx.send(payload).await.expect("Failed to send payload");
+                    // If the requester timed out or was dropped nobody is listening, and the late response is discarded
+                    if let Some(tx) = tx {
+                        let _ = tx.send(payload);
+                    }
                 }
                 .boxed()
             },
         )
-        .await
-        .expect("Server unreachable");
+        .await?;
 
     // Await the value that the emit-with-ack has sent
-    let mut elapsed = Duration::from_secs(0);
-    while rx.is_empty() && elapsed < timeout {
-        sleep(Duration::from_millis(100)).await;
-        elapsed += Duration::from_millis(100);
+    timeout(timeout_after, rx)
+        .await
+        .map_err(|_| FoundryClientError::Timeout(event.into()))?
+        .map_err(|_| FoundryClientError::Timeout(event.into()))
+}
+
+/// Where the response to a request is sent. Emptied when the request finishes or the caller stops waiting on it,
+/// rather than when the socket gives up on the ack, so nothing is kept for a request nobody is waiting on
+struct AckSlot(Arc<Mutex<Option<oneshot::Sender<Payload>>>>);
+
+impl Drop for AckSlot {
+    fn drop(&mut self) {
+        if let Ok(mut slot) = self.0.lock() {
+            slot.take();
+        }
+    }
+}
+
+/// A socket that is disconnected when dropped. Otherwise an abandoned client, such as the one replaced after logging in
+/// or one left behind by a failed connection attempt, would stay connected and keep reconnecting in the background
+struct Socket(Client);
+
+impl Deref for Socket {
+    type Target = Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
-    if rx.is_empty() {
-        None
-    } else {
-        rx.recv().await
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let client = self.0.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = client.disconnect().await;
+            });
+        }
+    }
+}
+
+/// Foundry acknowledges requests with a single array argument, whose first entry is the response
+fn first_ack_value(payload: Payload, event: &str) -> Result<Value, FoundryClientError> {
+    match payload {
+        Payload::Text(mut items) if !items.is_empty() => {
+            match items.swap_remove(0) {
+                Value::Array(mut values) if !values.is_empty() => Ok(values.swap_remove(0)),
+                other => Ok(other),
+            }
+        }
+        _ => Err(FoundryClientError::UnexpectedPayload(event.into())),
     }
 }
 
 #[derive(Default)]
 struct FoundryClientBuilder {
+    /// The url of the server
+    host: Option<String>,
     /// Our websocket
-    socket: Option<Client>,
+    socket: Option<Socket>,
     /// Our blocking http client, used for session acquisition & login
     http_client: Option<reqwest::Client>,
     /// The current session
     session_id: Option<String>,
     /// The user id associated with our current session
     user_id: Option<String>,
+    /// The versions of foundry and the game system the server runs
+    versions: Option<Versions>,
 }
 
 /// Essentially the fully built version of the above
 pub struct FoundryClient {
+    /// The url of the server
+    host: String,
     /// Our websocket
-    pub socket: Client,
+    socket: Socket,
     /// Our non blocking http client, used for session acquisition & login
     http_client: reqwest::Client,
     /// The current session
     session_id: String,
     /// The user id associated with our current session
     user_id: String,
+    /// The versions of foundry and the game system the server runs
+    versions: Versions,
+    /// Limits how many requests are in flight at once
+    requests: Semaphore,
+    /// Latency statistics, by event name
+    metrics: Mutex<HashMap<String, EventMetrics>>,
+}
+
+/// Latency statistics for one kind of socket request
+#[derive(Default, Clone, Debug)]
+pub struct EventMetrics {
+    pub requests: u64,
+    pub failures: u64,
+    pub timeouts: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl EventMetrics {
+    pub fn mean_latency(&self) -> Duration {
+        match self.requests {
+            0 => Duration::ZERO,
+            n => self.total_latency / n as u32,
+        }
+    }
 }
 
 impl FoundryClientBuilder {
@@ -90,15 +179,14 @@ impl FoundryClientBuilder {
         // Establish a session
         if let Some(client) = self.http_client.as_mut() {
             let response = client.get(format!("{}/join", host))
+                .timeout(HTTP_TIMEOUT)
                 .send().await
-                .map_err(|err| FoundryClientError::JoinError(err))?;
-            let session = response.cookies().find(|cookie| cookie.name() == "session");
-            self.session_id = Some(
-                session
-                    .expect("UNRECOVERABLE: Could not acquire session")
-                    .value()
-                    .to_string(),
-            );
+                .map_err(FoundryClientError::JoinError)?;
+            let session = response.cookies()
+                .find(|cookie| cookie.name() == "session")
+                .ok_or(FoundryClientError::MissingSession)?;
+            self.session_id = Some(session.value().to_string());
+            self.host = Some(host.trim_end_matches('/').to_owned());
             Ok(self)
         } else {
             Err(FailedInit("http_client must be initialized first".into()))
@@ -106,22 +194,23 @@ impl FoundryClientBuilder {
     }
 
     /// Build a new http client
-    fn build_client(mut self) -> Self {
+    fn build_client(mut self) -> Result<Self, FoundryClientError> {
         // Create our http client
         self.http_client = Some(
             reqwest::ClientBuilder::new()
                 .cookie_store(true)
-                .build()
-                .expect("UNRECOVERABLE: Unable to build HTTP client"),
+                .build()?,
         );
-        self
+        Ok(self)
     }
 
     async fn establish_socket(mut self, host: &str) -> Result<Self, FoundryClientError> {
         // Incorporate it into url
         let session_id = self.session_id.clone().ok_or(FailedInit("Need to acquire a session before establishing a socket".into()))?;
-        let mut session_url = Url::parse(host).map_err(|err| FoundryClientError::URLError(err))?;
-        session_url.path_segments_mut().unwrap().push("socket.io/"); // Technically should be out of here, but this flag is stupid anyway
+        let mut session_url = Url::parse(host).map_err(FoundryClientError::URLError)?;
+        session_url.path_segments_mut()
+            .map_err(|_| FoundryClientError::InvalidHost(host.into()))?
+            .push("socket.io/"); // Technically should be out of here, but this flag is stupid anyway
         session_url
             .query_pairs_mut()
             .append_pair("session", &session_id);
@@ -134,19 +223,22 @@ impl FoundryClientBuilder {
             }
             .boxed()
         };
-        self.socket = Some(
-            ClientBuilder::new(session_url)
-                .opening_header("Cookie", format!("session={}", &session_id))
-                .transport_type(TransportType::Websocket)
-                .reconnect(true)
-                .reconnect_on_disconnect(true)
-                .reconnect_delay(500, 500)
-                .max_reconnect_attempts(10)
-                .on_any(generic_callback)
-                .connect()
+        let connect = ClientBuilder::new(session_url)
+            .opening_header("Cookie", format!("session={}", &session_id))
+            .transport_type(TransportType::Websocket)
+            .reconnect(true)
+            .reconnect_on_disconnect(true)
+            .reconnect_delay(500, 500)
+            .max_reconnect_attempts(10)
+            .on_any(generic_callback)
+            .connect();
+        // Replacing an earlier socket disconnects it
+        self.socket = Some(Socket(
+            timeout(CONNECT_TIMEOUT, connect)
                 .await
-                .map_err(|err| FoundryClientError::SocketError(err))?,
-        );
+                .map_err(|_| FoundryClientError::Timeout("connect".into()))?
+                .map_err(FoundryClientError::from)?,
+        ));
         sleep(Duration::from_secs(1)).await;
         Ok(self)
     }
@@ -163,24 +255,19 @@ impl FoundryClientBuilder {
 
     pub async fn acquire_user_id(mut self, username: &str) -> Result<Self, FoundryClientError> {
         if let Some(socket) = self.socket.as_ref() {
-            let payload = promise_socket_emit(socket, "getJoinData", Payload::Text(vec![]), Duration::from_secs(2)).await;
-            match payload {
-                Some(Payload::Text(items)) => {
-                    let item = &items.get(0).unwrap();
-                    let item = &item.as_array().unwrap()[0];
-                    let data: JoinData = serde_json::from_value(item.clone()).expect("UNRECOVERABLE: getJoinData returned malformed data");
-                    self.user_id = data.users.iter()
-                        .find(|user| user.name == username)
-                        .map(|x| x._id.clone());
-                }
-                _ => panic!("UNRECOVERABLE: getJoinData returned non-json data"),
-            };
+            let payload = promise_socket_emit(socket, "getJoinData", Payload::Text(vec![]), Duration::from_secs(2)).await?;
+            let value = first_ack_value(payload, "getJoinData")?;
+            let data: JoinData = serde_path_to_error::deserialize(&value)
+                .map_err(|err| FoundryClientError::malformed(err, &value))?;
+            self.user_id = data.users.iter()
+                .find(|user| user.name == username)
+                .map(|x| x._id.clone());
         } else {
-            panic!("UNRECOVERABLE: Must initialize socket before acquiring user id")
+            return Err(FailedInit("Must initialize socket before acquiring user id".into()));
         }
 
         println!("Got userid: {:?}", self.user_id);
-        if let None = self.user_id {
+        if self.user_id.is_none() {
             return Err(FoundryClientError::NoUserError(username.into()));
         }
 
@@ -195,37 +282,90 @@ impl FoundryClientBuilder {
                     "action": "join"
                 });
             // let response = client.post("https://echo.free.beeceptor.com")
-            client.post(format!("{}/join", host))
+            let response = client.post(format!("{}/join", host))
                 .json(&payload)
+                .timeout(HTTP_TIMEOUT)
                 .send().await
-                .map_err(|err| FoundryClientError::JoinError(err))?;
+                .map_err(FoundryClientError::JoinError)?;
+
+            // Foundry answers a failed join (usually a wrong password) with an error status and a message explaining why
+            let status = response.status();
+            let body: Value = response.json().await.unwrap_or_default();
+            let succeeded = body.get("status").and_then(Value::as_str).map_or(status.is_success(), |s| s == "success");
+            if !succeeded {
+                let message = body.get("error")
+                    .or(body.get("message"))
+                    .and_then(Value::as_str)
+                    .unwrap_or(status.as_str())
+                    .to_owned();
+                return Err(FoundryClientError::LoginFailed(message));
+            }
+        } else {
+            return Err(FailedInit("http_client must be initialized first".into()));
         }
         Ok(self)
     }
 
+    /// Fetch the world as it is, without checking that we can read its versions
+    pub async fn fetch_world(&self) -> Result<Value, FoundryClientError> {
+        let socket = self.socket.as_ref().ok_or(FailedInit("Must initialize socket before fetching the world".into()))?;
+        let payload = promise_socket_emit(socket, "world", Payload::Text(vec![]), DEFAULT_REQUEST_TIMEOUT).await?;
+        first_ack_value(payload, "world")
+    }
+
+    /// Work out which foundry and system versions we are talking to, refusing any we can't read
+    pub async fn detect_versions(mut self) -> Result<Self, FoundryClientError> {
+        let socket = self.socket.as_ref().ok_or(FailedInit("Must initialize socket before detecting versions".into()))?;
+        let payload = promise_socket_emit(socket, "world", Payload::Text(vec![]), DEFAULT_REQUEST_TIMEOUT).await?;
+        let versions = version::detect(&first_ack_value(payload, "world")?)?;
+        println!("Connected to foundry {} running {}", versions.core, versions.system);
+        self.versions = Some(versions);
+        Ok(self)
+    }
+
     /// Finalize the values in the builder
-    pub fn build(self) -> FoundryClient {
-        FoundryClient {
+    pub fn build(self) -> Result<FoundryClient, FoundryClientError> {
+        Ok(FoundryClient {
+            host: self
+                .host
+                .ok_or(FailedInit("Missing host - be sure to establish_session".into()))?,
             socket: self
                 .socket
-                .expect("Missing socket - be sure to establish_socket"),
+                .ok_or(FailedInit("Missing socket - be sure to establish_socket".into()))?,
             http_client: self
                 .http_client
-                .expect("Missing http client - be sure to build_client"),
+                .ok_or(FailedInit("Missing http client - be sure to build_client".into()))?,
             session_id: self
                 .session_id
-                .expect("Missing session id - be sure to establish_session"),
+                .ok_or(FailedInit("Missing session id - be sure to establish_session".into()))?,
             user_id: self
                 .user_id
-                .expect("Missing user id - be sure to acquire_user_id"),
-        }
+                .ok_or(FailedInit("Missing user id - be sure to acquire_user_id".into()))?,
+            versions: self
+                .versions
+                .ok_or(FailedInit("Missing versions - be sure to detect_versions".into()))?,
+            requests: Semaphore::new(MAX_CONCURRENT_REQUESTS),
+            metrics: Mutex::new(HashMap::new()),
+        })
     }
 }
 
 impl FoundryClient {
-    pub async fn new(host: &str, username: &str, password: &str) -> Result<FoundryClient, Box<dyn std::error::Error>> {
-        let client = FoundryClientBuilder::default()
-            .build_client()
+    pub async fn new(host: &str, username: &str, password: &str) -> Result<FoundryClient, FoundryClientError> {
+        Self::log_in(host, username, password).await?
+            .detect_versions()
+            .await?
+            .build()
+    }
+
+    /// Log in and fetch the raw world, whichever versions it comes from. For diagnosing worlds we may not support
+    pub async fn fetch_raw_world(host: &str, username: &str, password: &str) -> Result<Value, FoundryClientError> {
+        Self::log_in(host, username, password).await?.fetch_world().await
+    }
+
+    async fn log_in(host: &str, username: &str, password: &str) -> Result<FoundryClientBuilder, FoundryClientError> {
+        FoundryClientBuilder::default()
+            .build_client()?
             .establish_session(host)
             .await?
             .establish_socket(host)
@@ -235,13 +375,93 @@ impl FoundryClient {
             .login(host, password)
             .await?
             .establish_socket(host) // RE-establish, now with a logged in session
-            .await?
-            .build();
-        Ok(client)
+            .await
+    }
+
+    /// The id of the foundry user we are logged in as
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    /// The versions of foundry and the game system the server runs
+    pub fn versions(&self) -> &Versions {
+        &self.versions
+    }
+
+    /// Resolve a path foundry gives us (e.g. for an image) into a full url
+    pub fn url_for(&self, path: &str) -> String {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            path.to_owned()
+        } else {
+            format!("{}/{}", self.host, path.trim_start_matches('/'))
+        }
+    }
+
+    /// Fetch a file from the server, such as an image, using our session
+    pub async fn download(&self, path: &str) -> Result<Vec<u8>, FoundryClientError> {
+        let response = self.http_client.get(self.url_for(path))
+            .timeout(HTTP_TIMEOUT)
+            .send().await?
+            .error_for_status()?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    pub async fn emit(&self, event: &str, payload: Payload) -> Result<Payload, FoundryClientError> {
+        self.emit_with_timeout(event, payload, DEFAULT_REQUEST_TIMEOUT).await
+    }
+
+    /// Emit an event and wait for its acknowledgement. The timeout covers time spent queued behind other requests.
+    /// Dropping the returned future cancels the request: its slot is freed and any late response is discarded
+    pub async fn emit_with_timeout(&self, event: &str, payload: Payload, timeout_after: Duration) -> Result<Payload, FoundryClientError> {
+        let started = Instant::now();
+        let result = async {
+            // Held until the request finishes, or until the caller drops it
+            let _permit = timeout(timeout_after, self.requests.acquire())
+                .await
+                .map_err(|_| FoundryClientError::Timeout(event.into()))?
+                .map_err(|_| FoundryClientError::Timeout(event.into()))?;
+            let remaining = timeout_after.saturating_sub(started.elapsed());
+            promise_socket_emit(&self.socket, event, payload, remaining).await
+        }.await;
+        self.record(event, started.elapsed(), &result);
+        result
+    }
+
+    /// Emit an event without waiting for any response
+    pub async fn broadcast(&self, event: &str, payload: Payload) -> Result<(), FoundryClientError> {
+        self.socket.emit(event, payload).await?;
+        Ok(())
+    }
+
+    /// Emit an event and return the value foundry responds with
+    pub async fn request(&self, event: &str, payload: Payload) -> Result<Value, FoundryClientError> {
+        self.request_with_timeout(event, payload, DEFAULT_REQUEST_TIMEOUT).await
+    }
+
+    pub async fn request_with_timeout(&self, event: &str, payload: Payload, timeout_after: Duration) -> Result<Value, FoundryClientError> {
+        let payload = self.emit_with_timeout(event, payload, timeout_after).await?;
+        first_ack_value(payload, event)
+    }
+
+    fn record(&self, event: &str, latency: Duration, result: &Result<Payload, FoundryClientError>) {
+        let Ok(mut metrics) = self.metrics.lock() else { return };
+        let entry = metrics.entry(event.to_owned()).or_default();
+        entry.requests += 1;
+        entry.total_latency += latency;
+        entry.max_latency = entry.max_latency.max(latency);
+        match result {
+            Ok(_) => {}
+            Err(FoundryClientError::Timeout(_)) => entry.timeouts += 1,
+            Err(_) => entry.failures += 1,
+        }
     }
 
-    pub async fn emit(&self, event: &str, payload: Payload) -> Option<Payload> {
-        promise_socket_emit(&self.socket, event, payload, Duration::from_secs(15)).await
+    /// A snapshot of request statistics so far, by event name
+    pub fn metrics(&self) -> Vec<(String, EventMetrics)> {
+        let Ok(metrics) = self.metrics.lock() else { return vec![] };
+        let mut snapshot: Vec<_> = metrics.iter().map(|(event, m)| (event.clone(), m.clone())).collect();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+        snapshot
     }
 }
 
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use serde_json::Value;
+use crate::dnd5e::DND5EWorld;
+use crate::error::FoundryClientError;
+use crate::version;
+use crate::world::Incompatibility;
+
+/// Strip array indices from a path, so the same drifted field across many documents groups together
+fn normalize_path(path: &str) -> String {
+    let mut normalized = String::new();
+    let mut in_index = false;
+    for c in path.chars() {
+        match c {
+            '[' => {
+                in_index = true;
+                normalized.push_str("[]");
+            }
+            ']' => in_index = false,
+            _ if in_index => {}
+            _ => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+fn report_collection(collection: &str, parsed: usize, skipped: &[Incompatibility], summary: &mut BTreeMap<String, usize>) {
+    println!("{}: {} parsed, {} skipped", collection, parsed, skipped.len());
+    for incompatibility in skipped {
+        println!("  - {} ({}) at {}: {}",
+            incompatibility.name.as_deref().unwrap_or("<unnamed>"),
+            incompatibility.id.as_deref().unwrap_or("<no id>"),
+            incompatibility.path,
+            incompatibility.error);
+        *summary.entry(format!("{}.{}", collection, normalize_path(&incompatibility.path))).or_default() += 1;
+    }
+}
+
+/// Validate a raw world against our models, printing every document that does not fit
+/// followed by the failing paths ordered by how many documents they affect
+pub fn diagnose(raw: &Value) {
+    let mut raw = raw.clone();
+    crate::tokens::synthesize_token_actors(&mut raw);
+    match version::detect(&raw) {
+        Ok(versions) => {
+            println!("Foundry {} running {}", versions.core, versions.system);
+            version::adapt_world(&versions, &mut raw);
+        }
+        // Carry on regardless, since the point is to find out what doesn't fit
+        Err(err) => println!("{}", err),
+    }
+
+    let world: DND5EWorld = match serde_path_to_error::deserialize(&raw) {
+        Ok(world) => world,
+        Err(err) => {
+            println!("The world could not be read at all: {}", FoundryClientError::malformed(err, &raw));
+            return;
+        }
+    };
+
+    let mut summary = BTreeMap::new();
+    report_collection("actors", world.actors.len(), &world.actors.skipped, &mut summary);
+    report_collection("items", world.items.len(), &world.items.skipped, &mut summary);
+    report_collection("scenes", world.scenes.len(), &world.scenes.skipped, &mut summary);
+    let token_actors: Vec<_> = world.token_actor_incompatibilities().cloned().collect();
+    let parsed = world.scenes.iter().flat_map(|scene| scene.tokens.iter()).filter(|token| token.actor.valid().is_some()).count();
+    report_collection("token actors", parsed, &token_actors, &mut summary);
+    report_collection("journal", world.journal.len(), &world.journal.skipped, &mut summary);
+    report_collection("tables", world.tables.len(), &world.tables.skipped, &mut summary);
+    report_collection("macros", world.macros.len(), &world.macros.skipped, &mut summary);
+
+    if summary.is_empty() {
+        println!("No incompatibilities found");
+        return;
+    }
+    let mut summary: Vec<_> = summary.into_iter().collect();
+    summary.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    println!("Incompatibilities by path:");
+    for (path, count) in summary {
+        println!("  {:>4} × {}", count, path);
+    }
+}
@@ -0,0 +1,683 @@
+use rand::Rng;
+use serde_json::Value;
+use crate::error::DiceError;
+
+/// Refuse to roll more dice than this in a single pool, including explosions and rerolls
+const MAX_DICE: usize = 200;
+/// Refuse dice with more faces than this
+const MAX_FACES: u32 = 10000;
+
+/// A single die that was rolled
+#[derive(Clone, Debug)]
+pub struct DieResult {
+    pub value: u32,
+    /// Whether this die counts towards the total, or was dropped by a keep/drop modifier
+    pub kept: bool,
+    /// Whether this die was replaced by a reroll
+    pub rerolled: bool,
+    /// Whether this die caused another to be rolled by exploding
+    pub exploded: bool,
+}
+
+impl DieResult {
+    fn new(value: u32) -> Self {
+        DieResult { value, kept: true, rerolled: false, exploded: false }
+    }
+}
+
+/// Which dice of a pool count
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Keep {
+    Highest(u32),
+    Lowest(u32),
+    DropHighest(u32),
+    DropLowest(u32),
+}
+
+/// A comparison against a die's value, as used by rerolls, explosions and success counting
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    Equal(u32),
+    Less(u32),
+    LessOrEqual(u32),
+    Greater(u32),
+    GreaterOrEqual(u32),
+}
+
+impl Condition {
+    pub fn matches(self, value: u32) -> bool {
+        match self {
+            Condition::Equal(target) => value == target,
+            Condition::Less(target) => value < target,
+            Condition::LessOrEqual(target) => value <= target,
+            Condition::Greater(target) => value > target,
+            Condition::GreaterOrEqual(target) => value >= target,
+        }
+    }
+
+    fn formula(self) -> String {
+        match self {
+            Condition::Equal(target) => target.to_string(),
+            Condition::Less(target) => format!("<{}", target),
+            Condition::LessOrEqual(target) => format!("<={}", target),
+            Condition::Greater(target) => format!(">{}", target),
+            Condition::GreaterOrEqual(target) => format!(">={}", target),
+        }
+    }
+}
+
+/// A pool of identical dice, e.g. 2d20kh1
+#[derive(Clone, Debug)]
+pub struct DiceTerm {
+    pub count: u32,
+    pub faces: u32,
+    pub keep: Option<Keep>,
+    /// Reroll dice matching the condition. If recursive, keep rerolling until they stop matching
+    pub reroll: Option<(Condition, bool)>,
+    /// Roll an additional die whenever one matches the condition. If once, the new dice don't explode further
+    pub explode: Option<(Condition, bool)>,
+    /// Dice rolling below this count as this
+    pub min: Option<u32>,
+    /// Dice rolling above this count as this
+    pub max: Option<u32>,
+    /// If set, the pool totals the number of dice meeting this rather than their sum
+    pub successes: Option<Condition>,
+    /// If set, dice meeting this count against the total (or are the total, without successes)
+    pub failures: Option<Condition>,
+    /// Whether this pool is subtracted from the roll
+    pub negative: bool,
+    /// Annotation describing the dice, e.g. "fire" or "Guidance"
+    pub flavor: Option<String>,
+    pub results: Vec<DieResult>,
+}
+
+impl DiceTerm {
+    pub fn new(count: u32, faces: u32) -> Self {
+        DiceTerm {
+            count,
+            faces,
+            keep: None,
+            reroll: None,
+            explode: None,
+            min: None,
+            max: None,
+            successes: None,
+            failures: None,
+            negative: false,
+            flavor: None,
+            results: vec![],
+        }
+    }
+
+    pub fn flavor(mut self, flavor: impl Into<String>) -> Self {
+        self.flavor = Some(flavor.into());
+        self
+    }
+
+    pub fn keep(mut self, keep: Keep) -> Self {
+        self.keep = Some(keep);
+        self
+    }
+
+    /// The value a die shows once min/max clamping is applied
+    fn clamp(&self, value: u32) -> u32 {
+        let value = self.min.map_or(value, |min| value.max(min));
+        self.max.map_or(value, |max| value.min(max))
+    }
+
+    /// Roll (or reroll) every die in the pool
+    pub fn roll(&mut self) {
+        let mut rng = rand::thread_rng();
+        let mut roll_one = || DieResult::new(rng.gen_range(1..=self.faces));
+        let mut results: Vec<DieResult> = Vec::new();
+
+        let mut pending = self.count as usize;
+        let mut may_explode = true;
+        while pending > 0 && results.len() < MAX_DICE {
+            let mut exploded = 0;
+            for _ in 0..pending {
+                let mut die = roll_one();
+
+                // Rerolls replace the die, keeping the old one around so it can be shown
+                if let Some((condition, recursive)) = self.reroll {
+                    while condition.matches(die.value) && results.len() < MAX_DICE {
+                        die.kept = false;
+                        die.rerolled = true;
+                        results.push(die);
+                        die = roll_one();
+                        if !recursive {
+                            break;
+                        }
+                    }
+                }
+
+                if let Some((condition, _)) = self.explode {
+                    if may_explode && condition.matches(die.value) {
+                        die.exploded = true;
+                        exploded += 1;
+                    }
+                }
+                results.push(die);
+            }
+            pending = exploded;
+            if let Some((_, once)) = self.explode {
+                may_explode = !once;
+            }
+        }
+
+        for die in &mut results {
+            die.value = self.clamp(die.value);
+        }
+        self.results = results;
+        self.apply_keep();
+    }
+
+    /// Marks dice as dropped according to the keep modifier
+    fn apply_keep(&mut self) {
+        let Some(keep) = self.keep else { return };
+        let mut order: Vec<usize> = (0..self.results.len())
+            .filter(|&i| !self.results[i].rerolled)
+            .collect();
+        let live = order.len() as u32;
+        let (descending, wanted) = match keep {
+            Keep::Highest(n) => (true, n),
+            Keep::Lowest(n) => (false, n),
+            Keep::DropHighest(n) => (false, live.saturating_sub(n)),
+            Keep::DropLowest(n) => (true, live.saturating_sub(n)),
+        };
+        if descending {
+            order.sort_by_key(|&i| std::cmp::Reverse(self.results[i].value));
+        } else {
+            order.sort_by_key(|&i| self.results[i].value);
+        }
+        for (rank, i) in order.into_iter().enumerate() {
+            self.results[i].kept = (rank as u32) < wanted;
+        }
+    }
+
+    /// The magnitude this pool contributes, before considering whether it is negative
+    pub fn total(&self) -> i64 {
+        let kept = self.results.iter().filter(|die| die.kept);
+        if self.successes.is_some() || self.failures.is_some() {
+            return kept.map(|die| {
+                match (self.successes, self.failures) {
+                    (Some(success), _) if success.matches(die.value) => 1,
+                    (Some(_), Some(failure)) if failure.matches(die.value) => -1,
+                    (None, Some(failure)) if failure.matches(die.value) => 1,
+                    _ => 0,
+                }
+            }).sum();
+        }
+        kept.map(|die| die.value as i64).sum()
+    }
+
+    /// The formula for this term alone, e.g. 2d20kh1
+    pub fn formula(&self) -> String {
+        let mut formula = format!("{}d{}", self.count, self.faces);
+        match self.reroll {
+            Some((condition, true)) => formula.push_str(&format!("rr{}", condition.formula())),
+            Some((condition, false)) => formula.push_str(&format!("r{}", condition.formula())),
+            None => {}
+        }
+        match self.explode {
+            Some((condition, true)) => formula.push_str(&format!("xo{}", condition.formula())),
+            Some((condition, false)) => formula.push_str(&format!("x{}", condition.formula())),
+            None => {}
+        }
+        match self.keep {
+            Some(Keep::Highest(n)) => formula.push_str(&format!("kh{}", n)),
+            Some(Keep::Lowest(n)) => formula.push_str(&format!("kl{}", n)),
+            Some(Keep::DropHighest(n)) => formula.push_str(&format!("dh{}", n)),
+            Some(Keep::DropLowest(n)) => formula.push_str(&format!("dl{}", n)),
+            None => {}
+        }
+        if let Some(min) = self.min {
+            formula.push_str(&format!("min{}", min));
+        }
+        if let Some(max) = self.max {
+            formula.push_str(&format!("max{}", max));
+        }
+        if let Some(condition) = self.successes {
+            formula.push_str(&format!("cs{}", condition.formula()));
+        }
+        if let Some(condition) = self.failures {
+            formula.push_str(&format!("cf{}", condition.formula()));
+        }
+        formula
+    }
+}
+
+/// A flat number added to a roll, along with where it came from
+#[derive(Clone, Debug)]
+pub struct Modifier {
+    pub source: String,
+    pub value: i64,
+}
+
+/// One part of a roll
+#[derive(Clone, Debug)]
+pub enum Term {
+    Dice(DiceTerm),
+    Flat(Modifier),
+}
+
+/// A sum of dice and flat modifiers
+#[derive(Clone, Debug, Default)]
+pub struct Roll {
+    pub terms: Vec<Term>,
+}
+
+impl Roll {
+    /// Parse a formula in Foundry's roll syntax. `@` references are looked up in data, if provided
+    pub fn parse(formula: &str, data: Option<&Value>) -> Result<Roll, DiceError> {
+        let mut parser = Parser { chars: formula.chars().collect(), position: 0, data, depth: 0 };
+        parser.formula()
+    }
+
+    pub fn dice(mut self, dice: DiceTerm) -> Self {
+        self.terms.push(Term::Dice(dice));
+        self
+    }
+
+    pub fn modifier(mut self, source: impl Into<String>, value: i64) -> Self {
+        self.terms.push(Term::Flat(Modifier { source: source.into(), value }));
+        self
+    }
+
+    /// Roll every dice term
+    pub fn evaluate(mut self) -> Self {
+        for term in &mut self.terms {
+            if let Term::Dice(dice) = term {
+                dice.roll();
+            }
+        }
+        self
+    }
+
+    pub fn total(&self) -> i64 {
+        self.terms.iter().map(|term| match term {
+            Term::Dice(dice) if dice.negative => -dice.total(),
+            Term::Dice(dice) => dice.total(),
+            Term::Flat(modifier) => modifier.value,
+        }).sum()
+    }
+
+    /// Whether the roll comes to the same total every time, having no dice
+    pub fn is_deterministic(&self) -> bool {
+        self.terms.iter().all(|term| matches!(term, Term::Flat(_)))
+    }
+
+    /// What the roll always comes to: the flat terms alone, leaving out dice
+    pub fn flat_total(&self) -> i64 {
+        self.terms.iter().map(|term| match term {
+            Term::Flat(modifier) => modifier.value,
+            Term::Dice(_) => 0,
+        }).sum()
+    }
+
+    /// The kept value of the first d20 rolled, used to spot natural 20s and 1s
+    pub fn natural_d20(&self) -> Option<u32> {
+        self.terms.iter().find_map(|term| match term {
+            Term::Dice(dice) if dice.faces == 20 => dice.results.iter().find(|die| die.kept).map(|die| die.value),
+            _ => None,
+        })
+    }
+
+    pub fn formula(&self) -> String {
+        let mut formula = String::new();
+        for term in &self.terms {
+            let (part, negative) = match term {
+                Term::Dice(dice) => (dice.formula(), dice.negative),
+                Term::Flat(modifier) => (modifier.value.abs().to_string(), modifier.value < 0),
+            };
+            if formula.is_empty() {
+                formula = if negative { format!("-{}", part) } else { part };
+            } else {
+                formula.push_str(if negative { " - " } else { " + " });
+                formula.push_str(&part);
+            }
+        }
+        formula
+    }
+}
+
+/// Recursive descent over a formula. Only sums of terms are supported, which covers nearly everything players roll
+struct Parser<'a> {
+    chars: Vec<char>,
+    position: usize,
+    data: Option<&'a Value>,
+    /// How many @ references deep we are, so self-referential roll data can't recurse forever
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn unexpected(&self) -> DiceError {
+        match self.peek() {
+            Some(found) => DiceError::Unexpected { found, position: self.position + 1 },
+            None => DiceError::UnexpectedEnd,
+        }
+    }
+
+    /// Consume the given text if it comes next, case insensitively
+    fn eat(&mut self, text: &str) -> bool {
+        let len = text.chars().count();
+        let upcoming: String = self.chars.iter().skip(self.position).take(len).collect();
+        if upcoming.eq_ignore_ascii_case(text) {
+            self.position += len;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+        if start == self.position {
+            return None;
+        }
+        let digits: String = self.chars[start..self.position].iter().collect();
+        digits.parse().ok()
+    }
+
+    fn required_number(&mut self) -> Result<u32, DiceError> {
+        self.number().ok_or_else(|| self.unexpected())
+    }
+
+    fn formula(&mut self) -> Result<Roll, DiceError> {
+        let mut roll = Roll::default();
+        self.skip_whitespace();
+        let mut negative = self.eat("-");
+        if !negative {
+            self.eat("+");
+        }
+        loop {
+            self.skip_whitespace();
+            self.term(&mut roll, negative)?;
+            self.skip_whitespace();
+            match self.peek() {
+                None => break,
+                Some('+') => negative = false,
+                Some('-') => negative = true,
+                Some('*') | Some('/') | Some('(') | Some(')') => return Err(Self::unsupported()),
+                Some(_) => return Err(self.unexpected()),
+            }
+            self.position += 1;
+        }
+        if roll.terms.is_empty() {
+            return Err(DiceError::UnexpectedEnd);
+        }
+        Ok(roll)
+    }
+
+    fn unsupported() -> DiceError {
+        DiceError::Unsupported("multiplication, division and parentheses".into())
+    }
+
+    fn term(&mut self, roll: &mut Roll, negative: bool) -> Result<(), DiceError> {
+        if self.peek() == Some('(') {
+            return Err(Self::unsupported());
+        }
+        if self.eat("@") {
+            return self.reference(roll, negative);
+        }
+
+        let count = self.number();
+        if self.peek().is_some_and(|c| c == 'd' || c == 'D') {
+            self.position += 1;
+            let mut dice = self.dice(count.unwrap_or(1))?;
+            dice.negative = negative;
+            dice.flavor = self.flavor()?;
+            roll.terms.push(Term::Dice(dice));
+            return Ok(());
+        }
+
+        let value = count.ok_or_else(|| self.unexpected())? as i64;
+        let source = self.flavor()?.unwrap_or_else(|| "Modifier".into());
+        roll.terms.push(Term::Flat(Modifier { source, value: if negative { -value } else { value } }));
+        Ok(())
+    }
+
+    /// Parse the remainder of a dice term, after the "d"
+    fn dice(&mut self, count: u32) -> Result<DiceTerm, DiceError> {
+        let faces = if self.eat("%") { 100 } else { self.required_number()? };
+        if faces == 0 || faces > MAX_FACES {
+            return Err(DiceError::Invalid(format!("dice must have between 1 and {} faces", MAX_FACES)));
+        }
+        if count as usize > MAX_DICE {
+            return Err(DiceError::Invalid(format!("at most {} dice can be rolled at once", MAX_DICE)));
+        }
+
+        let mut dice = DiceTerm::new(count, faces);
+        loop {
+            // Longer modifiers first, so that e.g. "kh" isn't read as "k"
+            if self.eat("min") {
+                dice.min = Some(self.required_number()?);
+            } else if self.eat("max") {
+                dice.max = Some(self.required_number()?);
+            } else if self.eat("kh") {
+                dice.keep = Some(Keep::Highest(self.number().unwrap_or(1)));
+            } else if self.eat("kl") {
+                dice.keep = Some(Keep::Lowest(self.number().unwrap_or(1)));
+            } else if self.eat("k") {
+                dice.keep = Some(Keep::Highest(self.number().unwrap_or(1)));
+            } else if self.eat("dh") {
+                dice.keep = Some(Keep::DropHighest(self.number().unwrap_or(1)));
+            } else if self.eat("dl") || self.eat("d") {
+                dice.keep = Some(Keep::DropLowest(self.number().unwrap_or(1)));
+            } else if self.eat("rr") {
+                dice.reroll = Some((self.condition(Condition::Equal(1))?, true));
+            } else if self.eat("r") {
+                dice.reroll = Some((self.condition(Condition::Equal(1))?, false));
+            } else if self.eat("xo") {
+                dice.explode = Some((self.condition(Condition::Equal(faces))?, true));
+            } else if self.eat("x") {
+                dice.explode = Some((self.condition(Condition::Equal(faces))?, false));
+            } else if self.eat("cs") {
+                dice.successes = Some(self.condition(Condition::Equal(faces))?);
+            } else if self.eat("cf") {
+                dice.failures = Some(self.condition(Condition::Equal(1))?);
+            } else {
+                break;
+            }
+        }
+
+        // A reroll or explosion that always triggers would never finish
+        let always = |condition: Condition| (1..=faces).all(|value| condition.matches(value));
+        if matches!(dice.reroll, Some((condition, true)) if always(condition)) {
+            return Err(DiceError::Invalid("that reroll would never stop".into()));
+        }
+        if matches!(dice.explode, Some((condition, false)) if always(condition)) {
+            return Err(DiceError::Invalid("that explosion would never stop".into()));
+        }
+        Ok(dice)
+    }
+
+    /// Parse an optional comparison, e.g. ">=5" or "3"
+    fn condition(&mut self, default: Condition) -> Result<Condition, DiceError> {
+        let condition = if self.eat(">=") {
+            Condition::GreaterOrEqual(self.required_number()?)
+        } else if self.eat("<=") {
+            Condition::LessOrEqual(self.required_number()?)
+        } else if self.eat(">") {
+            Condition::Greater(self.required_number()?)
+        } else if self.eat("<") {
+            Condition::Less(self.required_number()?)
+        } else if self.eat("=") {
+            Condition::Equal(self.required_number()?)
+        } else {
+            self.number().map_or(default, Condition::Equal)
+        };
+        Ok(condition)
+    }
+
+    /// Parse an optional flavor annotation, e.g. "[fire]"
+    fn flavor(&mut self) -> Result<Option<String>, DiceError> {
+        if !self.eat("[") {
+            return Ok(None);
+        }
+        let start = self.position;
+        while self.peek().is_some_and(|c| c != ']') {
+            self.position += 1;
+        }
+        if !self.eat("]") {
+            return Err(DiceError::UnexpectedEnd);
+        }
+        Ok(Some(self.chars[start..self.position - 1].iter().collect()))
+    }
+
+    /// Parse an @ reference and splice whatever it refers to into the roll
+    fn reference(&mut self, roll: &mut Roll, negative: bool) -> Result<(), DiceError> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '.' || c == '_' || c == '-') {
+            self.position += 1;
+        }
+        let path: String = self.chars[start..self.position].iter().collect();
+        if path.is_empty() {
+            return Err(self.unexpected());
+        }
+
+        let value = self.data
+            .and_then(|data| path.split('.').try_fold(data, |value, key| value.get(key)))
+            .ok_or_else(|| DiceError::UnknownReference(path.clone()))?;
+        match value {
+            Value::Number(number) => {
+                let value = number.as_i64()
+                    .or_else(|| number.as_f64().map(|float| float.floor() as i64))
+                    .ok_or_else(|| DiceError::UnknownReference(path.clone()))?;
+                let source = self.flavor()?.unwrap_or(format!("@{}", path));
+                roll.terms.push(Term::Flat(Modifier { source, value: if negative { -value } else { value } }));
+            }
+            // Unset bonuses are stored as empty strings
+            Value::String(formula) if formula.trim().is_empty() => {
+                let source = self.flavor()?.unwrap_or(format!("@{}", path));
+                roll.terms.push(Term::Flat(Modifier { source, value: 0 }));
+            }
+            // Roll data frequently holds formulas of its own, e.g. bonuses of "1d4 + 1"
+            Value::String(formula) if self.depth < 8 => {
+                let mut nested = Parser { chars: formula.chars().collect(), position: 0, data: self.data, depth: self.depth + 1 };
+                for mut term in nested.formula()?.terms {
+                    match &mut term {
+                        Term::Dice(dice) => dice.negative ^= negative,
+                        Term::Flat(modifier) if negative => modifier.value = -modifier.value,
+                        Term::Flat(_) => {}
+                    }
+                    roll.terms.push(term);
+                }
+            }
+            _ => return Err(DiceError::UnknownReference(path)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn values(roll: &Roll) -> Vec<i64> {
+        roll.terms.iter().map(|term| match term {
+            Term::Dice(dice) if dice.negative => -(dice.faces as i64),
+            Term::Dice(dice) => dice.faces as i64,
+            Term::Flat(modifier) => modifier.value,
+        }).collect()
+    }
+
+    /// The dice of a single pool after fixing their results and applying its keep modifier
+    fn kept(formula: &str, results: &[u32]) -> Vec<u32> {
+        let Term::Dice(mut dice) = Roll::parse(formula, None).unwrap().terms.remove(0) else { panic!("not dice") };
+        dice.results = results.iter().copied().map(DieResult::new).collect();
+        dice.apply_keep();
+        dice.results.iter().filter(|die| die.kept).map(|die| die.value).collect()
+    }
+
+    #[test]
+    fn signs_apply_to_the_term_that_follows() {
+        let roll = Roll::parse("-2 + 1d6 - 1d4 - 3 + 5", None).unwrap();
+        assert_eq!(values(&roll), vec![-2, 6, -4, -3, 5]);
+        assert_eq!(Roll::parse("1 - 2 - 3", None).unwrap().total(), -4);
+        assert_eq!(Roll::parse("+4", None).unwrap().total(), 4);
+    }
+
+    #[test]
+    fn products_and_parentheses_are_refused() {
+        assert!(matches!(Roll::parse("2 * 1d6", None), Err(DiceError::Unsupported(_))));
+        assert!(matches!(Roll::parse("1d6 / 2", None), Err(DiceError::Unsupported(_))));
+        assert!(matches!(Roll::parse("(1d6 + 2)", None), Err(DiceError::Unsupported(_))));
+    }
+
+    #[test]
+    fn keep_and_drop() {
+        assert_eq!(kept("2d20kh", &[7, 15]), vec![15]);
+        assert_eq!(kept("2d20k", &[7, 15]), vec![15]);
+        assert_eq!(kept("2d20kl", &[7, 15]), vec![7]);
+        assert_eq!(kept("4d6kh3", &[3, 1, 6, 4]), vec![3, 6, 4]);
+        assert_eq!(kept("4d6dl", &[3, 1, 6, 4]), vec![3, 6, 4]);
+        assert_eq!(kept("4d6d1", &[3, 1, 6, 4]), vec![3, 6, 4]);
+        assert_eq!(kept("4d6dh2", &[3, 1, 6, 4]), vec![3, 1]);
+        assert_eq!(kept("2d6kh5", &[2, 5]), vec![2, 5]);
+    }
+
+    #[test]
+    fn rerolls() {
+        let Term::Dice(dice) = &Roll::parse("1d20r", None).unwrap().terms[0] else { panic!("not dice") };
+        assert_eq!(dice.reroll, Some((Condition::Equal(1), false)));
+        let Term::Dice(dice) = &Roll::parse("2d6rr<3", None).unwrap().terms[0] else { panic!("not dice") };
+        assert_eq!(dice.reroll, Some((Condition::Less(3), true)));
+
+        // Recursive rerolls of a d2's 1s can only ever finish on a 2
+        let roll = Roll::parse("5d2rr1", None).unwrap().evaluate();
+        assert_eq!(roll.total(), 10);
+        let Term::Dice(dice) = &roll.terms[0] else { panic!("not dice") };
+        assert!(dice.results.iter().filter(|die| die.rerolled).all(|die| die.value == 1 && !die.kept));
+
+        assert!(matches!(Roll::parse("1d6rr<=6", None), Err(DiceError::Invalid(_))));
+    }
+
+    #[test]
+    fn references_are_looked_up_in_roll_data() {
+        let data = json!({
+            "abilities": {"dex": {"mod": 3}},
+            "prof": 2.5,
+            "bonuses": {"mwak": {"attack": "1d4 + 1", "damage": ""}},
+        });
+        let roll = Roll::parse("1d20 + @abilities.dex.mod + @prof", Some(&data)).unwrap();
+        assert_eq!(values(&roll), vec![20, 3, 2]);
+        let Term::Flat(modifier) = &roll.terms[1] else { panic!("not flat") };
+        assert_eq!(modifier.source, "@abilities.dex.mod");
+
+        // Nested formulas take on the sign of the reference
+        let roll = Roll::parse("10 - @bonuses.mwak.attack", Some(&data)).unwrap();
+        assert_eq!(values(&roll), vec![10, -4, -1]);
+
+        // Unset bonuses are blank rather than 0
+        let roll = Roll::parse("1d8 + @bonuses.mwak.damage", Some(&data)).unwrap();
+        assert_eq!(values(&roll), vec![8, 0]);
+
+        assert!(matches!(Roll::parse("@missing", Some(&data)), Err(DiceError::UnknownReference(path)) if path == "missing"));
+        assert!(matches!(Roll::parse("@prof", None), Err(DiceError::UnknownReference(_))));
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_character() {
+        assert!(matches!(Roll::parse("1d20 + x", None), Err(DiceError::Unexpected { found: 'x', position: 8 })));
+        assert!(matches!(Roll::parse("1d20 ? 2", None), Err(DiceError::Unexpected { found: '?', position: 6 })));
+        assert!(matches!(Roll::parse("1dx", None), Err(DiceError::Unexpected { found: 'x', position: 3 })));
+        assert!(matches!(Roll::parse("1d20 +", None), Err(DiceError::UnexpectedEnd)));
+        assert!(matches!(Roll::parse("", None), Err(DiceError::UnexpectedEnd)));
+        assert!(matches!(Roll::parse("1d6[fire", None), Err(DiceError::UnexpectedEnd)));
+    }
+}
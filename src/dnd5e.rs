@@ -1,8 +1,50 @@
 #![allow(non_camel_case_types)]
 
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use crate::world::{World, BaseActor, BaseToken, BaseItem, Document};
+use std::collections::BTreeMap;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::Error;
+use serde_json::{json, Value};
+use crate::dice::{Modifier, Roll};
+use crate::world::{World, BaseActor, BaseToken, BaseItem, Incompatibility, Nested, TokenActor};
+
+/// Every check that can be rolled, as (key, label). Keys are what the roll command accepts
+pub const CHECKS: &[(&str, &str)] = &[
+    ("str", "Strength"),
+    ("dex", "Dexterity"),
+    ("con", "Constitution"),
+    ("int", "Intelligence"),
+    ("wis", "Wisdom"),
+    ("cha", "Charisma"),
+    ("acr", "Acrobatics"),
+    ("ani", "Animal Handling"),
+    ("arc", "Arcana"),
+    ("ath", "Athletics"),
+    ("dec", "Deception"),
+    ("his", "History"),
+    ("ins", "Insight"),
+    ("itm", "Intimidation"),
+    ("inv", "Investigation"),
+    ("med", "Medicine"),
+    ("nat", "Nature"),
+    ("prc", "Perception"),
+    ("prf", "Performance"),
+    ("per", "Persuasion"),
+    ("rel", "Religion"),
+    ("slt", "Sleight of Hand"),
+    ("ste", "Stealth"),
+    ("sur", "Survival"),
+];
+
+/// Formulas for the ways armor class can be calculated, as the dnd5e system configures them.
+/// Unknown calculations fall back to the first
+const ARMOR_CLASS_FORMULAS: &[(&str, &str)] = &[
+    ("default", "@attributes.ac.armor + @attributes.ac.dex"),
+    ("mage", "13 + @abilities.dex.mod"),
+    ("draconic", "13 + @abilities.dex.mod"),
+    ("unarmoredMonk", "10 + @abilities.dex.mod + @abilities.wis.mod"),
+    ("unarmoredBarb", "10 + @abilities.dex.mod + @abilities.con.mod"),
+    ("unarmoredBard", "10 + @abilities.dex.mod + @abilities.cha.mod"),
+];
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -16,7 +58,8 @@ pub enum DND5EActor {
     character {
         #[serde(flatten)]
         base: BaseActor<DND5EItem, DND5EToken>,
-        system: CharacterSystem,
+        #[serde(deserialize_with = "character_system")]
+        system: Box<CharacterSystem>,
     },
 
     vehicle {
@@ -29,98 +72,607 @@ pub enum DND5EActor {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct CharacterSystem {
-    pub attributes: Attributes,
-    pub abilities: Abilities,
-    pub skills: Skills
-}
+impl DND5EActor {
+    /// The shared document data, for actor types that we model it on
+    pub fn base(&self) -> Option<&BaseActor<DND5EItem, DND5EToken>> {
+        match self {
+            DND5EActor::npc { base, .. } | DND5EActor::character { base, .. } => Some(base),
+            _ => None,
+        }
+    }
 
+    /// Character level: what the sheet records, else the total across class items
+    pub fn level(&self) -> u8 {
+        if let DND5EActor::character { system, .. } = self {
+            if let Some(level) = system.details.level {
+                return level;
+            }
+        }
+        let Some(base) = self.base() else { return 0 };
+        base.items.iter().map(|item| match item {
+            DND5EItem::class { system, .. } => system.levels.unwrap_or(0),
+            _ => 0,
+        }).sum()
+    }
 
-#[derive(Serialize, Deserialize)]
-pub struct Skills {
-    #[serde(rename="acr")]
-    pub acrobatics: Skill,
+    /// Proficiency bonus. A value on the sheet wins, then a class's own proficiency progression, then challenge
+    /// rating for NPCs, and only then the standard table by level
+    pub fn proficiency(&self) -> i32 {
+        let stored = match self {
+            DND5EActor::character { system, .. } => system.attributes.prof,
+            DND5EActor::npc { system, .. } => system.pointer("/attributes/prof").and_then(Value::as_i64),
+            _ => None,
+        };
+        if let Some(prof) = stored.filter(|prof| *prof > 0) {
+            return prof as i32;
+        }
 
-    #[serde(rename="ani")]
-    pub animal_handling: Skill,
+        // Scale values step with the level in their own class, not the character's total
+        let progression = self.base().into_iter()
+            .flat_map(|base| &base.items)
+            .find_map(|item| match item {
+                DND5EItem::class { system, .. } => {
+                    let level = system.levels.unwrap_or(0);
+                    system.scale_at("prof", level).or_else(|| system.scale_at("proficiency", level))
+                }
+                _ => None,
+            });
+        if let Some(prof) = progression {
+            return prof as i32;
+        }
 
-    #[serde(rename="arc")]
-    pub arcana: Skill,
+        let level = self.level();
+        if let DND5EActor::npc { system, .. } = self {
+            if let Some(cr) = challenge_rating(system.pointer("/details/cr")).filter(|_| level == 0) {
+                return ((cr.max(1.0) + 7.0) / 4.0).floor() as i32;
+            }
+        }
+        proficiency_by_level(level)
+    }
 
-    #[serde(rename="ath")]
-    pub athletics: Skill,
+    /// The data available to @ references in roll formulas, mirroring Foundry's getRollData
+    pub fn roll_data(&self) -> Value {
+        let mut data = match self {
+            // Formulas may refer to anything on the sheet, so start from all of it with what we model on top
+            DND5EActor::character { system, .. } => {
+                let mut data = system.raw.clone();
+                overlay(&mut data, serde_json::to_value(system).unwrap_or_default());
+                data
+            }
+            DND5EActor::npc { system, .. }
+            | DND5EActor::vehicle { system }
+            | DND5EActor::group { system } => system.clone(),
+        };
 
-    #[serde(rename="dec")]
-    pub deception: Skill,
+        // Foundry derives these when preparing data, so they are absent from what the server sends us
+        if let Some(abilities) = data.get_mut("abilities").and_then(Value::as_object_mut) {
+            for ability in abilities.values_mut() {
+                if let Some(score) = ability.get("value").and_then(Value::as_i64) {
+                    ability["mod"] = json!((score - 10).div_euclid(2));
+                }
+            }
+        }
+        let prof = self.proficiency();
+        data["prof"] = json!(prof);
+        data["attributes"]["prof"] = json!(prof);
+        data
+    }
 
-    #[serde(rename="his")]
-    pub history: Skill,
+    /// The tools the actor can make checks with: their tool proficiencies, named after the tool items they have, and any
+    /// other tool items
+    pub fn tools(&self) -> Vec<Tool> {
+        let tool_items: Vec<(&BaseItem, &PhysicalSystem)> = self.base().into_iter()
+            .flat_map(|base| &base.items)
+            .filter_map(|item| match item {
+                DND5EItem::tool { base, system } => Some((base, system)),
+                _ => None,
+            })
+            .collect();
+        let base_item = |system: &PhysicalSystem| system.other.pointer("/type/baseItem").and_then(Value::as_str).map(str::to_owned);
 
-    #[serde(rename="ins")]
-    pub insight: Skill,
+        let mut tools = vec![];
+        if let DND5EActor::character { system, .. } = self {
+            for (key, proficiency) in &system.tools {
+                let label = tool_items.iter()
+                    .find(|(_, item)| base_item(item).as_deref() == Some(key.as_str()))
+                    .map_or(key.clone(), |(base, _)| base.document.name.clone());
+                tools.push(Tool {
+                    key: format!("tool:{}", key),
+                    label,
+                    ability: if proficiency.ability.is_empty() { "int".into() } else { proficiency.ability.clone() },
+                    proficiency: proficiency.value.unwrap_or(0.0),
+                    bonus: proficiency.bonuses.check.clone(),
+                });
+            }
+        }
+        for (base, system) in tool_items {
+            if base_item(system).is_some_and(|key| tools.iter().any(|tool| tool.key == format!("tool:{}", key))) {
+                continue;
+            }
+            let text = |key: &str| system.other.get(key).and_then(Value::as_str).filter(|text| !text.is_empty()).map(str::to_owned);
+            tools.push(Tool {
+                key: format!("tool:{}", base.document.name.to_lowercase()),
+                label: base.document.name.clone(),
+                ability: text("ability").unwrap_or_else(|| "int".into()),
+                proficiency: system.other.get("proficient").and_then(Value::as_f64).unwrap_or(0.0) as f32,
+                bonus: text("bonus").unwrap_or_default(),
+            });
+        }
+        tools
+    }
 
-    #[serde(rename="inv")]
-    pub investigation: Skill,
+    /// Every check the actor can roll, as (key, label): abilities, skills including homebrew ones, and tools
+    pub fn checks(&self) -> Vec<(String, String)> {
+        let mut checks: Vec<(String, String)> = CHECKS.iter().map(|(key, label)| (key.to_string(), label.to_string())).collect();
+        if let DND5EActor::character { system, .. } = self {
+            for (key, skill) in &system.skills {
+                if !checks.iter().any(|(check, _)| check == key) {
+                    checks.push((key.clone(), skill_label(key, skill)));
+                }
+            }
+        }
+        checks.extend(self.tools().into_iter().map(|tool| (tool.key, tool.label)));
+        checks
+    }
 
-    #[serde(rename="itm")]
-    pub intimidation: Skill,
+    /// Whether one of the dnd5e system's special traits, e.g. "jackOfAllTrades", is set on the actor
+    pub fn flag(&self, name: &str) -> bool {
+        self.base()
+            .and_then(|base| base.document.flags.pointer(&format!("/dnd5e/{}", name)))
+            .is_some_and(|flag| flag.as_bool() == Some(true) || flag.as_f64().is_some_and(|value| value != 0.0))
+    }
 
-    #[serde(rename="med")]
-    pub medicine: Skill,
+    /// Whether the actor has a feature by name, such as "Silver Tongue"
+    pub fn has_feat(&self, name: &str) -> bool {
+        self.base().into_iter()
+            .flat_map(|base| &base.items)
+            .any(|item| matches!(item, DND5EItem::feat { base, .. } if base.document.name.eq_ignore_ascii_case(name)))
+    }
 
-    #[serde(rename="nat")]
-    pub nature: Skill,
+    /// Work out a character's ability, skill or tool check the way the dnd5e system does. Stat is anything the
+    /// roll command accepts: a key or name from CHECKS, a homebrew skill, or a tool
+    pub fn check(&self, stat: &str) -> Option<Check> {
+        let DND5EActor::character { system, .. } = self else { return None };
+        let stat = stat.to_lowercase();
+        let text = |formula: &str| Some(formula.to_owned()).filter(|formula| !formula.trim().is_empty());
 
-    #[serde(rename="per")]
-    pub persuasion: Skill,
+        let ability_key = CHECKS.iter()
+            .filter(|(key, _)| system.abilities.get(key).is_some())
+            .find(|(key, label)| *key == stat || label.to_lowercase() == stat)
+            .map(|(key, label)| (key.to_string(), label.to_string()));
+        let (label, ability, multiplier, specific, passive_bonuses, skill): (String, String, f32, Vec<String>, Vec<String>, Option<String>) = if let Some((key, label)) = ability_key {
+            (label, key, 0.0, vec![], vec![], None)
+        } else if let Some((key, skill)) = system.skill(&stat) {
+            let specific = [text(&system.bonuses_at("skill")), text(&skill.bonuses.check)].into_iter().flatten().collect();
+            let passive = text(&skill.bonuses.passive).into_iter().collect();
+            (skill_label(key, skill), skill.ability.clone(), skill.value.unwrap_or(0.0), specific, passive, Some(key.to_owned()))
+        } else {
+            let tool = self.tools().into_iter().find(|tool| tool.key == stat || tool.label.to_lowercase() == stat)?;
+            (tool.label, tool.ability, tool.proficiency, text(&tool.bonus).into_iter().collect(), vec![], None)
+        };
 
-    #[serde(rename="prc")]
-    pub perception: Skill,
+        let ability_score = system.abilities.get(&ability)?;
+        let mut modifiers = vec![Modifier { source: "Ability modifier".into(), value: ability_modifier(ability_score.value as i64) }];
 
-    #[serde(rename="prf")]
-    pub performance: Skill,
+        // Jack of All Trades adds half proficiency, rounded down, to checks that don't add any; Remarkable Athlete half
+        // rounded up to those using strength, dexterity or constitution
+        let bonus = self.proficiency() as i64;
+        let proficiency = if multiplier > 0.0 {
+            Some(Proficiency::new(bonus, multiplier))
+        } else if self.flag("remarkableAthlete") && matches!(ability.as_str(), "str" | "dex" | "con") {
+            Some(Proficiency { bonus, multiplier: 0.5, rounding: Rounding::Up })
+        } else if self.flag("jackOfAllTrades") {
+            Some(Proficiency::new(bonus, 0.5))
+        } else {
+            None
+        };
+        if let Some(proficiency) = proficiency.filter(|proficiency| proficiency.flat() != 0) {
+            modifiers.push(Modifier { source: proficiency.source().into(), value: proficiency.flat() });
+        }
 
-    #[serde(rename="rel")]
-    pub religion: Skill,
+        // Bonuses to every check come before those to the ability's checks and to this skill or tool
+        let bonuses = [text(&system.bonuses_at("check")), text(&ability_score.bonuses.check)].into_iter()
+            .flatten()
+            .chain(specific)
+            .collect();
 
-    #[serde(rename="slt")]
-    pub sleight_of_hand: Skill,
+        // Reliable Talent covers checks the character is proficient in, Silver Tongue deception and persuasion
+        let reliable = multiplier >= 1.0 && self.flag("reliableTalent");
+        let silver_tongue = matches!(skill.as_deref(), Some("dec" | "per")) && self.has_feat("Silver Tongue");
+        Some(Check {
+            label,
+            modifiers,
+            bonuses,
+            passive_bonuses,
+            minimum: (reliable || silver_tongue).then_some(10),
+            reroll_ones: self.flag("halflingLucky"),
+        })
+    }
 
-    #[serde(rename="ste")]
-    pub stealth: Skill,
+    /// Maximum hit points, including any temporary change to them. dnd5e 3.x onwards works a character's maximum out
+    /// from their classes rather than storing it, so it is worked out here the same way. None if it can't be
+    pub fn max_hp(&self) -> Option<i64> {
+        let data = self.roll_data();
+        let tempmax = data.pointer("/attributes/hp/tempmax").and_then(Value::as_i64).unwrap_or(0);
+        if let Some(max) = data.pointer("/attributes/hp/max").and_then(Value::as_i64) {
+            return Some(max + tempmax);
+        }
 
-    #[serde(rename="sur")]
-    pub survival: Skill,
+        let DND5EActor::character { base, .. } = self else { return None };
+        let classes: Vec<&ClassSystem> = base.items.iter()
+            .filter_map(|item| match item {
+                DND5EItem::class { system, .. } => Some(system),
+                _ => None,
+            })
+            .collect();
+        if classes.is_empty() {
+            return None;
+        }
+        let level = self.level() as i64;
+        let con = data.pointer("/abilities/con/mod").and_then(Value::as_i64).unwrap_or(0);
+        let bonus = |pointer: &str| data.pointer(pointer).map_or(0, |bonus| simplify_bonus(bonus, &data));
+        let classes = classes.iter().enumerate().map(|(index, class)| class.hit_points(index == 0)).sum::<i64>();
+        Some(classes + (con + bonus("/attributes/hp/bonuses/level")) * level + bonus("/attributes/hp/bonuses/overall") + tempmax)
+    }
+
+    /// Whether an effect on the actor has them concentrating on a spell
+    pub fn concentrating(&self) -> bool {
+        self.base().is_some_and(|base| base.effects.iter().any(|effect| effect.applies("concentrating")))
+    }
+
+    /// Armor class, worked out as the dnd5e system does when preparing the sheet
+    pub fn armor_class(&self) -> Option<i64> {
+        let mut data = self.roll_data();
+        let Some(ac) = data.pointer("/attributes/ac").and_then(|ac| serde_json::from_value::<ArmorClass>(ac.clone()).ok()) else {
+            // Old versions stored the value itself
+            return data.pointer("/attributes/ac/value").and_then(Value::as_i64);
+        };
+        let flat = ac.flat.map(i64::from);
+        if ac.calc == "flat" {
+            return flat;
+        }
+
+        let equipped: Vec<&Value> = self.base().into_iter()
+            .flat_map(|base| &base.items)
+            .filter_map(|item| match item {
+                DND5EItem::equipment { system, .. } if system.equipped => Some(&system.other),
+                _ => None,
+            })
+            .collect();
+        // 3.x onwards keep the kind of armor in type.value, older versions in armor.type
+        let armor_type = |system: &Value| system.pointer("/type/value")
+            .or(system.pointer("/armor/type"))
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_owned();
+        // 4.x keeps magical enhancement apart from the base value
+        let armor_value = |system: &Value| ["/armor/value", "/armor/magicalBonus"].iter()
+            .filter_map(|pointer| system.pointer(pointer).and_then(Value::as_i64))
+            .sum::<i64>();
+        let shield = equipped.iter()
+            .copied()
+            .filter(|system| armor_type(system) == "shield")
+            .map(armor_value)
+            .max()
+            .unwrap_or(0);
+
+        // Formulas refer to the armor worn and how much of the dex modifier it allows, which foundry derives
+        let dex = data.pointer("/abilities/dex/mod").and_then(Value::as_i64).unwrap_or(0);
+        let (armor, dex) = match equipped.iter().copied().find(|system| matches!(armor_type(system).as_str(), "light" | "medium" | "heavy")) {
+            // Heavy armor allows none of it, whatever the item says
+            Some(system) if armor_type(system) == "heavy" => (armor_value(system), 0),
+            Some(system) => (armor_value(system), system.pointer("/armor/dex").and_then(Value::as_i64).map_or(dex, |cap| dex.min(cap))),
+            None => (10, dex),
+        };
+        data["attributes"]["ac"]["armor"] = json!(armor);
+        data["attributes"]["ac"]["dex"] = json!(dex);
+        data["attributes"]["ac"]["shield"] = json!(shield);
+
+        let formula = match ac.calc.as_str() {
+            "natural" => None,
+            "custom" => ac.formula.clone(),
+            calc => ARMOR_CLASS_FORMULAS.iter()
+                .find(|(key, _)| *key == calc)
+                .or(ARMOR_CLASS_FORMULAS.first())
+                .map(|(_, formula)| formula.to_string()),
+        };
+        let base = match formula {
+            Some(formula) => {
+                // Armor class mustn't change each time it's looked at, so like dnd5e we refuse formulas with dice
+                let roll = Roll::parse(&formula, Some(&data)).ok()?;
+                if !roll.is_deterministic() {
+                    return None;
+                }
+                roll.flat_total()
+            }
+            None => flat?,
+        };
+        let bonus = simplify_bonus(&ac.bonus, &data);
+        Some(base + shield + bonus)
+    }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct CharacterSystem {
+    pub attributes: Attributes,
+    pub abilities: Abilities,
+    /// Keyed as in CHECKS, along with any homebrew skills the world adds
+    pub skills: BTreeMap<String, Skill>,
+    /// Tool proficiencies, keyed by the tool's base item, e.g. "thief"
+    #[serde(default)]
+    pub tools: BTreeMap<String, ToolProficiency>,
+    #[serde(default)]
+    pub details: Details,
+    #[serde(default)]
+    pub currency: Currency,
+    /// The sheet's free-form resources, keyed primary, secondary and tertiary
+    #[serde(default)]
+    pub resources: BTreeMap<String, Resource>,
+    /// Global bonus formulas, such as abilities.check, kept as they are for roll data
+    #[serde(default)]
+    pub bonuses: Value,
+    /// Everything foundry sent, including what isn't modelled above
+    #[serde(skip)]
+    pub raw: Value,
+}
+
+/// Deserialize a character's system, keeping the raw data alongside for roll data
+fn character_system<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Box<CharacterSystem>, D::Error> {
+    let raw = Value::deserialize(deserializer)?;
+    let mut system: CharacterSystem = serde_path_to_error::deserialize(&raw)
+        .map_err(|err| D::Error::custom(format!("{} at system.{}", err.inner(), err.path())))?;
+    system.raw = raw;
+    Ok(Box::new(system))
+}
+
+/// Merge one object into another, as foundry's mergeObject. Anything in patch wins
+fn overlay(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(fields) => for (key, value) in fields {
+            overlay(&mut target[key.as_str()], value);
+        },
+        patch => *target = patch,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Resource {
+    pub value: Option<i64>,
+    pub max: Option<i64>,
+    /// Whether it comes back on a short rest
+    #[serde(default)]
+    pub sr: bool,
+    /// Whether it comes back on a long rest
+    #[serde(default)]
+    pub lr: bool,
+    pub label: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Details {
+    #[serde(default)]
+    pub xp: Experience,
+    /// Kept on the sheet by some worlds and importers. Otherwise we sum class levels
+    #[serde(default)]
+    pub level: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Experience {
+    #[serde(default)]
+    pub value: i64,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Currency {
+    pub pp: i64,
+    pub gp: i64,
+    pub ep: i64,
+    pub sp: i64,
+    pub cp: i64,
+}
+
+
 #[derive(Serialize, Deserialize)]
 pub struct Skill {
     pub ability: String,
+    #[serde(default)]
     pub bonuses: SkillBonuses,
-    pub value: Option<f32>
+    /// Proficiency multiplier: 0, 0.5, 1 or 2
+    pub value: Option<f32>,
+    /// Homebrew skills may name themselves. Standard ones are named in CHECKS
+    #[serde(default)]
+    pub label: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct SkillBonuses {
     #[serde(default)]
     pub check: String,
+    #[serde(default, alias = "passives")]
+    pub passive: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ToolProficiency {
     #[serde(default)]
-    pub passives: String,
+    pub ability: String,
+    /// Proficiency multiplier: 0, 0.5, 1 or 2
+    pub value: Option<f32>,
+    #[serde(default)]
+    pub bonuses: ToolBonuses,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ToolBonuses {
+    #[serde(default)]
+    pub check: String,
+}
+
+/// A tool the actor can make checks with
+pub struct Tool {
+    /// What the roll command accepts, e.g. "tool:thief"
+    pub key: String,
+    pub label: String,
+    pub ability: String,
+    /// Proficiency multiplier: 0, 0.5, 1 or 2
+    pub proficiency: f32,
+    pub bonus: String,
+}
+
+impl CharacterSystem {
+    /// One of the global ability bonus formulas, e.g. "check" or "skill"
+    fn bonuses_at(&self, key: &str) -> String {
+        match self.bonuses.pointer(&format!("/abilities/{}", key)) {
+            Some(Value::String(formula)) => formula.clone(),
+            Some(Value::Number(number)) => number.to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Find a skill by key (e.g. "prc"), name (e.g. "Perception") or the first word of its name (e.g. "sleight")
+    pub fn skill(&self, name: &str) -> Option<(&str, &Skill)> {
+        let name = name.to_lowercase();
+        self.skills.iter()
+            .find(|(key, skill)| {
+                let label = skill_label(key, skill).to_lowercase();
+                **key == name || label == name || label.split_whitespace().next() == Some(name.as_str())
+            })
+            .map(|(key, skill)| (key.as_str(), skill))
+    }
+}
+
+/// What a skill is called: its name in CHECKS, else whatever homebrew calls it, else its key
+fn skill_label(key: &str, skill: &Skill) -> String {
+    CHECKS.iter()
+        .find(|(check, _)| *check == key)
+        .map(|(_, label)| label.to_string())
+        .or(skill.label.clone())
+        .unwrap_or_else(|| key.to_owned())
+}
+
+/// The standard proficiency bonus at a character level
+fn proficiency_by_level(level: u8) -> i32 {
+    match level {
+        0..=4 => 2,
+        5..=8 => 3,
+        9..=12 => 4,
+        13..=16 => 5,
+        17..=20 => 6,
+        _ => 7
+    }
+}
+
+/// A challenge rating, which older worlds may keep as a fraction like "1/2"
+fn challenge_rating(cr: Option<&Value>) -> Option<f64> {
+    match cr? {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => match text.split_once('/') {
+            Some((numerator, denominator)) => Some(numerator.trim().parse::<f64>().ok()? / denominator.trim().parse::<f64>().ok()?),
+            None => text.trim().parse().ok(),
+        },
+        _ => None,
+    }
+}
+
+/// The modifier an ability score gives
+pub fn ability_modifier(score: i64) -> i64 {
+    (score - 10).div_euclid(2)
+}
+
+/// How a fractional proficiency bonus is rounded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+/// Some multiple of the proficiency bonus, as the dnd5e system's Proficiency
+pub struct Proficiency {
+    pub bonus: i64,
+    /// 0, 0.5, 1 or 2
+    pub multiplier: f32,
+    pub rounding: Rounding,
+}
+
+impl Proficiency {
+    pub fn new(bonus: i64, multiplier: f32) -> Self {
+        Proficiency { bonus, multiplier, rounding: Rounding::Down }
+    }
+
+    /// What it adds to a roll
+    pub fn flat(&self) -> i64 {
+        let exact = self.bonus as f32 * self.multiplier;
+        match self.rounding {
+            Rounding::Down => exact.floor() as i64,
+            Rounding::Up => exact.ceil() as i64,
+        }
+    }
+
+    /// What to call it on a roll
+    pub fn source(&self) -> &'static str {
+        match self.multiplier {
+            multiplier if multiplier >= 2.0 => "Expertise",
+            multiplier if multiplier >= 1.0 => "Proficiency",
+            _ => "Half proficiency",
+        }
+    }
+}
+
+/// Everything that goes into an ability, skill or tool check besides the d20 itself
+pub struct Check {
+    /// What is being rolled, e.g. "Stealth"
+    pub label: String,
+    /// The ability modifier and any proficiency
+    pub modifiers: Vec<Modifier>,
+    /// Bonus formulas from the sheet, which may refer to roll data
+    pub bonuses: Vec<String>,
+    /// Bonus formulas that only count towards the passive score
+    pub passive_bonuses: Vec<String>,
+    /// The lowest the d20 can count as, from Reliable Talent or Silver Tongue
+    pub minimum: Option<u32>,
+    /// Whether a 1 on the d20 is rerolled, from Halfling Lucky
+    pub reroll_ones: bool,
+}
+
+impl Check {
+    /// The passive score before advantage or disadvantage: 10 plus everything added to the d20. Bonus is the flat
+    /// part of the bonus formulas, passive ones included
+    pub fn passive(&self, bonus: i64) -> i64 {
+        10 + self.modifiers.iter().map(|modifier| modifier.value).sum::<i64>() + bonus
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Attributes {
     pub ac: ArmorClass,
-    pub hp: HitPoints
+    pub hp: HitPoints,
+    #[serde(default)]
+    pub death: DeathSaves,
+    /// Foundry derives this, so it is only stored when a world overrides it
+    #[serde(default)]
+    pub prof: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DeathSaves {
+    #[serde(default)]
+    pub success: u8,
+    #[serde(default)]
+    pub failure: u8,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ArmorClass {
-    /// Observed to be "flat" or "default"
+    /// How armor class is calculated, e.g. "flat", "natural", "default" or "custom". See ARMOR_CLASS_FORMULAS
+    #[serde(default)]
     pub calc: String,
     pub flat: Option<u8>,
-    pub formula: Option<String>
+    pub formula: Option<String>,
+    /// Added to any calculation but flat, e.g. by a ring of protection. A number or a formula
+    #[serde(default)]
+    pub bonus: Value,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -144,15 +696,30 @@ pub struct Abilities {
     pub wis: AbilityScore,
 }
 
+impl Abilities {
+    /// An ability by its key, e.g. "dex"
+    pub fn get(&self, key: &str) -> Option<&AbilityScore> {
+        let ability = match key {
+            "cha" => &self.cha,
+            "con" => &self.con,
+            "dex" => &self.dex,
+            "int" => &self.int,
+            "str" => &self.str,
+            "wis" => &self.wis,
+            _ => return None,
+        };
+        Some(ability)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AbilityScore {
     pub value: u8,
-    // #[serde(default)]
-    // pub proficient: u8,
-    // #[serde(default)]
-    // pub bonuses: AbilityScoreBonus,
-    // #[serde(default)]
-    // pub save: String
+    /// Saving throw proficiency multiplier
+    #[serde(default)]
+    pub proficient: f32,
+    #[serde(default)]
+    pub bonuses: AbilityScoreBonus,
     // Unsure what the rest means or whether it matters
 }
 
@@ -175,29 +742,702 @@ pub enum DND5EItem {
 
     base {}, // Apparently unused, but we support
     background {},
-    consumable {},
-    container {},
-    backpack {}, // What's the difference? Who knows
-    equipment {},
+    consumable {
+        #[serde(flatten)]
+        base: BaseItem,
+        #[serde(default)]
+        system: PhysicalSystem,
+    },
+    container {
+        #[serde(flatten)]
+        base: BaseItem,
+        #[serde(default)]
+        system: PhysicalSystem,
+    },
+    backpack { // What's the difference? Who knows
+        #[serde(flatten)]
+        base: BaseItem,
+        #[serde(default)]
+        system: PhysicalSystem,
+    },
+    equipment {
+        #[serde(flatten)]
+        base: BaseItem,
+        #[serde(default)]
+        system: PhysicalSystem,
+    },
     facility {},
-    feat {},
-    loot {},
+    feat {
+        #[serde(flatten)]
+        base: BaseItem,
+        #[serde(default)]
+        system: FeatSystem,
+    },
+    loot {
+        #[serde(flatten)]
+        base: BaseItem,
+        #[serde(default)]
+        system: PhysicalSystem,
+    },
     race {}, // Aka species
     spell {},
     subclass {},
-    tool {},
-    weapon {},
+    tool {
+        #[serde(flatten)]
+        base: BaseItem,
+        #[serde(default)]
+        system: PhysicalSystem,
+    },
+    weapon {
+        #[serde(flatten)]
+        base: BaseItem,
+        #[serde(default)]
+        system: PhysicalSystem,
+    },
+}
+
+impl DND5EItem {
+    /// The shared document data, for item types that we model it on
+    pub fn base(&self) -> Option<&BaseItem> {
+        match self {
+            DND5EItem::class { base, .. } | DND5EItem::feat { base, .. } => Some(base),
+            _ => self.physical().map(|(base, _)| base),
+        }
+    }
+
+    /// Limited uses, for item types that can have them
+    pub fn uses(&self) -> Option<&Uses> {
+        match self {
+            DND5EItem::feat { system, .. } => Some(&system.uses),
+            _ => self.physical().map(|(_, system)| &system.uses),
+        }
+    }
+
+    /// Items that can be carried, along with what they have in common
+    pub fn physical(&self) -> Option<(&BaseItem, &PhysicalSystem)> {
+        match self {
+            DND5EItem::consumable { base, system }
+            | DND5EItem::container { base, system }
+            | DND5EItem::backpack { base, system }
+            | DND5EItem::equipment { base, system }
+            | DND5EItem::loot { base, system }
+            | DND5EItem::tool { base, system }
+            | DND5EItem::weapon { base, system } => Some((base, system)),
+            _ => None,
+        }
+    }
+
+    /// Whether other items can be put in this one
+    pub fn is_container(&self) -> bool {
+        matches!(self, DND5EItem::container { .. } | DND5EItem::backpack { .. })
+    }
+}
+
+/// What every item that can be carried has in common
+#[derive(Serialize, Deserialize)]
+pub struct PhysicalSystem {
+    #[serde(default = "one")]
+    pub quantity: i64,
+    /// Weight of one, in pounds (or kilograms, in metric worlds)
+    #[serde(default, deserialize_with = "weight")]
+    pub weight: f64,
+    #[serde(default)]
+    pub price: Price,
+    #[serde(default)]
+    pub equipped: bool,
+    #[serde(default)]
+    pub attuned: bool,
+    /// Id of the container item this is in, if any
+    #[serde(default)]
+    pub container: Option<String>,
+    #[serde(default)]
+    pub uses: Uses,
+    /// Coins held, for containers
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// Everything else, such as armor and damage, which is read where it is needed
+    #[serde(flatten)]
+    pub other: Value,
+}
+
+impl Default for PhysicalSystem {
+    fn default() -> Self {
+        PhysicalSystem {
+            quantity: 1,
+            weight: 0.0,
+            price: Price::default(),
+            equipped: false,
+            attuned: false,
+            container: None,
+            uses: Uses::default(),
+            currency: None,
+            other: json!({}),
+        }
+    }
+}
+
+fn one() -> i64 {
+    1
+}
+
+/// Weight was a bare number before dnd5e 3.2, and an object with units since
+fn weight<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    Ok(value.get("value").unwrap_or(&value).as_f64().unwrap_or(0.0))
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Price {
+    #[serde(default)]
+    pub value: f64,
+    /// Coin the price is in, e.g. "gp"
+    #[serde(default)]
+    pub denomination: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Uses {
+    /// Uses left. dnd5e 4.x only records uses spent, see version::adapt_uses_v4
+    pub value: Option<i64>,
+    /// Usually a number, but may be a formula
+    #[serde(default)]
+    pub max: Value,
+    /// When uses come back: "sr" or "lr" for short and long rests, "day", "dawn", "dusk", or "charges" for never
+    #[serde(default)]
+    pub per: Option<String>,
+    /// Whether the item is used up along with its last use
+    #[serde(default, rename = "autoDestroy")]
+    pub auto_destroy: bool,
+}
+
+impl Uses {
+    /// The most uses the item has, working out formulas such as "@abilities.cha.mod" with the owner's roll data
+    pub fn maximum(&self, data: &Value) -> Option<i64> {
+        uses_maximum(&self.max, data)
+    }
+}
+
+/// What a bonus always comes to, as dnd5e's simplifyBonus: numbers as they are, and only the flat terms of formulas
+pub fn simplify_bonus(bonus: &Value, data: &Value) -> i64 {
+    match bonus {
+        Value::Number(number) => number.as_i64().unwrap_or(0),
+        Value::String(formula) if !formula.trim().is_empty() => Roll::parse(formula, Some(data)).map_or(0, |roll| roll.flat_total()),
+        _ => 0,
+    }
+}
+
+/// A maximum number of uses, which may be a number or a formula over roll data. Like other bonuses, any dice in the
+/// formula are left out so the maximum stays put. None if it can't be worked out
+pub fn uses_maximum(max: &Value, data: &Value) -> Option<i64> {
+    match max {
+        Value::Number(number) => number.as_i64(),
+        Value::String(text) if !text.trim().is_empty() => Roll::parse(text, Some(data)).ok().map(|roll| roll.flat_total()),
+        _ => None,
+    }.filter(|max| *max > 0)
+}
+
+/// Features, such as Channel Divinity or Ki, and feats
+#[derive(Serialize, Deserialize, Default)]
+pub struct FeatSystem {
+    #[serde(default)]
+    pub uses: Uses,
+    #[serde(flatten)]
+    pub other: Value,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ClassSystem {
-    pub levels: Option<u8>
+    pub levels: Option<u8>,
+    /// The class's hit die before 4.x, e.g. "d8"
+    #[serde(rename = "hitDice", default)]
+    pub hit_dice: Option<String>,
+    /// The class's hit dice from 4.x, e.g. {"denomination": "d8"}
+    #[serde(default)]
+    pub hd: Value,
+    /// A list of advancements, or in 4.x a map of them by id
+    #[serde(default)]
+    pub advancement: Value,
+}
+
+impl ClassSystem {
+    /// The class's advancements of a type, e.g. "ScaleValue"
+    fn advancements<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = &'a Value> {
+        let advancements: Vec<&Value> = match &self.advancement {
+            Value::Array(list) => list.iter().collect(),
+            Value::Object(map) => map.values().collect(),
+            _ => vec![],
+        };
+        advancements.into_iter().filter(move |advancement| advancement.get("type").and_then(Value::as_str) == Some(kind))
+    }
+
+    /// The number of faces on the class's hit die
+    fn hit_die(&self) -> Option<i64> {
+        let denomination = self.hd.get("denomination").and_then(Value::as_str).or(self.hit_dice.as_deref())?;
+        denomination.trim_start_matches('d').parse().ok()
+    }
+
+    /// The hit points the class's levels gave, before constitution. Levels its hit points advancement doesn't record
+    /// count as rolling the average, except the first level of the character's first class, which takes the maximum
+    pub fn hit_points(&self, first_class: bool) -> i64 {
+        let Some(faces) = self.hit_die() else { return 0 };
+        let taken = self.advancements("HitPoints").next().and_then(|advancement| advancement.get("value"));
+        (1..=self.levels.unwrap_or(0)).map(|level| match taken.and_then(|taken| taken.get(level.to_string())) {
+            Some(Value::String(taken)) if taken == "max" => faces,
+            Some(Value::Number(rolled)) => rolled.as_i64().unwrap_or(0),
+            _ if level == 1 && first_class => faces,
+            _ => faces / 2 + 1,
+        }).sum()
+    }
+
+    /// The value a scale value advancement, found by its identifier, has reached at a level in this class
+    pub fn scale_at(&self, identifier: &str, level: u8) -> Option<i64> {
+        let scale = self.advancements("ScaleValue")
+            .find(|advancement| advancement.pointer("/configuration/identifier").and_then(Value::as_str) == Some(identifier))?
+            .pointer("/configuration/scale")?
+            .as_object()?;
+        // The scale only lists the levels it changes at
+        scale.iter()
+            .filter_map(|(at, step)| Some((at.parse::<u8>().ok()?, step)))
+            .filter(|(at, _)| *at <= level)
+            .max_by_key(|(at, _)| *at)
+            .and_then(|(_, step)| step.get("value").and_then(Value::as_i64))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DND5EToken {
-    // #[serde(flatten)]
-    // pub base: BaseToken
+    #[serde(flatten)]
+    pub base: BaseToken,
+
+    /// For unlinked tokens, the base actor with the token's delta applied. See tokens::synthesize_token_actors
+    #[serde(rename="syntheticActor", default, skip_serializing)]
+    pub actor: Nested<Box<DND5EActor>>,
 }
 
-pub type DND5EWorld = World<DND5EActor, DND5EItem, DND5EToken>;
\ No newline at end of file
+impl TokenActor for DND5EToken {
+    fn skipped_actor(&self) -> Option<&Incompatibility> {
+        self.actor.skipped()
+    }
+}
+
+pub type DND5EWorld = World<DND5EActor, DND5EItem, DND5EToken>;
+/// A document laid out for display, independent of how it will be shown
+pub struct Description {
+    pub title: String,
+    pub subtitle: Option<String>,
+    pub fields: Vec<(String, String)>,
+    /// Html
+    pub body: Option<String>,
+    pub image: Option<String>,
+}
+
+/// Follow a dotted path into a value, rendering whatever is there as text
+fn text_at(value: &Value, path: &str) -> Option<String> {
+    let found = path.split('.').try_fold(value, |value, key| value.get(key))?;
+    match found {
+        Value::String(text) if !text.is_empty() => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(flag) => Some(flag.to_string()),
+        _ => None,
+    }
+}
+
+/// Join a value with its units, e.g. "60 ft" or "self"
+fn with_units(system: &Value, path: &str) -> Option<String> {
+    let value = text_at(system, &format!("{}.value", path));
+    let units = text_at(system, &format!("{}.units", path))
+        .or_else(|| text_at(system, &format!("{}.type", path)));
+    match (value, units) {
+        (Some(value), Some(units)) => Some(format!("{} {}", value, units)),
+        (None, Some(units)) => Some(units),
+        (Some(value), None) => Some(value),
+        (None, None) => None,
+    }
+}
+
+fn spell_school(abbreviation: &str) -> &str {
+    match abbreviation {
+        "abj" => "Abjuration",
+        "con" => "Conjuration",
+        "div" => "Divination",
+        "enc" => "Enchantment",
+        "evo" => "Evocation",
+        "ill" => "Illusion",
+        "nec" => "Necromancy",
+        "trs" => "Transmutation",
+        other => other,
+    }
+}
+
+/// The properties of an item, which 3.x onwards keep as a list and older versions as a map of flags
+pub fn properties(system: &Value) -> Vec<String> {
+    match system.get("properties") {
+        Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).map(str::to_owned).collect(),
+        Some(Value::Object(flags)) => flags.iter()
+            .filter(|(_, set)| set.as_bool() == Some(true))
+            .map(|(name, _)| name.clone())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Damage dealt by an item, from either 3.x damage parts or 4.x base damage
+fn damage(system: &Value) -> Option<String> {
+    if let Some(parts) = system.get("damage").and_then(|d| d.get("parts")).and_then(Value::as_array) {
+        let parts: Vec<String> = parts.iter().filter_map(|part| {
+            let formula = part.get(0).and_then(Value::as_str)?;
+            let kind = part.get(1).and_then(Value::as_str).unwrap_or("");
+            Some(format!("{} {}", formula, kind).trim().to_owned())
+        }).collect();
+        if !parts.is_empty() {
+            return Some(parts.join(" + "));
+        }
+    }
+    let base = system.get("damage")?.get("base")?;
+    let number = base.get("number").and_then(Value::as_u64)?;
+    let denomination = base.get("denomination").and_then(Value::as_u64)?;
+    let types: Vec<&str> = base.get("types").and_then(Value::as_array)
+        .map(|types| types.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    Some(format!("{}d{} {}", number, denomination, types.join("/")).trim().to_owned())
+}
+
+/// Describe a raw dnd5e document (e.g. from a compendium) with formatting suited to its type
+pub fn describe(document: &Value) -> Description {
+    let null = Value::Null;
+    let system = document.get("system").unwrap_or(&null);
+    let kind = document.get("type").and_then(Value::as_str).unwrap_or("");
+    let mut fields: Vec<(String, String)> = vec![];
+    let mut field = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            fields.push((name.to_owned(), value));
+        }
+    };
+
+    let subtitle = match kind {
+        "spell" => {
+            let level = system.get("level").and_then(Value::as_u64).unwrap_or(0);
+            let school = spell_school(system.get("school").and_then(Value::as_str).unwrap_or(""));
+            field("Casting Time", with_units(system, "activation"));
+            field("Range", with_units(system, "range"));
+            field("Duration", with_units(system, "duration"));
+            let components: Vec<&str> = properties(system).iter().filter_map(|property| match property.as_str() {
+                "vocal" => Some("V"),
+                "somatic" => Some("S"),
+                "material" => Some("M"),
+                "concentration" => Some("Concentration"),
+                "ritual" => Some("Ritual"),
+                _ => None,
+            }).collect();
+            if !components.is_empty() {
+                field("Components", Some(components.join(", ")));
+            }
+            field("Materials", text_at(system, "materials.value"));
+            Some(match level {
+                0 => format!("{} cantrip", school),
+                level => format!("Level {} {}", level, school.to_lowercase()),
+            })
+        }
+        "npc" => {
+            field("Armor Class", text_at(system, "attributes.ac.flat").or_else(|| text_at(system, "attributes.ac.value")));
+            let hp = text_at(system, "attributes.hp.max").or_else(|| text_at(system, "attributes.hp.value"));
+            field("Hit Points", match (hp, text_at(system, "attributes.hp.formula")) {
+                (Some(hp), Some(formula)) => Some(format!("{} ({})", hp, formula)),
+                (hp, _) => hp,
+            });
+            let speeds: Vec<String> = ["walk", "fly", "swim", "climb", "burrow"].iter()
+                .filter_map(|mode| text_at(system, &format!("attributes.movement.{}", mode))
+                    .filter(|speed| speed != "0")
+                    .map(|speed| format!("{} {}", mode, speed)))
+                .collect();
+            if !speeds.is_empty() {
+                field("Speed", Some(speeds.join(", ")));
+            }
+            let scores: Vec<String> = ["str", "dex", "con", "int", "wis", "cha"].iter()
+                .filter_map(|ability| {
+                    let score = system.get("abilities")?.get(ability)?.get("value")?.as_i64()?;
+                    Some(format!("{} {} ({:+})", ability.to_uppercase(), score, (score - 10).div_euclid(2)))
+                })
+                .collect();
+            if !scores.is_empty() {
+                field("Abilities", Some(scores.join(" · ")));
+            }
+            let cr = text_at(system, "details.cr");
+            let creature = text_at(system, "details.type.value");
+            match (cr, creature) {
+                (Some(cr), Some(creature)) => Some(format!("CR {} {}", cr, creature)),
+                (Some(cr), None) => Some(format!("CR {}", cr)),
+                (None, creature) => creature,
+            }
+        }
+        "feat" => {
+            field("Requirements", text_at(system, "requirements"));
+            field("Uses", text_at(system, "uses.max"));
+            text_at(system, "type.value").or(Some("Feature".into()))
+        }
+        _ => {
+            let price = text_at(system, "price.value").or_else(|| text_at(system, "price"));
+            let denomination = text_at(system, "price.denomination").unwrap_or("gp".into());
+            field("Price", price.map(|price| format!("{} {}", price, denomination)));
+            field("Weight", text_at(system, "weight.value").or_else(|| text_at(system, "weight")));
+            field("Damage", damage(system));
+            let properties = properties(system);
+            if !properties.is_empty() {
+                field("Properties", Some(properties.join(", ")));
+            }
+            let rarity = text_at(system, "rarity");
+            match rarity {
+                Some(rarity) => Some(format!("{} {}", rarity, kind)),
+                None if !kind.is_empty() => Some(kind.to_owned()),
+                None => None,
+            }
+        }
+    };
+
+    Description {
+        title: document.get("name").and_then(Value::as_str).unwrap_or("Unnamed").to_owned(),
+        subtitle,
+        fields,
+        body: text_at(system, "description.value"),
+        image: document.get("img").and_then(Value::as_str).map(str::to_owned),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A level 5 character, so proficiency 3 and half proficiency rounds differently each way
+    fn character(abilities: Value, flags: Value, items: Value) -> DND5EActor {
+        serde_json::from_value(json!({
+            "type": "character",
+            "_id": "actor",
+            "name": "Tester",
+            "flags": flags,
+            "ownership": {"default": 0},
+            "items": items,
+            "prototypeToken": {},
+            "system": {
+                "attributes": {"ac": {"flat": null, "formula": null}, "hp": {"value": 10, "max": 10}},
+                "abilities": abilities,
+                "skills": {
+                    "ath": {"ability": "str", "value": 0},
+                    "dec": {"ability": "cha", "value": 0},
+                    "per": {"ability": "cha", "value": 1},
+                    "prc": {"ability": "wis", "value": 0.5},
+                    "ste": {"ability": "dex", "value": 1},
+                    "inv": {"ability": "int", "value": 2},
+                    "his": {"ability": "int", "value": 0},
+                },
+                "details": {"level": 5},
+            },
+        })).unwrap()
+    }
+
+    fn average() -> Value {
+        json!({"str": {"value": 10}, "dex": {"value": 10}, "con": {"value": 10}, "int": {"value": 10}, "wis": {"value": 10}, "cha": {"value": 10}})
+    }
+
+    fn with_flags(flags: Value) -> DND5EActor {
+        character(average(), json!({"dnd5e": flags}), json!([]))
+    }
+
+    /// The modifier a check adds besides the ability modifier, if any
+    fn proficiency(check: &Check) -> Option<(&str, i64)> {
+        check.modifiers.iter()
+            .find(|modifier| modifier.source != "Ability modifier")
+            .map(|modifier| (modifier.source.as_str(), modifier.value))
+    }
+
+    fn ability_mod(check: &Check) -> i64 {
+        check.modifiers.iter().find(|modifier| modifier.source == "Ability modifier").unwrap().value
+    }
+
+    #[test]
+    fn ability_modifiers_round_down() {
+        assert_eq!(ability_modifier(10), 0);
+        assert_eq!(ability_modifier(11), 0);
+        assert_eq!(ability_modifier(9), -1);
+        assert_eq!(ability_modifier(1), -5);
+        assert_eq!(ability_modifier(0), -5);
+        assert_eq!(ability_modifier(-1), -6);
+        assert_eq!(ability_modifier(30), 10);
+    }
+
+    #[test]
+    fn each_ability_check_uses_its_own_score() {
+        let actor = character(
+            json!({"str": {"value": 1}, "dex": {"value": 9}, "con": {"value": 10}, "int": {"value": 15}, "wis": {"value": 20}, "cha": {"value": 7}}),
+            json!({}),
+            json!([]),
+        );
+        for (stat, expected) in [("str", -5), ("dex", -1), ("con", 0), ("int", 2), ("wis", 5), ("Charisma", -2)] {
+            let check = actor.check(stat).unwrap();
+            assert_eq!(ability_mod(&check), expected, "{}", stat);
+            assert_eq!(proficiency(&check), None, "{}", stat);
+        }
+    }
+
+    #[test]
+    fn proficiency_multipliers() {
+        assert_eq!(Proficiency::new(3, 0.0).flat(), 0);
+        assert_eq!(Proficiency::new(3, 0.5).flat(), 1);
+        assert_eq!(Proficiency { bonus: 3, multiplier: 0.5, rounding: Rounding::Up }.flat(), 2);
+        assert_eq!(Proficiency::new(3, 1.0).flat(), 3);
+        assert_eq!(Proficiency::new(3, 2.0).flat(), 6);
+
+        let actor = with_flags(json!({}));
+        assert_eq!(proficiency(&actor.check("ath").unwrap()), None);
+        assert_eq!(proficiency(&actor.check("prc").unwrap()), Some(("Half proficiency", 1)));
+        assert_eq!(proficiency(&actor.check("stealth").unwrap()), Some(("Proficiency", 3)));
+        assert_eq!(proficiency(&actor.check("inv").unwrap()), Some(("Expertise", 6)));
+    }
+
+    #[test]
+    fn jack_of_all_trades_only_adds_to_checks_without_proficiency() {
+        let actor = with_flags(json!({"jackOfAllTrades": true}));
+        assert_eq!(proficiency(&actor.check("his").unwrap()), Some(("Half proficiency", 1)));
+        assert_eq!(proficiency(&actor.check("int").unwrap()), Some(("Half proficiency", 1)));
+        assert_eq!(proficiency(&actor.check("ste").unwrap()), Some(("Proficiency", 3)));
+        assert_eq!(proficiency(&actor.check("inv").unwrap()), Some(("Expertise", 6)));
+    }
+
+    #[test]
+    fn remarkable_athlete_only_adds_to_physical_checks() {
+        let actor = with_flags(json!({"remarkableAthlete": true}));
+        assert_eq!(proficiency(&actor.check("ath").unwrap()), Some(("Half proficiency", 2)));
+        assert_eq!(proficiency(&actor.check("con").unwrap()), Some(("Half proficiency", 2)));
+        assert_eq!(proficiency(&actor.check("his").unwrap()), None);
+        assert_eq!(proficiency(&actor.check("wis").unwrap()), None);
+    }
+
+    #[test]
+    fn reliable_talent_sets_a_minimum_on_proficient_checks() {
+        let actor = with_flags(json!({"reliableTalent": true}));
+        assert_eq!(actor.check("ste").unwrap().minimum, Some(10));
+        assert_eq!(actor.check("inv").unwrap().minimum, Some(10));
+        assert_eq!(actor.check("prc").unwrap().minimum, None);
+        assert_eq!(actor.check("ath").unwrap().minimum, None);
+        assert_eq!(with_flags(json!({})).check("ste").unwrap().minimum, None);
+    }
+
+    #[test]
+    fn silver_tongue_sets_a_minimum_on_deception_and_persuasion() {
+        let feat = json!([{"type": "feat", "_id": "feat", "name": "Silver Tongue", "flags": {}, "ownership": {"default": 0}, "system": {}}]);
+        let actor = character(average(), json!({}), feat);
+        assert_eq!(actor.check("dec").unwrap().minimum, Some(10));
+        assert_eq!(actor.check("persuasion").unwrap().minimum, Some(10));
+        assert_eq!(actor.check("ste").unwrap().minimum, None);
+        assert_eq!(with_flags(json!({})).check("dec").unwrap().minimum, None);
+    }
+
+    #[test]
+    fn proficiency_progression_follows_class_level() {
+        let progression = json!([{"type": "ScaleValue", "configuration": {"identifier": "prof", "scale": {"1": {"value": 1}, "3": {"value": 4}}}}]);
+        let class = |id: &str, levels: u8, advancement: &Value| json!({
+            "type": "class", "_id": id, "name": id, "flags": {}, "ownership": {"default": 0},
+            "system": {"levels": levels, "advancement": advancement},
+        });
+        // Level 2 in the homebrew class and 3 in another: the homebrew class's own level 2 step applies
+        let actor = character(average(), json!({}), json!([class("homebrew", 2, &progression), class("fighter", 3, &json!([]))]));
+        assert_eq!(actor.proficiency(), 1);
+
+        let actor = character(average(), json!({}), json!([class("fighter", 5, &json!([]))]));
+        assert_eq!(actor.proficiency(), 3);
+    }
+
+    #[test]
+    fn max_hp_is_derived_when_not_stored() {
+        let fighter = json!({
+            "type": "class", "_id": "fighter", "name": "Fighter", "flags": {}, "ownership": {"default": 0},
+            "system": {"levels": 2, "hitDice": "d10", "advancement": [{"type": "HitPoints", "value": {"1": "max", "2": 6}}]},
+        });
+        let wizard = json!({
+            "type": "class", "_id": "wizard", "name": "Wizard", "flags": {}, "ownership": {"default": 0},
+            "system": {"levels": 1, "hd": {"denomination": "d6"}},
+        });
+        let mut abilities = average();
+        abilities["con"]["value"] = json!(14);
+        let mut actor = character(abilities, json!({}), json!([fighter, wizard]));
+        assert_eq!(actor.max_hp(), Some(10));
+
+        let DND5EActor::character { system, .. } = &mut actor else { unreachable!() };
+        system.attributes.hp.max = None;
+        system.details.level = None;
+        // 10 and 6 from fighter, the wizard's average of 4, and 2 constitution for each of 3 levels
+        assert_eq!(actor.max_hp(), Some(26));
+
+        // Only the flat part of hit point bonuses counts, so the maximum doesn't change from one look to the next
+        let mut data = serde_json::to_value(&actor).unwrap();
+        data["system"]["attributes"]["hp"]["bonuses"] = json!({"level": "1 + 1d4", "overall": "1d6 + 2"});
+        let actor: DND5EActor = serde_json::from_value(data).unwrap();
+        assert_eq!(actor.max_hp(), Some(31));
+    }
+
+    #[test]
+    fn armor_class_follows_the_armor_worn() {
+        let armor = |kind: &str, value: i64, system: Value| {
+            let mut item = json!({
+                "type": "equipment", "_id": kind, "name": kind, "flags": {}, "ownership": {"default": 0},
+                "system": {"equipped": true, "type": {"value": kind}, "armor": {"value": value, "dex": null}},
+            });
+            overlay(&mut item["system"], system);
+            item
+        };
+        let mut abilities = average();
+        abilities["dex"]["value"] = json!(16);
+        let armored = |items: Value, ac: Value| {
+            let mut actor = serde_json::to_value(character(abilities.clone(), json!({}), items)).unwrap();
+            actor["system"]["attributes"]["ac"] = ac;
+            serde_json::from_value::<DND5EActor>(actor).unwrap()
+        };
+        let default = json!({"calc": "default", "flat": null, "formula": null});
+
+        assert_eq!(armored(json!([]), default.clone()).armor_class(), Some(13));
+        assert_eq!(armored(json!([armor("light", 11, json!({}))]), default.clone()).armor_class(), Some(14));
+        assert_eq!(armored(json!([armor("medium", 14, json!({"armor": {"dex": 2}}))]), default.clone()).armor_class(), Some(16));
+        // Heavy armor leaves dexterity out even when the item doesn't cap it
+        assert_eq!(armored(json!([armor("heavy", 18, json!({}))]), default.clone()).armor_class(), Some(18));
+        // A +1 shield and +2 plate, and a bonus whose dice are left out
+        let items = json!([armor("heavy", 18, json!({"armor": {"magicalBonus": 2}})), armor("shield", 2, json!({"armor": {"magicalBonus": 1}}))]);
+        assert_eq!(armored(items, json!({"calc": "default", "flat": null, "formula": null, "bonus": "1 + 1d4"})).armor_class(), Some(24));
+        assert_eq!(armored(json!([]), json!({"calc": "flat", "flat": 15, "formula": null, "bonus": "1"})).armor_class(), Some(15));
+        assert_eq!(armored(json!([]), json!({"calc": "custom", "flat": null, "formula": "12 + @abilities.dex.mod"})).armor_class(), Some(15));
+        assert_eq!(armored(json!([]), json!({"calc": "custom", "flat": null, "formula": "10 + 1d4"})).armor_class(), None);
+    }
+
+    #[test]
+    fn roll_data_keeps_what_isnt_modelled() {
+        let mut actor = serde_json::to_value(with_flags(json!({}))).unwrap();
+        actor["system"]["scale"] = json!({"rogue": {"sneak-attack": "3d6"}});
+        actor["system"]["resources"]["primary"] = json!({"value": 2, "max": 4, "label": "Grit"});
+        let actor: DND5EActor = serde_json::from_value(actor).unwrap();
+
+        let data = actor.roll_data();
+        assert_eq!(data.pointer("/abilities/str/mod"), Some(&json!(0)));
+        let roll = Roll::parse("@scale.rogue.sneak-attack + @details.level + @resources.primary.max + @prof", Some(&data)).unwrap();
+        assert_eq!(roll.formula(), "3d6 + 5 + 4 + 3");
+    }
+
+    #[test]
+    fn halfling_lucky_rerolls_ones() {
+        assert!(with_flags(json!({"halflingLucky": true})).check("ste").unwrap().reroll_ones);
+        assert!(with_flags(json!({"halflingLucky": true})).check("str").unwrap().reroll_ones);
+        assert!(!with_flags(json!({})).check("ste").unwrap().reroll_ones);
+    }
+
+    #[test]
+    fn unfit_token_actors_are_recorded() {
+        let token = |actor: Value| serde_json::from_value::<DND5EToken>(json!({"_id": "token", "actorId": "actor", "syntheticActor": actor})).unwrap();
+
+        let fitting = token(serde_json::to_value(with_flags(json!({}))).unwrap());
+        assert!(fitting.actor.valid().is_some());
+        assert!(fitting.skipped_actor().is_none());
+
+        let unfit = token(json!({"type": "character", "_id": "actor", "name": "Tester", "system": {"abilities": 3}}));
+        assert!(unfit.actor.valid().is_none());
+        let skipped = unfit.skipped_actor().unwrap();
+        assert_eq!(skipped.name.as_deref(), Some("Tester"));
+        assert!(!skipped.error.is_empty());
+    }
+}
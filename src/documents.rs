@@ -0,0 +1,164 @@
+use rust_socketio::Payload;
+use serde_json::{json, Map, Value};
+use crate::connection::FoundryClient;
+use crate::error::FoundryClientError;
+use crate::world::BaseToken;
+
+/// What a modifyDocument request does
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Delete => "delete",
+        }
+    }
+
+    /// The key foundry expects the documents of this action under
+    fn key(self) -> &'static str {
+        match self {
+            Action::Create => "data",
+            Action::Update => "updates",
+            Action::Delete => "ids",
+        }
+    }
+}
+
+/// The uuid of a world level document, for use as the parent of embedded documents
+pub fn uuid(document_type: &str, id: &str) -> String {
+    format!("{}.{}", document_type, id)
+}
+
+impl FoundryClient {
+    /// Create, update or delete documents of a type, optionally embedded within a parent (given by uuid).
+    /// Returns the documents foundry reports as affected
+    pub async fn modify_document(&self, document_type: &str, action: Action, parent: Option<&str>, documents: Vec<Value>) -> Result<Vec<Value>, FoundryClientError> {
+        // Core 12 wrapped everything describing the change into an "operation"
+        let request = if self.versions().core.generation >= 12 {
+            json!({
+                "type": document_type,
+                "action": action.name(),
+                "operation": {
+                    "action": action.name(),
+                    action.key(): documents,
+                    "parentUuid": parent,
+                    "pack": null,
+                    "modifiedTime": now_millis(),
+                }
+            })
+        } else {
+            json!({
+                "type": document_type,
+                "action": action.name(),
+                action.key(): documents,
+                "options": {},
+                "parentUuid": parent,
+                "pack": null,
+            })
+        };
+
+        let response = self.request("modifyDocument", Payload::Text(vec![request])).await?;
+        response_documents(response)
+    }
+
+    pub async fn create_documents(&self, document_type: &str, parent: Option<&str>, data: Vec<Value>) -> Result<Vec<Value>, FoundryClientError> {
+        self.modify_document(document_type, Action::Create, parent, data).await
+    }
+
+    /// Each update must include the _id of the document it changes. Keys may be dotted paths, e.g. "system.attributes.hp.value"
+    pub async fn update_documents(&self, document_type: &str, parent: Option<&str>, updates: Vec<Value>) -> Result<Vec<Value>, FoundryClientError> {
+        self.modify_document(document_type, Action::Update, parent, updates).await
+    }
+
+    pub async fn delete_documents(&self, document_type: &str, parent: Option<&str>, ids: Vec<String>) -> Result<Vec<Value>, FoundryClientError> {
+        self.modify_document(document_type, Action::Delete, parent, ids.into_iter().map(Value::from).collect()).await
+    }
+
+    /// Fetch documents of a type matching a query, optionally from a compendium pack.
+    /// With index set, only the index fields (plus _id, name and type) of each document are returned
+    pub async fn get_documents(&self, document_type: &str, pack: Option<&str>, query: Value, index: Option<&[&str]>) -> Result<Vec<Value>, FoundryClientError> {
+        let index_fields = index.unwrap_or_default();
+        let request = if self.versions().core.generation >= 12 {
+            json!({
+                "type": document_type,
+                "action": "get",
+                "operation": {
+                    "action": "get",
+                    "query": query,
+                    "index": index.is_some(),
+                    "indexFields": index_fields,
+                    "pack": pack,
+                }
+            })
+        } else {
+            json!({
+                "type": document_type,
+                "action": "get",
+                "query": query,
+                "options": {
+                    "index": index.is_some(),
+                    "indexFields": index_fields,
+                },
+                "pack": pack,
+            })
+        };
+
+        let response = self.request("modifyDocument", Payload::Text(vec![request])).await?;
+        response_documents(response)
+    }
+
+    /// Update the actor a token stands for, given dotted paths. Unlinked tokens keep changes to their actor in their own delta
+    pub async fn update_token_actor(&self, scene_id: &str, token: &BaseToken, changes: Map<String, Value>) -> Result<Vec<Value>, FoundryClientError> {
+        if token.actor_link {
+            let actor_id = token.actor_id.as_deref().ok_or(FoundryClientError::DocumentError("The token has no actor".into()))?;
+            let mut update = changes;
+            update.insert("_id".into(), actor_id.into());
+            self.update_documents("Actor", None, vec![Value::Object(update)]).await
+        } else {
+            let token_id = token.id.as_deref().ok_or(FoundryClientError::DocumentError("The token has no id".into()))?;
+            let mut update: Map<String, Value> = changes.into_iter()
+                .map(|(key, value)| (format!("delta.{}", key), value))
+                .collect();
+            update.insert("_id".into(), token_id.into());
+            self.update_documents("Token", Some(&uuid("Scene", scene_id)), vec![Value::Object(update)]).await
+        }
+    }
+
+    /// Post a chat message as our user
+    pub async fn create_chat_message(&self, content: &str, flavor: Option<&str>) -> Result<(), FoundryClientError> {
+        let message = json!({
+            "content": content,
+            "flavor": flavor,
+            "author": self.user_id(),
+            "user": self.user_id(),
+        });
+        self.create_documents("ChatMessage", None, vec![message]).await?;
+        Ok(())
+    }
+}
+
+/// Pull the affected documents out of a modifyDocument response, or the reason there are none
+fn response_documents(response: Value) -> Result<Vec<Value>, FoundryClientError> {
+    if let Some(error) = response.get("error") {
+        let message = error.get("message").and_then(Value::as_str)
+            .map(str::to_owned)
+            .unwrap_or_else(|| error.to_string());
+        return Err(FoundryClientError::DocumentError(message));
+    }
+    Ok(response.get("result").and_then(Value::as_array).cloned().unwrap_or_default())
+}
+
+/// Milliseconds since the epoch, which foundry stamps modifications with
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
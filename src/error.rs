@@ -1,4 +1,4 @@
-use std::fmt::{Display};
+use serde_path_to_error::Segment;
 use thiserror::Error;
 
 /// All the errors that can occur
@@ -11,26 +11,197 @@ pub enum FoundryClientError {
     #[error("HTTP Connection error: {0}")]
     JoinError(#[from] reqwest::Error),
     #[error("Socket Connection error: {0}")]
-    SocketError(#[from] rust_socketio::Error),
+    SocketError(Box<rust_socketio::Error>),
     #[error("Failed to login as user: {0}")]
     NoUserError(String),
     #[error("Failed to parse initial userdata: {path} within {value} is not formatted as expected - perhaps a version incompatibility?")]
-    MalformedData {path: String, value: serde_json::Value}
+    MalformedData {path: String, value: serde_json::Value},
+    #[error("Provided host {0} cannot have paths appended to it")]
+    InvalidHost(String),
+    #[error("The server did not give us a session cookie - is this a Foundry server?")]
+    MissingSession,
+    #[error("Login was refused: {0}")]
+    LoginFailed(String),
+    #[error("Timed out waiting for the server to respond to {0}")]
+    Timeout(String),
+    #[error("The server responded to {0} with something other than json")]
+    UnexpectedPayload(String),
+    #[error("Foundry refused to change a document: {0}")]
+    DocumentError(String),
+    #[error("Could not tell which {0} version the server is running")]
+    UnknownVersion(String),
+    #[error("Foundry {core} with {system} is not supported. Supported are dnd5e 3.x on Foundry 11-12, and dnd5e 4.x-5.x on Foundry 12-13")]
+    Unsupported { core: String, system: String },
+}
+
+/// Socket errors are boxed, being far larger than every other variant
+impl From<rust_socketio::Error> for FoundryClientError {
+    fn from(err: rust_socketio::Error) -> Self {
+        FoundryClientError::SocketError(Box::new(err))
+    }
+}
+
+impl FoundryClientError {
+    /// Build a MalformedData from a failed deserialization of root, pointing at the deepest value we can still locate
+    pub fn malformed(err: serde_path_to_error::Error<serde_json::Error>, root: &serde_json::Value) -> Self {
+        let mut value = root;
+        for segment in err.path().iter() {
+            let next = match segment {
+                Segment::Seq { index } => value.get(index),
+                Segment::Map { key } => value.get(key),
+                _ => None,
+            };
+            match next {
+                Some(next) => value = next,
+                None => break,
+            }
+        }
+        FoundryClientError::MalformedData {
+            path: format!("{} ({})", err.path(), err.inner()),
+            value: value.clone(),
+        }
+    }
+
+    /// Whether trying again later might succeed. Bad credentials or hosts won't fix themselves
+    pub fn is_retryable(&self) -> bool {
+        matches!(self,
+            FoundryClientError::JoinError(_)
+            | FoundryClientError::SocketError(_)
+            | FoundryClientError::Timeout(_)
+            | FoundryClientError::MissingSession
+        )
+    }
 }
 
 /// Specific errors with discord commands
 #[derive(Error, Debug)]
 pub enum CommandError {
     /// Associating a character failed
-    #[error("No actor named '{0}' found. Be careful about case sensitivity")]
+    #[error("No actor named '{0}' found. Pick one of the suggested names")]
     CharacterNotFound(String),
     /// No character was associated
     #[error("You must first /assoc with a character name in the foundry world")]
     MissingAssocChar,
+    /// A nickname was provided that the user has not associated with any actor
+    #[error("You have no character nicknamed '{0}'. /assoc it first, or check /characters")]
+    UnknownNickname(String),
     /// An associated character was provided but could not be resolved / is not valid for this operation
     #[error("Your currently associated character is invalid/deleted. /assoc with a new character in the foundry world")]
     InvalidAssocChar,
     /// A provided stat or attribute
     #[error("The attribute you tried to roll ({0}) was not recognized")]
     InvalidAttribute(String),
-}
\ No newline at end of file
+    /// GM commands were used without a GM role being configured
+    #[error("No GM role is configured. Start the bot with --gm-role")]
+    NoGmRole,
+    /// GM commands were used by someone without the GM role
+    #[error("Only GMs can do that")]
+    NotGm,
+    /// A scene was asked for that doesn't exist
+    #[error("No scene named '{0}' found")]
+    SceneNotFound(String),
+    /// An item was asked for that doesn't exist
+    #[error("No item named '{0}' found")]
+    ItemNotFound(String),
+    /// A journal entry was asked for that doesn't exist or can't be read
+    #[error("No journal entry named '{0}' that you can read")]
+    JournalNotFound(String),
+    /// Nothing in the compendiums matched a lookup
+    #[error("Nothing named '{0}' found in the compendiums")]
+    LookupNotFound(String),
+    /// A roll table was asked for that doesn't exist or can't be seen
+    #[error("No table named '{0}' that you can see")]
+    TableNotFound(String),
+    /// Every result of a table without replacement has been drawn
+    #[error("Every result of {0} has been drawn. Reset the table in foundry to draw from it again")]
+    TableExhausted(String),
+    /// Rolling a table's formula kept landing on results that can't be drawn
+    #[error("Couldn't draw anything from {0}. Check that its formula covers its results")]
+    TableNoResult(String),
+    /// A macro was asked for that doesn't exist or can't be run
+    #[error("No macro named '{0}' that you can run")]
+    MacroNotFound(String),
+    /// The bot's foundry user isn't trusted with script macros
+    #[error("The bot's foundry user isn't allowed to run script macros")]
+    ScriptMacrosForbidden,
+    /// A script macro threw, or couldn't be run
+    #[error("The macro failed: {0}")]
+    MacroFailed(String),
+    /// A script macro ran for too long and was stopped
+    #[error("The macro took longer than {0} seconds and was stopped")]
+    MacroTimeout(u64),
+    /// A token was asked for that isn't on the scene
+    #[error("No token '{0}' on the active scene")]
+    TokenNotFound(String),
+    /// A token doesn't stand for any actor we can read
+    #[error("{0} has no actor that can be read")]
+    TokenWithoutActor(String),
+    /// A scene has no grid to count cells on
+    #[error("{0} has no grid to move on")]
+    Gridless(String),
+    /// A direction that the scene's grid has no neighbour in, like north on a hex grid with pointy tops
+    #[error("Can't move {0} on this grid")]
+    InvalidDirection(String),
+    /// A cell that couldn't be read
+    #[error("'{0}' isn't a cell. Use column,row or a letter and number, e.g. C5")]
+    InvalidCell(String),
+    /// A cell beyond the edges of the scene
+    #[error("({0}) is off the map")]
+    CellOffMap(String),
+    /// The user's actor has no token on the active scene
+    #[error("Your character has no token on the active scene")]
+    NoOwnToken,
+    /// A move would pass through a wall
+    #[error("A wall is in the way")]
+    BlockedByWall,
+    /// The actor attacking has no such weapon
+    #[error("No weapon named '{0}' on your character")]
+    WeaponNotFound(String),
+    /// The actor has no such item, or none that the command works on
+    #[error("No item named '{0}' on your character that can be used like that")]
+    ItemNotCarried(String),
+    /// A consumable is used up
+    #[error("{0} has nothing left to use")]
+    NothingLeft(String),
+    /// Containers are only given away empty
+    #[error("Empty {0} before giving it away")]
+    ContainerNotEmpty(String),
+    /// More of an item was asked for than the actor has
+    #[error("You only have {1} of {0}")]
+    NotEnough(String, i64),
+    /// The actor has no resource or feature with limited uses by that name
+    #[error("No resource or limited-use feature named '{0}'")]
+    PoolNotFound(String),
+    /// More uses were spent than are left
+    #[error("{0} only has {1} uses left")]
+    NotEnoughUses(String, i64),
+    /// Death saves are only rolled at 0 hit points
+    #[error("{0} isn't dying")]
+    NotDying(String),
+    /// Three death saves have already failed
+    #[error("{0} has already failed three death saves")]
+    AlreadyDead(String),
+    /// Drawing a map failed
+    #[error("Couldn't draw the map: {0}")]
+    RenderFailed(String),
+    /// The user we log into foundry as is missing from the world
+    #[error("The bot's foundry user could not be found in the world")]
+    InvalidFoundryUser,
+    /// No users have characters assigned in foundry
+    #[error("No players have a character assigned in foundry")]
+    NoParty,
+}
+/// Problems with a dice formula
+#[derive(Error, Debug)]
+pub enum DiceError {
+    #[error("Unexpected '{found}' at character {position} of the formula")]
+    Unexpected { found: char, position: usize },
+    #[error("The formula ended unexpectedly - is something missing?")]
+    UnexpectedEnd,
+    #[error("The formula refers to @{0}, which doesn't exist on your character")]
+    UnknownReference(String),
+    #[error("Formulas do not support {0} yet")]
+    Unsupported(String),
+    #[error("Invalid dice: {0}")]
+    Invalid(String),
+}
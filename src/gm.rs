@@ -0,0 +1,379 @@
+use poise::serenity_prelude as serenity;
+use rust_socketio::Payload;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use crate::autocomplete::{autocomplete_actor, autocomplete_scene, autocomplete_token, fuzzy_filter};
+use crate::dice::Roll;
+use crate::dnd5e::{CharacterSystem, DND5EActor};
+use crate::documents::uuid;
+use crate::error::CommandError;
+use crate::rolls::render_roll;
+use crate::saves::concentration_prompt;
+use crate::store::Settings;
+use crate::tokens::{active_scene, damage_changes, find_token, sheet_embed, token_actor, token_label};
+use crate::world::UserRole;
+use crate::{get_raw_world, get_world, Context, DiscordError};
+
+/// Where GM actions are recorded
+const AUDIT_LOG: &str = "audit.log";
+
+/// Only members holding the configured GM role may use these commands
+async fn is_gm(ctx: Context<'_>) -> Result<bool, DiscordError> {
+    ctx.data().gm_role.ok_or(CommandError::NoGmRole)?;
+    if !has_gm_role(ctx).await {
+        Err(CommandError::NotGm)?;
+    }
+    Ok(true)
+}
+
+/// Whether the author holds the configured GM role, for commands that show GMs more
+pub async fn has_gm_role(ctx: Context<'_>) -> bool {
+    let Some(role) = ctx.data().gm_role else { return false };
+    ctx.author_member().await.is_some_and(|member| member.roles.contains(&role))
+}
+
+/// Record who did what, both to stdout and the audit log
+pub async fn audit(ctx: Context<'_>, action: String) {
+    let line = format!("{} {} ({}): {}\n",
+        serenity::Timestamp::now(), ctx.author().name, ctx.author().id, action);
+    print!("AUDIT {}", line);
+    let file = tokio::fs::OpenOptions::new().create(true).append(true).open(AUDIT_LOG).await;
+    if let Ok(mut file) = file {
+        let _ = file.write_all(line.as_bytes()).await;
+    }
+}
+
+/// Game master tools
+#[poise::command(
+    slash_command,
+    subcommands("pause", "unpause", "scene", "token", "users", "xp", "grant", "announce", "autodamage"),
+    subcommand_required,
+    check = "is_gm"
+)]
+pub async fn gm(_ctx: Context<'_>) -> Result<(), DiscordError> {
+    Ok(())
+}
+
+async fn set_paused(ctx: Context<'_>, paused: bool) -> Result<(), DiscordError> {
+    ctx.data().foundry.broadcast("pause", Payload::Text(vec![json!(paused)])).await?;
+    audit(ctx, format!("set paused to {}", paused)).await;
+    ctx.say(if paused { "Game paused" } else { "Game unpaused" }).await?;
+    Ok(())
+}
+
+/// Pauses the game
+#[poise::command(slash_command, check = "is_gm")]
+async fn pause(ctx: Context<'_>) -> Result<(), DiscordError> {
+    set_paused(ctx, true).await
+}
+
+/// Unpauses the game
+#[poise::command(slash_command, check = "is_gm")]
+async fn unpause(ctx: Context<'_>) -> Result<(), DiscordError> {
+    set_paused(ctx, false).await
+}
+
+/// Scene management
+#[poise::command(slash_command, subcommands("activate", "view"), subcommand_required, check = "is_gm")]
+async fn scene(_ctx: Context<'_>) -> Result<(), DiscordError> {
+    Ok(())
+}
+
+/// Activates a scene for all players
+#[poise::command(slash_command, check = "is_gm")]
+async fn activate(
+    ctx: Context<'_>,
+    #[description = "Scene name"]
+    #[autocomplete = "autocomplete_scene"]
+    name: String,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let scene = world.scenes.iter()
+        .find(|scene| scene.document.name == name)
+        .ok_or(CommandError::SceneNotFound(name.clone()))?;
+    let scene_id = scene.document.id.as_deref().unwrap_or_default();
+    foundry.update_documents("Scene", None, vec![json!({"_id": scene_id, "active": true})]).await?;
+    audit(ctx, format!("activated scene {} ({})", scene.document.name, scene_id)).await;
+    ctx.say(format!("Activated {}", scene.document.name)).await?;
+    Ok(())
+}
+
+/// Shows a scene, or the active scene if none is given
+#[poise::command(slash_command, check = "is_gm")]
+async fn view(
+    ctx: Context<'_>,
+    #[description = "Scene name"]
+    #[autocomplete = "autocomplete_scene"]
+    name: Option<String>,
+) -> Result<(), DiscordError> {
+    let world = get_world(&ctx.data().foundry).await?;
+    let scene = match &name {
+        Some(name) => world.scenes.iter().find(|scene| &scene.document.name == name),
+        None => world.scenes.iter().find(|scene| scene.active),
+    }.ok_or(CommandError::SceneNotFound(name.unwrap_or("active scene".into())))?;
+
+    let embed = serenity::CreateEmbed::new()
+        .title(&scene.document.name)
+        .field("Active", if scene.active { "Yes" } else { "No" }, true)
+        .field("Tokens", scene.tokens.len().to_string(), true);
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// Tokens on the active scene, acting on the actor each token stands for
+#[poise::command(slash_command, subcommands("sheet", "damage", "check"), subcommand_required, check = "is_gm")]
+async fn token(_ctx: Context<'_>) -> Result<(), DiscordError> {
+    Ok(())
+}
+
+/// Shows a token's sheet
+#[poise::command(slash_command, check = "is_gm")]
+async fn sheet(
+    ctx: Context<'_>,
+    #[description = "Token"]
+    #[autocomplete = "autocomplete_token"]
+    token: String,
+) -> Result<(), DiscordError> {
+    let world = get_world(&ctx.data().foundry).await?;
+    let scene = active_scene(&world)?;
+    let found = find_token(scene, &token)?;
+    let label = token_label(scene, found);
+    let actor = token_actor(&world, found).ok_or(CommandError::TokenWithoutActor(label.clone()))?;
+    ctx.send(poise::CreateReply::default().embed(sheet_embed(&label, actor))).await?;
+    Ok(())
+}
+
+/// Damages a token, or heals it with a negative amount
+#[poise::command(slash_command, check = "is_gm")]
+async fn damage(
+    ctx: Context<'_>,
+    #[description = "Token"]
+    #[autocomplete = "autocomplete_token"]
+    token: String,
+    #[description = "Damage to deal. Negative heals"] amount: i64,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let scene = active_scene(&world)?;
+    let found = find_token(scene, &token)?;
+    let label = token_label(scene, found);
+    let actor = token_actor(&world, found).ok_or(CommandError::TokenWithoutActor(label.clone()))?;
+
+    let (changes, hp) = damage_changes(actor, amount);
+    let scene_id = scene.document.id.as_deref().unwrap_or_default();
+    foundry.update_token_actor(scene_id, &found.base, changes).await?;
+    audit(ctx, format!("dealt {} damage to {} ({})", amount, label, found.base.id.as_deref().unwrap_or_default())).await;
+    ctx.say(format!("{} is at {} HP", label, hp)).await?;
+    concentration_prompt(ctx, &label, actor, amount, hp).await
+}
+
+/// Rolls a formula for a token, with @ references to its actor's data
+#[poise::command(slash_command, check = "is_gm")]
+async fn check(
+    ctx: Context<'_>,
+    #[description = "Token"]
+    #[autocomplete = "autocomplete_token"]
+    token: String,
+    #[description = "Formula, e.g. 1d20 + @abilities.dex.mod"] formula: String,
+) -> Result<(), DiscordError> {
+    let world = get_world(&ctx.data().foundry).await?;
+    let scene = active_scene(&world)?;
+    let found = find_token(scene, &token)?;
+    let label = token_label(scene, found);
+    let actor = token_actor(&world, found).ok_or(CommandError::TokenWithoutActor(label.clone()))?;
+
+    let roll = Roll::parse(&formula, Some(&actor.roll_data()))?.evaluate();
+    let embed = render_roll(&format!("{}: {}", label, formula), &roll);
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// Lists the users currently connected to foundry
+#[poise::command(slash_command, check = "is_gm")]
+async fn users(ctx: Context<'_>) -> Result<(), DiscordError> {
+    let world = get_world(&ctx.data().foundry).await?;
+    let names: Vec<String> = world.active_users.iter()
+        .map(|id| match world.user(id) {
+            Some(user) if user.role >= UserRole::Assistant => format!("{} (GM)", user.name),
+            Some(user) => user.name.clone(),
+            None => id.clone(),
+        })
+        .collect();
+    if names.is_empty() {
+        ctx.say("Nobody is connected").await?;
+    } else {
+        ctx.say(format!("Connected: {}", names.join(", "))).await?;
+    }
+    Ok(())
+}
+
+/// Awards experience to every player's assigned character
+#[poise::command(slash_command, check = "is_gm")]
+async fn xp(
+    ctx: Context<'_>,
+    #[description = "Experience to award each character"] amount: i64,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+
+    let party: Vec<(&str, &CharacterSystem, &str)> = world.users.iter()
+        .filter(|user| user.role < UserRole::Assistant)
+        .filter_map(|user| user.character.as_deref())
+        .filter_map(|id| world.actors.iter().find_map(|actor| match actor {
+            DND5EActor::character { base, system } if base.document.id.as_deref() == Some(id) => {
+                Some((id, &**system, base.document.name.as_str()))
+            }
+            _ => None,
+        }))
+        .collect();
+    if party.is_empty() {
+        Err(CommandError::NoParty)?;
+    }
+
+    let updates = party.iter()
+        .map(|(id, system, _)| json!({"_id": id, "system.details.xp.value": system.details.xp.value + amount}))
+        .collect();
+    foundry.update_documents("Actor", None, updates).await?;
+
+    let names: Vec<&str> = party.iter().map(|(_, _, name)| *name).collect();
+    audit(ctx, format!("awarded {} xp to {}", amount, names.join(", "))).await;
+    ctx.say(format!("Awarded {} XP to {}", amount, names.join(", "))).await?;
+    Ok(())
+}
+
+/// Gives things to characters
+#[poise::command(slash_command, subcommands("item", "currency"), subcommand_required, check = "is_gm")]
+async fn grant(_ctx: Context<'_>) -> Result<(), DiscordError> {
+    Ok(())
+}
+
+/// Suggests the names of world items
+async fn autocomplete_world_item(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let Ok(raw_world) = get_raw_world(&ctx.data().foundry).await else { return vec![] };
+    let names = raw_world.get("items")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.get("name").and_then(Value::as_str))
+        .map(str::to_owned);
+    fuzzy_filter(names, partial, |name| name.as_str())
+}
+
+/// Find an actor's id by name
+fn find_actor_id(actors: &[DND5EActor], name: &str) -> Result<String, CommandError> {
+    actors.iter()
+        .filter_map(|actor| actor.base())
+        .find(|base| base.document.name == name)
+        .and_then(|base| base.document.id.clone())
+        .ok_or(CommandError::CharacterNotFound(name.into()))
+}
+
+/// Copies an item from the world's item directory onto an actor
+#[poise::command(slash_command, check = "is_gm")]
+async fn item(
+    ctx: Context<'_>,
+    #[description = "Item name"]
+    #[autocomplete = "autocomplete_world_item"]
+    name: String,
+    #[description = "Actor to give it to"]
+    #[autocomplete = "autocomplete_actor"]
+    actor: String,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let actor_id = find_actor_id(&world.actors, &actor)?;
+
+    // Use the raw item, so that fields we don't model survive the copy
+    let raw_world = get_raw_world(foundry).await?;
+    let mut data = raw_world.get("items")
+        .and_then(Value::as_array)
+        .and_then(|items| items.iter().find(|item| item.get("name").and_then(Value::as_str) == Some(name.as_str())))
+        .cloned()
+        .ok_or(CommandError::ItemNotFound(name.clone()))?;
+    if let Some(data) = data.as_object_mut() {
+        data.remove("_id");
+        data.remove("folder");
+    }
+
+    foundry.create_documents("Item", Some(&uuid("Actor", &actor_id)), vec![data]).await?;
+    audit(ctx, format!("granted {} to {} ({})", name, actor, actor_id)).await;
+    ctx.say(format!("Gave {} to {}", name, actor)).await?;
+    Ok(())
+}
+
+#[derive(poise::ChoiceParameter, Clone, Copy)]
+enum Denomination {
+    #[name = "pp"]
+    Platinum,
+    #[name = "gp"]
+    Gold,
+    #[name = "ep"]
+    Electrum,
+    #[name = "sp"]
+    Silver,
+    #[name = "cp"]
+    Copper,
+}
+
+/// Adds (or with a negative amount, removes) currency from a character
+#[poise::command(slash_command, check = "is_gm")]
+async fn currency(
+    ctx: Context<'_>,
+    #[description = "Character to give it to"]
+    #[autocomplete = "autocomplete_actor"]
+    actor: String,
+    #[description = "Amount"] amount: i64,
+    #[description = "Denomination"] denomination: Denomination,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let (actor_id, system) = world.actors.iter().find_map(|candidate| match candidate {
+        DND5EActor::character { base, system } if base.document.name == actor => {
+            Some((base.document.id.clone()?, system))
+        }
+        _ => None,
+    }).ok_or(CommandError::CharacterNotFound(actor.clone()))?;
+
+    let (key, current) = match denomination {
+        Denomination::Platinum => ("pp", system.currency.pp),
+        Denomination::Gold => ("gp", system.currency.gp),
+        Denomination::Electrum => ("ep", system.currency.ep),
+        Denomination::Silver => ("sp", system.currency.sp),
+        Denomination::Copper => ("cp", system.currency.cp),
+    };
+    let total = (current + amount).max(0);
+    foundry.update_documents("Actor", None, vec![json!({"_id": actor_id, format!("system.currency.{}", key): total})]).await?;
+    audit(ctx, format!("changed {} of {} ({}) by {}", key, actor, actor_id, amount)).await;
+    ctx.say(format!("{} now has {} {}", actor, total, key)).await?;
+    Ok(())
+}
+
+/// Posts an announcement to the foundry chat
+#[poise::command(slash_command, check = "is_gm")]
+async fn announce(
+    ctx: Context<'_>,
+    #[description = "Message"] message: String,
+) -> Result<(), DiscordError> {
+    ctx.data().foundry.create_chat_message(&message, Some("Announcement")).await?;
+    audit(ctx, format!("announced \"{}\"", message)).await;
+    ctx.say("Announced").await?;
+    Ok(())
+}
+
+/// Sets whether hits made with /attack damage their target automatically
+#[poise::command(slash_command, check = "is_gm")]
+async fn autodamage(
+    ctx: Context<'_>,
+    #[description = "Apply damage from hits"] enabled: bool,
+) -> Result<(), DiscordError> {
+    {
+        let mut store = ctx.data().store.lock().await;
+        let mut settings = Settings::load(&store);
+        settings.auto_apply_damage = enabled;
+        settings.save(&mut store)?;
+    }
+    audit(ctx, format!("set automatic attack damage to {}", enabled)).await;
+    ctx.say(if enabled { "Hits now apply their damage" } else { "Hits no longer apply their damage" }).await?;
+    Ok(())
+}
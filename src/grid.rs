@@ -0,0 +1,315 @@
+use poise::ChoiceParameter;
+use crate::error::CommandError;
+use crate::world::{GridType, Scene};
+
+/// A direction to step in. Square grids allow all eight, hex grids the six their hexes have sides in
+#[derive(poise::ChoiceParameter, Clone, Copy, PartialEq, Debug)]
+pub enum Direction {
+    #[name = "north"]
+    North,
+    #[name = "north-east"]
+    NorthEast,
+    #[name = "east"]
+    East,
+    #[name = "south-east"]
+    SouthEast,
+    #[name = "south"]
+    South,
+    #[name = "south-west"]
+    SouthWest,
+    #[name = "west"]
+    West,
+    #[name = "north-west"]
+    NorthWest,
+}
+
+/// Converts between grid cells, as (column, row) from the top left of the map, and canvas pixels
+pub struct Geometry {
+    kind: GridType,
+    size: f64,
+    offset: (f64, f64),
+    /// Size of the whole canvas, map and padding both
+    canvas: (f64, f64),
+}
+
+impl Geometry {
+    pub fn new<TokenType>(scene: &Scene<TokenType>) -> Result<Self, CommandError> {
+        if scene.grid.kind == GridType::Gridless {
+            return Err(CommandError::Gridless(scene.document.name.clone()));
+        }
+        let offset = scene.offset();
+        let canvas = (scene.width as f64 + 2.0 * offset.0, scene.height as f64 + 2.0 * offset.1);
+        Ok(Geometry { kind: scene.grid.kind, size: scene.grid.size.max(1.0), offset, canvas })
+    }
+
+    /// Whether hexes are in rows (pointy tops) rather than columns (flat tops)
+    fn rows(&self) -> bool {
+        matches!(self.kind, GridType::HexOddRows | GridType::HexEvenRows)
+    }
+
+    /// Whether a row (or column, for column grids) is pushed along by half a hex
+    fn shifted(&self, index: i64) -> bool {
+        let odd = index.rem_euclid(2) == 1;
+        match self.kind {
+            GridType::HexOddRows | GridType::HexOddColumns => odd,
+            GridType::HexEvenRows | GridType::HexEvenColumns => !odd,
+            _ => false,
+        }
+    }
+
+    /// The width and height of one cell's bounding box. Foundry sizes hexes by the distance between their flat sides
+    fn cell_size(&self) -> (f64, f64) {
+        let long = self.size * 2.0 / 3f64.sqrt();
+        match self.kind {
+            GridType::Square | GridType::Gridless => (self.size, self.size),
+            _ if self.rows() => (self.size, long),
+            _ => (long, self.size),
+        }
+    }
+
+    /// Whether a cell lies wholly on the canvas, padding included. Scenes without a size are taken to fit anything
+    pub fn contains(&self, cell: (i64, i64)) -> bool {
+        if self.canvas.0 <= 0.0 || self.canvas.1 <= 0.0 {
+            return true;
+        }
+        let (width, height) = self.cell_size();
+        let (x, y) = self.top_left(cell);
+        // Leave a little slack for rounding in the hex arithmetic
+        let slack = 0.01;
+        x >= -slack && y >= -slack && x + width <= self.canvas.0 + slack && y + height <= self.canvas.1 + slack
+    }
+
+    /// The top left corner of a cell's bounding box, which is where a token in it is placed
+    pub fn top_left(&self, (column, row): (i64, i64)) -> (f64, f64) {
+        let (width, height) = self.cell_size();
+        let (x, y) = match self.kind {
+            GridType::Square | GridType::Gridless => (column as f64 * width, row as f64 * height),
+            // Hexes interlock, so each row (or column) only advances three quarters of a hex
+            _ if self.rows() => {
+                let shift = if self.shifted(row) { width / 2.0 } else { 0.0 };
+                (column as f64 * width + shift, row as f64 * height * 0.75)
+            }
+            _ => {
+                let shift = if self.shifted(column) { height / 2.0 } else { 0.0 };
+                (column as f64 * width * 0.75, row as f64 * height + shift)
+            }
+        };
+        (x + self.offset.0, y + self.offset.1)
+    }
+
+    pub fn center(&self, cell: (i64, i64)) -> (f64, f64) {
+        let (width, height) = self.cell_size();
+        let (x, y) = self.top_left(cell);
+        (x + width / 2.0, y + height / 2.0)
+    }
+
+    /// The corners of a cell, clockwise
+    pub fn outline(&self, cell: (i64, i64)) -> Vec<(f64, f64)> {
+        let (width, height) = self.cell_size();
+        let (x, y) = self.center(cell);
+        let (w, h) = (width / 2.0, height / 2.0);
+        match self.kind {
+            GridType::Square | GridType::Gridless => vec![(x - w, y - h), (x + w, y - h), (x + w, y + h), (x - w, y + h)],
+            _ if self.rows() => vec![(x, y - h), (x + w, y - h / 2.0), (x + w, y + h / 2.0), (x, y + h), (x - w, y + h / 2.0), (x - w, y - h / 2.0)],
+            _ => vec![(x - w, y), (x - w / 2.0, y - h), (x + w / 2.0, y - h), (x + w, y), (x + w / 2.0, y + h), (x - w / 2.0, y + h)],
+        }
+    }
+
+    /// The cell whose bounding box starts nearest a point, such as a token's position
+    pub fn cell_at(&self, (x, y): (f64, f64)) -> (i64, i64) {
+        let (width, height) = self.cell_size();
+        let (x, y) = (x - self.offset.0, y - self.offset.1);
+        match self.kind {
+            GridType::Square | GridType::Gridless => ((x / width).round() as i64, (y / height).round() as i64),
+            _ if self.rows() => {
+                let row = (y / (height * 0.75)).round() as i64;
+                let shift = if self.shifted(row) { width / 2.0 } else { 0.0 };
+                (((x - shift) / width).round() as i64, row)
+            }
+            _ => {
+                let column = (x / (width * 0.75)).round() as i64;
+                let shift = if self.shifted(column) { height / 2.0 } else { 0.0 };
+                (column, ((y - shift) / height).round() as i64)
+            }
+        }
+    }
+
+    /// The cell next to another in a direction
+    pub fn neighbour(&self, (column, row): (i64, i64), direction: Direction) -> Result<(i64, i64), CommandError> {
+        use Direction::*;
+        let step = match self.kind {
+            GridType::Square | GridType::Gridless => match direction {
+                North => (0, -1),
+                NorthEast => (1, -1),
+                East => (1, 0),
+                SouthEast => (1, 1),
+                South => (0, 1),
+                SouthWest => (-1, 1),
+                West => (-1, 0),
+                NorthWest => (-1, -1),
+            },
+            // Diagonal neighbours depend on whether this row is the one pushed along
+            _ if self.rows() => {
+                let right = if self.shifted(row) { 1 } else { 0 };
+                match direction {
+                    East => (1, 0),
+                    West => (-1, 0),
+                    NorthEast => (right, -1),
+                    NorthWest => (right - 1, -1),
+                    SouthEast => (right, 1),
+                    SouthWest => (right - 1, 1),
+                    North | South => return Err(CommandError::InvalidDirection(direction.name().into())),
+                }
+            }
+            _ => {
+                let down = if self.shifted(column) { 1 } else { 0 };
+                match direction {
+                    North => (0, -1),
+                    South => (0, 1),
+                    NorthEast => (1, down - 1),
+                    SouthEast => (1, down),
+                    NorthWest => (-1, down - 1),
+                    SouthWest => (-1, down),
+                    East | West => return Err(CommandError::InvalidDirection(direction.name().into())),
+                }
+            }
+        };
+        Ok((column + step.0, row + step.1))
+    }
+}
+
+/// Read a cell written as column,row (as tokens are labelled) or in battle map style, e.g. C5 for column 2, row 4
+pub fn parse_cell(text: &str) -> Option<(i64, i64)> {
+    let text = text.trim().trim_start_matches('(').trim_end_matches(')');
+    if let Some((column, row)) = text.split_once(',') {
+        return Some((column.trim().parse().ok()?, row.trim().parse().ok()?));
+    }
+
+    let letters: String = text.chars().take_while(char::is_ascii_alphabetic).collect();
+    let digits = &text[letters.len()..];
+    if letters.is_empty() || digits.is_empty() {
+        return None;
+    }
+    let column = letters.to_ascii_uppercase().bytes()
+        .try_fold(0i64, |column, letter| column.checked_mul(26)?.checked_add((letter - b'A') as i64 + 1))? - 1;
+    let row = digits.parse::<i64>().ok()? - 1;
+    Some((column, row))
+}
+
+/// Whether two line segments cross. Segments that only touch or overlap don't count
+pub fn segments_cross(a: (f64, f64), b: (f64, f64), c: (f64, f64), d: (f64, f64)) -> bool {
+    // Which side of p-q r is on: 1 or -1, or 0 if in line with it
+    let orientation = |p: (f64, f64), q: (f64, f64), r: (f64, f64)| {
+        let cross = (q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0);
+        if cross.abs() < 1e-9 { 0 } else if cross > 0.0 { 1 } else { -1 }
+    };
+    let (abc, abd) = (orientation(a, b, c), orientation(a, b, d));
+    let (cda, cdb) = (orientation(c, d, a), orientation(c, d, b));
+    abc * abd < 0 && cda * cdb < 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEXES: [GridType; 4] = [GridType::HexOddRows, GridType::HexEvenRows, GridType::HexOddColumns, GridType::HexEvenColumns];
+
+    fn geometry(kind: GridType) -> Geometry {
+        Geometry { kind, size: 100.0, offset: (200.0, 100.0), canvas: (1400.0, 1200.0) }
+    }
+
+    fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+        ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+    }
+
+    #[test]
+    fn hexes_are_sized_flat_side_to_flat_side() {
+        let long = 200.0 / 3f64.sqrt();
+        let (width, height) = geometry(GridType::HexOddRows).cell_size();
+        assert_eq!(width, 100.0);
+        assert!((height - long).abs() < 1e-9);
+        let (width, height) = geometry(GridType::HexEvenColumns).cell_size();
+        assert!((width - long).abs() < 1e-9);
+        assert_eq!(height, 100.0);
+    }
+
+    #[test]
+    fn cells_survive_a_round_trip_through_pixels() {
+        for kind in [GridType::Square].into_iter().chain(HEXES) {
+            let geometry = geometry(kind);
+            for column in -3..12 {
+                for row in -3..12 {
+                    assert_eq!(geometry.cell_at(geometry.top_left((column, row))), (column, row), "{:?}", kind);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hex_neighbours_are_one_hex_away_and_back_again() {
+        use Direction::*;
+        let opposite = |direction| match direction {
+            North => South, NorthEast => SouthWest, East => West, SouthEast => NorthWest,
+            South => North, SouthWest => NorthEast, West => East, NorthWest => SouthEast,
+        };
+        for kind in HEXES {
+            let geometry = geometry(kind);
+            let sides = if geometry.rows() { [East, SouthEast, SouthWest, West, NorthWest, NorthEast] } else { [North, NorthEast, SouthEast, South, SouthWest, NorthWest] };
+            for start in [(4, 4), (4, 5), (5, 4), (5, 5)] {
+                let mut neighbours = vec![];
+                for direction in sides {
+                    let cell = geometry.neighbour(start, direction).unwrap();
+                    assert!((distance(geometry.center(start), geometry.center(cell)) - 100.0).abs() < 1e-6, "{:?} {:?} {:?}", kind, start, direction);
+                    assert_eq!(geometry.neighbour(cell, opposite(direction)).unwrap(), start);
+                    neighbours.push(cell);
+                }
+                neighbours.dedup();
+                assert_eq!(neighbours.len(), 6);
+            }
+            let missing = if geometry.rows() { North } else { East };
+            assert!(matches!(geometry.neighbour((4, 4), missing), Err(CommandError::InvalidDirection(_))));
+        }
+    }
+
+    #[test]
+    fn neighbouring_hexes_share_an_edge() {
+        let shared = |a: &[(f64, f64)], b: &[(f64, f64)]| a.iter().filter(|p| b.iter().any(|q| distance(**p, *q) < 1e-6)).count();
+        for kind in HEXES {
+            let geometry = geometry(kind);
+            let outline = geometry.outline((4, 4));
+            assert_eq!(outline.len(), 6);
+            // Every side is as long as every other
+            for (index, &corner) in outline.iter().enumerate() {
+                assert!((distance(corner, outline[(index + 1) % 6]) - 100.0 / 3f64.sqrt()).abs() < 1e-6, "{:?}", kind);
+            }
+            let directions = if geometry.rows() { [Direction::East, Direction::SouthWest] } else { [Direction::North, Direction::SouthEast] };
+            for direction in directions {
+                let neighbour = geometry.neighbour((4, 4), direction).unwrap();
+                assert_eq!(shared(&outline, &geometry.outline(neighbour)), 2, "{:?} {:?}", kind, direction);
+            }
+        }
+    }
+
+    #[test]
+    fn cells_off_the_canvas_are_not_contained() {
+        let square = geometry(GridType::Square);
+        assert!(square.contains((0, 0)));
+        assert!(square.contains((-2, -1)));
+        assert!(!square.contains((-3, 0)));
+        assert!(!square.contains((0, -5)));
+        assert!(square.contains((11, 10)));
+        assert!(!square.contains((12, 0)));
+        assert!(!square.contains((0, 11)));
+    }
+
+    #[test]
+    fn parse_cells() {
+        assert_eq!(parse_cell("3,4"), Some((3, 4)));
+        assert_eq!(parse_cell("(0, -5)"), Some((0, -5)));
+        assert_eq!(parse_cell("C5"), Some((2, 4)));
+        assert_eq!(parse_cell("aa1"), Some((26, 0)));
+        assert_eq!(parse_cell("AAAAAAAAAAAAAAA1"), None);
+        assert_eq!(parse_cell("C"), None);
+        assert_eq!(parse_cell("5"), None);
+    }
+}
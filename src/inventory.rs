@@ -0,0 +1,364 @@
+use poise::serenity_prelude as serenity;
+use serde_json::{json, Value};
+use crate::autocomplete::{autocomplete_actor, autocomplete_carried, autocomplete_consumable};
+use crate::dnd5e::{properties, Currency, DND5EActor, DND5EItem, DND5EWorld};
+use crate::documents::uuid;
+use crate::error::CommandError;
+use crate::store::own_actor;
+use crate::version::uses_change;
+use crate::{autocomplete_nickname, get_raw_world, get_world, Context, DiscordError};
+
+/// Coins that weigh a pound, as dnd5e counts them
+const COINS_PER_POUND: f64 = 50.0;
+
+/// Pounds a medium creature can carry per point of strength
+const CAPACITY_PER_STRENGTH: f64 = 15.0;
+
+/// Containers nested deeper than this are left out, in case an item claims to contain itself
+const MAX_DEPTH: usize = 8;
+
+/// Discord refuses embed fields longer than this
+const FIELD_LENGTH: usize = 1024;
+
+/// Discord refuses embeds with more fields than this
+const MAX_FIELDS: usize = 25;
+
+/// Discord refuses embeds longer than this in total, counting the title, field names and values, and the footer.
+/// Room is kept for a footer saying what was left out
+const EMBED_LENGTH: usize = 6000 - 100;
+
+fn items(actor: &DND5EActor) -> &[DND5EItem] {
+    actor.base().map_or(&[], |base| base.items.as_slice())
+}
+
+/// Find a carried item by name, among those a filter accepts
+fn find_item<'a>(actor: &'a DND5EActor, name: &str, filter: fn(&DND5EItem) -> bool) -> Result<&'a DND5EItem, CommandError> {
+    items(actor).iter()
+        .filter(|item| filter(item))
+        .find(|item| item.base().is_some_and(|base| base.document.name.eq_ignore_ascii_case(name)))
+        .ok_or(CommandError::ItemNotCarried(name.into()))
+}
+
+/// The container an item is in, if it is in one the actor has
+fn container_of<'a>(items: &'a [DND5EItem], item: &DND5EItem) -> Option<&'a DND5EItem> {
+    let id = item.physical()?.1.container.as_deref()?;
+    items.iter().find(|candidate| candidate.is_container() && candidate.base().and_then(|base| base.document.id.as_deref()) == Some(id))
+}
+
+/// What is directly inside a container
+fn contents<'a>(items: &'a [DND5EItem], container: &'a DND5EItem) -> impl Iterator<Item = &'a DND5EItem> {
+    let id = container.base().and_then(|base| base.document.id.as_deref());
+    items.iter().filter(move |item| item.physical().is_some_and(|(_, system)| system.container.is_some() && system.container.as_deref() == id))
+}
+
+fn coins(currency: &Currency) -> i64 {
+    currency.pp + currency.gp + currency.ep + currency.sp + currency.cp
+}
+
+fn format_currency(currency: &Currency) -> String {
+    let amounts: Vec<String> = [(currency.pp, "pp"), (currency.gp, "gp"), (currency.ep, "ep"), (currency.sp, "sp"), (currency.cp, "cp")]
+        .iter()
+        .filter(|(amount, _)| *amount != 0)
+        .map(|(amount, coin)| format!("{} {}", amount, coin))
+        .collect();
+    if amounts.is_empty() { "None".into() } else { amounts.join(", ") }
+}
+
+/// What an item weighs, counting what's inside it unless it makes its contents weightless
+fn weight_of(items: &[DND5EItem], item: &DND5EItem, depth: usize) -> f64 {
+    let Some((_, system)) = item.physical() else { return 0.0 };
+    let mut weight = system.weight * system.quantity as f64;
+    let weightless = properties(&system.other).iter().any(|property| property == "weightlessContents");
+    if item.is_container() && !weightless && depth < MAX_DEPTH {
+        weight += contents(items, item).map(|inner| weight_of(items, inner, depth + 1)).sum::<f64>();
+        weight += system.currency.as_ref().map_or(0, coins) as f64 / COINS_PER_POUND;
+    }
+    weight
+}
+
+/// One line of the inventory, e.g. "Potion of Healing ×2 (1/1 uses)"
+fn describe_item(item: &DND5EItem, data: &Value) -> String {
+    let Some((base, system)) = item.physical() else { return String::new() };
+    let mut line = base.document.name.clone();
+    if system.quantity != 1 {
+        line = format!("{} ×{}", line, system.quantity);
+    }
+    let mut notes = vec![];
+    if system.equipped {
+        notes.push("equipped".to_owned());
+    }
+    if system.attuned {
+        notes.push("attuned".to_owned());
+    }
+    if let (Some(value), Some(max)) = (system.uses.value, system.uses.maximum(data)) {
+        notes.push(format!("{}/{} uses", value, max));
+    }
+    if !notes.is_empty() {
+        line = format!("{} ({})", line, notes.join(", "));
+    }
+    line
+}
+
+/// Join lines for an embed field, cutting them short if there are too many
+fn field_text(lines: &[String]) -> String {
+    let mut text = String::new();
+    for line in lines {
+        if text.len() + line.len() + 2 > FIELD_LENGTH {
+            text.push('…');
+            break;
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    if text.is_empty() { "Nothing".into() } else { text }
+}
+
+/// Shows what you carry, by container, and how much it weighs
+#[poise::command(slash_command)]
+pub async fn inventory(
+    ctx: Context<'_>,
+    #[description = "Nickname of the actor whose inventory to show, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let world = get_world(&ctx.data().foundry).await?;
+    let (actor, _) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    let name = actor.base().map_or("", |base| base.document.name.as_str());
+    let items = items(actor);
+    let data = actor.roll_data();
+    let currency: Currency = serde_json::from_value(data["currency"].clone()).unwrap_or_default();
+
+    let carried: Vec<&DND5EItem> = items.iter()
+        .filter(|item| item.physical().is_some() && container_of(items, item).is_none())
+        .collect();
+    let weight = carried.iter().map(|item| weight_of(items, item, 0)).sum::<f64>() + coins(&currency) as f64 / COINS_PER_POUND;
+    let capacity = data.pointer("/abilities/str/value").and_then(Value::as_f64).unwrap_or(10.0) * CAPACITY_PER_STRENGTH;
+    let encumbrance = if weight > capacity {
+        format!("{:.1} / {:.0} lb (over capacity)", weight, capacity)
+    } else {
+        format!("{:.1} / {:.0} lb", weight, capacity)
+    };
+
+    let lines: Vec<String> = carried.iter().filter(|item| !item.is_container()).map(|item| describe_item(item, &data)).collect();
+    let mut fields = vec![
+        ("Currency".to_owned(), format_currency(&currency), true),
+        ("Carrying".to_owned(), encumbrance, true),
+        ("Carried".to_owned(), field_text(&lines), false),
+    ];
+
+    // Every container gets a field, however deeply it is nested
+    for container in items.iter().filter(|item| item.is_container()) {
+        let Some((base, system)) = container.physical() else { continue };
+        let mut lines: Vec<String> = contents(items, container).map(|item| describe_item(item, &data)).collect();
+        if let Some(currency) = system.currency.as_ref().filter(|currency| coins(currency) != 0) {
+            lines.push(format_currency(currency));
+        }
+        let title = match container_of(items, container) {
+            Some(outer) => format!("{} (in {}, {:.1} lb)", base.document.name, outer.base().map_or("", |outer| outer.document.name.as_str()), weight_of(items, container, 0)),
+            None => format!("{} ({:.1} lb)", base.document.name, weight_of(items, container, 0)),
+        };
+        fields.push((title, field_text(&lines), false));
+    }
+
+    // Containers that would take the embed past discord's limits are left out
+    let title = format!("{}'s inventory", name);
+    let mut length = title.chars().count();
+    let mut embed = serenity::CreateEmbed::new().title(title);
+    let mut shown = 0;
+    for (field_name, value, inline) in &fields {
+        let field_length = field_name.chars().count() + value.chars().count();
+        if shown == MAX_FIELDS || length + field_length > EMBED_LENGTH {
+            break;
+        }
+        length += field_length;
+        shown += 1;
+        embed = embed.field(field_name, value, *inline);
+    }
+    if shown < fields.len() {
+        embed = embed.footer(serenity::CreateEmbedFooter::new(format!("{} more containers didn't fit", fields.len() - shown)));
+    }
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// Uses up a consumable: one of its uses, or one of it if it has none
+#[poise::command(slash_command, rename = "use")]
+pub async fn use_item(
+    ctx: Context<'_>,
+    #[description = "Consumable"]
+    #[autocomplete = "autocomplete_consumable"]
+    item: String,
+    #[description = "Nickname of the actor to use it as, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let (actor, _) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    let found = find_item(actor, &item, |item| matches!(item, DND5EItem::consumable { .. }))?;
+    let Some((base, system)) = found.physical() else { Err(CommandError::ItemNotCarried(item))? };
+    let name = base.document.name.as_str();
+    let item_id = base.document.id.clone().unwrap_or_default();
+    let parent = uuid("Actor", actor.base().and_then(|base| base.document.id.as_deref()).unwrap_or_default());
+
+    let mut quantity = system.quantity;
+    let mut update = json!({"_id": item_id});
+    let uses = match (system.uses.maximum(&actor.roll_data()), system.uses.value) {
+        (Some(max), Some(value)) => {
+            if value <= 0 {
+                Err(CommandError::NothingLeft(name.into()))?;
+            }
+            // Using up the last use of one of a stack moves on to the next
+            let mut value = value - 1;
+            if value == 0 && system.uses.auto_destroy && quantity > 0 {
+                quantity -= 1;
+                if quantity > 0 {
+                    value = max;
+                }
+            }
+            let (key, stored) = uses_change(foundry.versions().adapter, max, value);
+            update[key] = json!(stored);
+            Some((value, max))
+        }
+        _ => {
+            if quantity <= 0 {
+                Err(CommandError::NothingLeft(name.into()))?;
+            }
+            quantity -= 1;
+            None
+        }
+    };
+    update["system.quantity"] = json!(quantity);
+
+    if quantity == 0 && system.uses.auto_destroy {
+        foundry.delete_documents("Item", Some(&parent), vec![item_id]).await?;
+        ctx.say(format!("Used the last {}", name)).await?;
+    } else {
+        foundry.update_documents("Item", Some(&parent), vec![update]).await?;
+        let left = match uses {
+            Some((value, max)) => format!("{}/{} uses left, {} remaining", value, max, quantity),
+            None => format!("{} remaining", quantity),
+        };
+        ctx.say(format!("Used {} ({})", name, left)).await?;
+    }
+    Ok(())
+}
+
+/// Equips or unequips an item
+#[poise::command(slash_command)]
+pub async fn equip(
+    ctx: Context<'_>,
+    #[description = "Item"]
+    #[autocomplete = "autocomplete_carried"]
+    item: String,
+    #[description = "Whether to equip it. Defaults to switching"] equipped: Option<bool>,
+    #[description = "Nickname of the actor to equip, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let (actor, _) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    // Containers and loot can't be equipped
+    let found = find_item(actor, &item, |item| item.physical().is_some() && !item.is_container() && !matches!(item, DND5EItem::loot { .. }))?;
+    let Some((base, system)) = found.physical() else { Err(CommandError::ItemNotCarried(item))? };
+    let equipped = equipped.unwrap_or(!system.equipped);
+
+    let parent = uuid("Actor", actor.base().and_then(|base| base.document.id.as_deref()).unwrap_or_default());
+    foundry.update_documents("Item", Some(&parent), vec![json!({"_id": base.document.id, "system.equipped": equipped})]).await?;
+    ctx.say(format!("{} {}", if equipped { "Equipped" } else { "Unequipped" }, base.document.name)).await?;
+    Ok(())
+}
+
+/// Find an actor by name, preferring an exact match but forgiving case if that is unambiguous
+fn find_actor<'a>(world: &'a DND5EWorld, name: &str) -> Result<&'a DND5EActor, CommandError> {
+    let exact = world.actors.iter().find(|actor| actor.base().is_some_and(|base| base.document.name == name));
+    let loose: Vec<_> = world.actors.iter()
+        .filter(|actor| actor.base().is_some_and(|base| base.document.name.eq_ignore_ascii_case(name)))
+        .collect();
+    match (exact, loose.as_slice()) {
+        (Some(actor), _) => Ok(actor),
+        (None, [actor]) => Ok(*actor),
+        _ => Err(CommandError::CharacterNotFound(name.into())),
+    }
+}
+
+/// The raw data of an actor's item, which keeps everything our models leave out
+fn raw_item<'a>(raw_world: &'a Value, actor_id: &str, item_id: &str) -> Option<&'a Value> {
+    raw_world.get("actors")?
+        .as_array()?
+        .iter()
+        .find(|raw| raw.get("_id").and_then(Value::as_str) == Some(actor_id))?
+        .get("items")?
+        .as_array()?
+        .iter()
+        .find(|raw| raw.get("_id").and_then(Value::as_str) == Some(item_id))
+}
+
+/// Gives an item to another actor
+#[poise::command(slash_command)]
+pub async fn give(
+    ctx: Context<'_>,
+    #[description = "Item"]
+    #[autocomplete = "autocomplete_carried"]
+    item: String,
+    #[description = "Actor to give it to"]
+    #[autocomplete = "autocomplete_actor"]
+    to: String,
+    #[description = "How many to give. Defaults to all of them"]
+    #[min = 1]
+    quantity: Option<i64>,
+    #[description = "Nickname of the actor giving it, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let (actor, _) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    let found = find_item(actor, &item, |item| item.physical().is_some())?;
+    let Some((base, system)) = found.physical() else { Err(CommandError::ItemNotCarried(item))? };
+    let name = base.document.name.as_str();
+    // Foundry moves containers along with their contents, which we don't attempt
+    if found.is_container() && contents(items(actor), found).next().is_some() {
+        Err(CommandError::ContainerNotEmpty(name.into()))?;
+    }
+    let moving = quantity.unwrap_or(system.quantity);
+    if moving > system.quantity {
+        Err(CommandError::NotEnough(name.into(), system.quantity))?;
+    }
+
+    let recipient = find_actor(&world, &to)?;
+    let recipient_base = recipient.base().ok_or(CommandError::CharacterNotFound(to.clone()))?;
+    let actor_id = actor.base().and_then(|base| base.document.id.as_deref()).unwrap_or_default();
+    let recipient_id = recipient_base.document.id.as_deref().unwrap_or_default();
+    if recipient_id == actor_id {
+        Err(CommandError::CharacterNotFound(to))?;
+    }
+
+    // Copy the item as foundry has it, so nothing we don't model is lost
+    let item_id = base.document.id.clone().unwrap_or_default();
+    let raw_world = get_raw_world(foundry).await?;
+    let mut copy = raw_item(&raw_world, actor_id, &item_id).cloned().ok_or(CommandError::ItemNotCarried(name.into()))?;
+    if let Some(copy) = copy.as_object_mut() {
+        copy.remove("_id");
+    }
+    copy["system"]["quantity"] = json!(moving);
+    copy["system"]["container"] = Value::Null;
+    copy["system"]["equipped"] = json!(false);
+    copy["system"]["attuned"] = json!(false);
+
+    // Create the copy before taking the original away, so a failure can't lose the item
+    foundry.create_documents("Item", Some(&uuid("Actor", recipient_id)), vec![copy]).await?;
+    let parent = uuid("Actor", actor_id);
+    if moving == system.quantity {
+        foundry.delete_documents("Item", Some(&parent), vec![item_id]).await?;
+    } else {
+        foundry.update_documents("Item", Some(&parent), vec![json!({"_id": item_id, "system.quantity": system.quantity - moving})]).await?;
+    }
+    ctx.say(format!("Gave {} ×{} to {}", name, moving, recipient_base.document.name)).await?;
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use crate::autocomplete::fuzzy_filter;
+use crate::dnd5e::DND5EWorld;
+use crate::error::CommandError;
+use crate::markdown::{html_to_markdown, paginate};
+use crate::world::{JournalEntry, JournalEntryPage, PageContent, Permissions, User};
+use crate::{get_world, Context, DiscordError};
+
+/// How much text goes on each page of a reply
+const PAGE_LENGTH: usize = 1800;
+
+/// The journal entries our foundry user may read, each with the pages they may read
+fn readable_entries<'a>(world: &'a DND5EWorld, user: &'a User) -> Vec<(&'a JournalEntry, Vec<&'a JournalEntryPage>)> {
+    world.journal.iter()
+        .filter(|entry| entry.document.ownership.level(user) >= Permissions::Observer)
+        .map(|entry| {
+            let mut pages: Vec<&JournalEntryPage> = entry.pages.iter()
+                .filter(|page| page.ownership.level_within(user, &entry.document.ownership) >= Permissions::Observer)
+                .collect();
+            pages.sort_by_key(|page| page.sort);
+            (entry, pages)
+        })
+        .collect()
+}
+
+/// A page as markdown
+fn render_page(ctx: Context<'_>, page: &JournalEntryPage) -> String {
+    let foundry = &ctx.data().foundry;
+    let body = match &page.content {
+        PageContent::text { text } => html_to_markdown(text.content.as_deref().unwrap_or("")),
+        PageContent::image { src, image } => {
+            let url = src.as_deref().map(|src| foundry.url_for(src)).unwrap_or_default();
+            match &image.caption {
+                Some(caption) => format!("{}\n{}", caption, url),
+                None => url,
+            }
+        }
+        PageContent::pdf { src } | PageContent::video { src } => {
+            src.as_deref().map(|src| foundry.url_for(src)).unwrap_or_default()
+        }
+        PageContent::other => "*This page can only be viewed in foundry*".into(),
+    };
+    format!("# {}\n{}", page.name, body)
+}
+
+/// Read handouts and notes from the foundry journal
+#[poise::command(slash_command, subcommands("search", "read"), subcommand_required)]
+pub async fn journal(_ctx: Context<'_>) -> Result<(), DiscordError> {
+    Ok(())
+}
+
+/// Suggests the names of readable journal entries
+async fn autocomplete_entry(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let foundry = &ctx.data().foundry;
+    let Ok(world) = get_world(foundry).await else { return vec![] };
+    let Some(user) = world.user(foundry.user_id()) else { return vec![] };
+    let names = readable_entries(&world, user).into_iter().map(|(entry, _)| entry.document.name.clone());
+    fuzzy_filter(names, partial, |name| name.as_str())
+}
+
+/// Finds journal pages containing some text
+#[poise::command(slash_command)]
+async fn search(
+    ctx: Context<'_>,
+    #[description = "Text to look for"] text: String,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let user = world.user(foundry.user_id()).ok_or(CommandError::InvalidFoundryUser)?;
+    // Ascii lowercasing keeps byte offsets intact, so hits can be sliced out of the original text
+    let needle = text.to_ascii_lowercase();
+
+    let mut results = vec![];
+    for (entry, pages) in readable_entries(&world, user) {
+        let name_matches = entry.document.name.to_ascii_lowercase().contains(&needle);
+        for page in pages {
+            let content = match &page.content {
+                PageContent::text { text } => html_to_markdown(text.content.as_deref().unwrap_or("")),
+                _ => String::new(),
+            };
+            let lower = content.to_ascii_lowercase();
+            if let Some(index) = lower.find(&needle) {
+                // Show a little context around the first hit
+                let start = content[..index].char_indices().rev().nth(40).map_or(0, |(i, _)| i);
+                let snippet: String = content[start..].chars().take(needle.chars().count() + 80).collect();
+                results.push(format!("**{}** › {}: …{}…", entry.document.name, page.name, snippet.replace('\n', " ")));
+            } else if name_matches || page.name.to_ascii_lowercase().contains(&needle) {
+                results.push(format!("**{}** › {}", entry.document.name, page.name));
+            }
+        }
+    }
+
+    if results.is_empty() {
+        ctx.say(format!("Nothing in the journal mentions '{}'", text)).await?;
+        return Ok(());
+    }
+    let pages = paginate(&results.join("\n"), PAGE_LENGTH);
+    let pages: Vec<&str> = pages.iter().map(String::as_str).collect();
+    poise::builtins::paginate(ctx, &pages).await?;
+    Ok(())
+}
+
+/// Reads a journal entry
+#[poise::command(slash_command)]
+async fn read(
+    ctx: Context<'_>,
+    #[description = "Journal entry"]
+    #[autocomplete = "autocomplete_entry"]
+    entry: String,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let user = world.user(foundry.user_id()).ok_or(CommandError::InvalidFoundryUser)?;
+    let (_, pages) = readable_entries(&world, user).into_iter()
+        .find(|(candidate, _)| candidate.document.name == entry)
+        .ok_or(CommandError::JournalNotFound(entry.clone()))?;
+    if pages.is_empty() {
+        ctx.say(format!("{} has no pages you can read", entry)).await?;
+        return Ok(());
+    }
+
+    // Each journal page gets at least one reply page, long ones more
+    let rendered: Vec<String> = pages.iter()
+        .flat_map(|page| paginate(&render_page(ctx, page), PAGE_LENGTH))
+        .collect();
+    let rendered: Vec<&str> = rendered.iter().map(String::as_str).collect();
+    poise::builtins::paginate(ctx, &rendered).await?;
+    Ok(())
+}
@@ -0,0 +1,106 @@
+// A small subset of the foundry client API, enough for the macros a table commonly uses.
+// Anything that touches the server is forwarded to the bot through op_foundry.
+// Expects globalThis.__janus to already hold { actors, user, character } from the world
+((globalThis) => {
+  const janus = globalThis.__janus;
+  const output = [];
+
+  const call = async (method, args) => {
+    const response = await Deno.core.ops.op_foundry(method, args ?? {});
+    if ("error" in response) {
+      throw new Error(response.error);
+    }
+    return response.ok;
+  };
+
+  const log = (level) => (...args) => {
+    const message = args.map((arg) => typeof arg === "string" ? arg : JSON.stringify(arg)).join(" ");
+    output.push(level === "log" ? message : `[${level}] ${message}`);
+  };
+
+  class Collection extends Map {
+    get contents() {
+      return Array.from(this.values());
+    }
+    getName(name) {
+      return this.contents.find((document) => document.name === name);
+    }
+    find(predicate) {
+      return this.contents.find(predicate);
+    }
+    filter(predicate) {
+      return this.contents.filter(predicate);
+    }
+    map(transform) {
+      return this.contents.map(transform);
+    }
+    some(predicate) {
+      return this.contents.some(predicate);
+    }
+  }
+
+  class Actor {
+    constructor(source) {
+      Object.assign(this, source);
+      this.id = source._id;
+    }
+    getRollData() {
+      return JSON.parse(JSON.stringify(this.system ?? {}));
+    }
+    async update(changes) {
+      await call("updateActor", { id: this.id, changes });
+      return this;
+    }
+  }
+
+  class Roll {
+    constructor(formula, data = {}) {
+      this.formula = formula;
+      this.data = data;
+      this.total = undefined;
+      this._evaluated = false;
+    }
+    static async create(formula, data = {}) {
+      return new Roll(formula, data);
+    }
+    async evaluate() {
+      const result = await call("roll", { formula: this.formula, data: this.data });
+      this.total = result.total;
+      this._evaluated = true;
+      return this;
+    }
+    async roll() {
+      return this.evaluate();
+    }
+    async toMessage(messageData = {}) {
+      if (!this._evaluated) {
+        await this.evaluate();
+      }
+      return ChatMessage.create({ content: `${this.formula} = ${this.total}`, ...messageData });
+    }
+  }
+
+  const actors = new Collection(janus.actors.map((source) => [source._id, new Actor(source)]));
+  const character = janus.character ? actors.get(janus.character) ?? null : null;
+
+  class ChatMessage {
+    static async create(data) {
+      return call("createChatMessage", data);
+    }
+    static getSpeaker({ actor } = {}) {
+      actor ??= character;
+      return actor ? { actor: actor.id, alias: actor.name } : { alias: janus.user.name };
+    }
+  }
+
+  globalThis.game = { actors, user: janus.user, userId: janus.user._id };
+  globalThis.ui = { notifications: { info: log("info"), warn: log("warn"), error: log("error") } };
+  globalThis.console = { log: log("log"), info: log("info"), warn: log("warn"), error: log("error"), debug: log("debug") };
+  globalThis.Actor = Actor;
+  globalThis.Roll = Roll;
+  globalThis.ChatMessage = ChatMessage;
+
+  // The arguments foundry passes to script macros: speaker, actor, token, character, scope
+  janus.args = () => [ChatMessage.getSpeaker(), character, null, character, {}];
+  janus.flush = () => call("output", { lines: output });
+})(globalThis);
@@ -0,0 +1,258 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+use deno_core::{op2, v8, JsRuntime, OpState, PollEventLoopOptions, RuntimeOptions};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+use crate::autocomplete::fuzzy_filter;
+use crate::connection::FoundryClient;
+use crate::dice::Roll;
+use crate::dnd5e::DND5EWorld;
+use crate::error::CommandError;
+use crate::store::own_actor_id;
+use crate::world::{Macro, MacroType, Permissions, User, UserRole};
+use crate::{get_raw_world, get_world, Context, DiscordError};
+
+/// The foundry API subset script macros run against
+const SHIM: &str = include_str!("macro_shim.js");
+
+/// Script macros are stopped if they run longer than this
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Only this much of what a script logs is shown
+const OUTPUT_LENGTH: usize = 1800;
+
+/// A call from a running script to the bot, answered with the result or an error message
+struct ShimRequest {
+    method: String,
+    args: Value,
+    reply: oneshot::Sender<Result<Value, String>>,
+}
+
+/// The only way scripts reach the outside world. Requests are served by run_script on the bot's side
+#[op2(async)]
+#[serde]
+async fn op_foundry(state: Rc<RefCell<OpState>>, #[string] method: String, #[serde] args: Value) -> Value {
+    let requests = state.borrow().borrow::<mpsc::UnboundedSender<ShimRequest>>().clone();
+    let (reply, response) = oneshot::channel();
+    if requests.send(ShimRequest { method, args, reply }).is_err() {
+        return json!({"error": "The bot stopped listening to this macro"});
+    }
+    match response.await {
+        Ok(Ok(value)) => json!({"ok": value}),
+        Ok(Err(message)) => json!({"error": message}),
+        Err(_) => json!({"error": "The bot stopped listening to this macro"}),
+    }
+}
+
+deno_core::extension!(foundry_shim, ops = [op_foundry]);
+
+/// Run a script in a fresh isolate. Must be called on a thread of its own, since isolates can't move between threads
+async fn evaluate(
+    prelude: String,
+    script: String,
+    requests: mpsc::UnboundedSender<ShimRequest>,
+    isolate: oneshot::Sender<v8::IsolateHandle>,
+) -> Result<(), String> {
+    let mut runtime = JsRuntime::new(RuntimeOptions {
+        extensions: vec![foundry_shim::init()],
+        ..Default::default()
+    });
+    runtime.op_state().borrow_mut().put(requests);
+    let _ = isolate.send(runtime.v8_isolate().thread_safe_handle());
+
+    runtime.execute_script("[janus:prelude]", prelude).map_err(|err| err.to_string())?;
+    runtime.execute_script("[janus:shim]", SHIM).map_err(|err| err.to_string())?;
+    let promise = runtime.execute_script("[janus:macro]", script).map_err(|err| err.to_string())?;
+    let completion = runtime.resolve(promise);
+    runtime.with_event_loop_promise(completion, PollEventLoopOptions::default()).await
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+/// Answer a request from a script
+async fn serve(foundry: &FoundryClient, method: &str, args: Value, output: &mut Vec<String>) -> Result<Value, DiscordError> {
+    match method {
+        "output" => {
+            let lines = args.get("lines").and_then(Value::as_array).into_iter().flatten();
+            output.extend(lines.filter_map(Value::as_str).map(str::to_owned));
+            Ok(Value::Null)
+        }
+        "roll" => {
+            let formula = args.get("formula").and_then(Value::as_str).unwrap_or("");
+            let roll = Roll::parse(formula, args.get("data"))?.evaluate();
+            Ok(json!({"formula": roll.formula(), "total": roll.total()}))
+        }
+        "createChatMessage" => {
+            let mut message = args;
+            if let Some(message) = message.as_object_mut() {
+                message.entry("author").or_insert(foundry.user_id().into());
+                message.entry("user").or_insert(foundry.user_id().into());
+            }
+            let created = foundry.create_documents("ChatMessage", None, vec![message]).await?;
+            Ok(created.into_iter().next().unwrap_or(Value::Null))
+        }
+        "updateActor" => {
+            let id = args.get("id").and_then(Value::as_str).unwrap_or("");
+            let mut changes = args.get("changes").cloned().unwrap_or(json!({}));
+            changes["_id"] = id.into();
+            let updated = foundry.update_documents("Actor", None, vec![changes]).await?;
+            Ok(updated.into_iter().next().unwrap_or(Value::Null))
+        }
+        _ => Err(format!("{} isn't available to macros run through discord", method).into()),
+    }
+}
+
+/// Run a script macro against the shim, serving its requests until it finishes. Returns what it logged
+async fn run_script(foundry: &FoundryClient, command: &str, character: Option<String>) -> Result<Vec<String>, DiscordError> {
+    let raw_world = get_raw_world(foundry).await?;
+    let user = raw_world.get("users")
+        .and_then(Value::as_array)
+        .and_then(|users| users.iter().find(|user| user.get("_id").and_then(Value::as_str) == Some(foundry.user_id())))
+        .cloned()
+        .unwrap_or(json!({"_id": foundry.user_id(), "name": "Janus"}));
+    let janus = json!({
+        "actors": raw_world.get("actors").cloned().unwrap_or(json!([])),
+        "user": user,
+        "character": character,
+    });
+    let prelude = format!("globalThis.__janus = {};", janus);
+    let script = format!(
+        "(async (...args) => {{ try {{ await (async (speaker, actor, token, character, scope) => {{\n{}\n}})(...args); }} finally {{ await __janus.flush(); }} }})(...__janus.args())",
+        command);
+
+    let (requests, mut incoming) = mpsc::unbounded_channel();
+    let (isolate_sender, mut isolate) = oneshot::channel();
+    let (done_sender, mut done) = oneshot::channel();
+    std::thread::spawn(move || {
+        let result = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|err| err.to_string())
+            .and_then(|runtime| runtime.block_on(evaluate(prelude, script, requests, isolate_sender)));
+        let _ = done_sender.send(result);
+    });
+
+    let mut output = vec![];
+    let deadline = tokio::time::sleep(SCRIPT_TIMEOUT);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            Some(request) = incoming.recv() => {
+                let result = serve(foundry, &request.method, request.args, &mut output).await;
+                let _ = request.reply.send(result.map_err(|err| err.to_string()));
+            }
+            result = &mut done => {
+                return match result {
+                    Ok(Ok(())) => Ok(output),
+                    Ok(Err(message)) => Err(CommandError::MacroFailed(message).into()),
+                    Err(_) => Err(CommandError::MacroFailed("The script runtime crashed".into()).into()),
+                };
+            }
+            _ = &mut deadline => {
+                if let Ok(isolate) = isolate.try_recv() {
+                    isolate.terminate_execution();
+                }
+                return Err(CommandError::MacroTimeout(SCRIPT_TIMEOUT.as_secs()).into());
+            }
+        }
+    }
+}
+
+/// Post a chat macro. Roll commands are rolled here, since foundry only understands them when typed into its chat box
+async fn run_chat(foundry: &FoundryClient, command: &str) -> Result<(), DiscordError> {
+    for line in command.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let formula = line.strip_prefix("/roll ").or_else(|| line.strip_prefix("/r "));
+        match formula {
+            Some(formula) => {
+                let roll = Roll::parse(formula, None)?.evaluate();
+                foundry.create_chat_message(&format!("{} = {}", roll.formula(), roll.total()), None).await?;
+            }
+            None => foundry.create_chat_message(line, None).await?,
+        }
+    }
+    Ok(())
+}
+
+/// The macros our foundry user may run
+fn runnable_macros<'a>(world: &'a DND5EWorld, user: &'a User) -> impl Iterator<Item = &'a Macro> {
+    world.macros.iter().filter(move |candidate| candidate.document.ownership.level(user) >= Permissions::Limited)
+}
+
+/// Run foundry macros
+#[poise::command(slash_command, rename = "macro", subcommands("list", "run"), subcommand_required)]
+pub async fn macros(_ctx: Context<'_>) -> Result<(), DiscordError> {
+    Ok(())
+}
+
+/// Suggests the names of macros our foundry user can run
+async fn autocomplete_macro(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let foundry = &ctx.data().foundry;
+    let Ok(world) = get_world(foundry).await else { return vec![] };
+    let Some(user) = world.user(foundry.user_id()) else { return vec![] };
+    let names = runnable_macros(&world, user).map(|candidate| candidate.document.name.clone());
+    fuzzy_filter(names, partial, |name| name.as_str())
+}
+
+/// Lists the macros that can be run
+#[poise::command(slash_command)]
+async fn list(ctx: Context<'_>) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let user = world.user(foundry.user_id()).ok_or(CommandError::InvalidFoundryUser)?;
+    let mut lines: Vec<String> = runnable_macros(&world, user)
+        .map(|candidate| match candidate.kind {
+            MacroType::Chat => format!("{} (chat)", candidate.document.name),
+            MacroType::Script => format!("{} (script)", candidate.document.name),
+        })
+        .collect();
+    if lines.is_empty() {
+        ctx.say("There are no macros you can run").await?;
+        return Ok(());
+    }
+    lines.sort();
+    ctx.say(lines.join("\n")).await?;
+    Ok(())
+}
+
+/// Runs a macro, as your active character
+#[poise::command(slash_command)]
+async fn run(
+    ctx: Context<'_>,
+    #[description = "Macro name"]
+    #[autocomplete = "autocomplete_macro"]
+    name: String,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let user = world.user(foundry.user_id()).ok_or(CommandError::InvalidFoundryUser)?;
+    let found = runnable_macros(&world, user)
+        .find(|candidate| candidate.document.name == name)
+        .ok_or(CommandError::MacroNotFound(name.clone()))?;
+
+    match found.kind {
+        MacroType::Chat => {
+            run_chat(foundry, &found.command).await?;
+            ctx.say(format!("Ran {}", name)).await?;
+        }
+        MacroType::Script => {
+            // Foundry only lets trusted players run scripts by default. Don't let discord get around that
+            if user.role < UserRole::Trusted {
+                Err(CommandError::ScriptMacrosForbidden)?;
+            }
+            ctx.defer().await?;
+            let character = own_actor_id(ctx, None).await.ok();
+            let output = run_script(foundry, &found.command, character).await?;
+            let mut output = output.join("\n");
+            if output.chars().count() > OUTPUT_LENGTH {
+                output = output.chars().take(OUTPUT_LENGTH).collect::<String>() + "…";
+            }
+            if output.is_empty() {
+                ctx.say(format!("Ran {}", name)).await?;
+            } else {
+                ctx.say(format!("Ran {}:\n```\n{}\n```", name, output)).await?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,51 +1,106 @@
+mod autocomplete;
+mod combat;
+mod compendium;
 mod connection;
+mod diagnose;
+mod dice;
 mod dnd5e;
+mod documents;
 pub mod error;
+mod gm;
+mod inventory;
+mod grid;
+mod journal;
+mod macros;
+mod map;
+mod markdown;
+mod movement;
+mod resources;
+mod rolls;
+mod saves;
+mod store;
+mod tables;
+mod tokens;
+mod version;
 mod world;
 
 use crate::connection::FoundryClient;
-use crate::dnd5e::{DND5EActor, DND5EItem, DND5EWorld};
-use clap::Parser;
+use crate::dnd5e::{DND5EActor, DND5EWorld};
+use clap::{Parser, Subcommand};
 use rust_socketio::Payload;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
 
 use poise::serenity_prelude as serenity;
+use std::collections::BTreeSet;
 use std::env;
-use caith::Roller;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
 use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
-use crate::error::CommandError;
+use crate::error::{CommandError, FoundryClientError};
 use crate::error::CommandError::InvalidAttribute;
+use crate::store::{own_actor, UserActors};
+use crate::autocomplete::{autocomplete_actor, autocomplete_stat};
+use crate::rolls::{add_bonus, flat_bonus, render_roll, reply_with_rerolls, D20Check, RollMode};
+use crate::dice::Roll;
+use crate::diagnose::diagnose;
+use crate::tokens::synthesize_token_actors;
+use crate::version::adapt_world;
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Url to connect to. Include http(s):// and any trailing suffix, if needed
-    #[arg(long)]
-    host: String,
+    #[arg(long, required = true)]
+    host: Option<String>,
 
     /// Name of user to connect as
-    #[arg(long)]
-    user: String,
+    #[arg(long, required = true)]
+    user: Option<String>,
 
     /// User password. Defaults to empty
     #[arg(long, default_missing_value(None))]
     password: Option<String>,
+
+    /// Id of the discord role allowed to use GM commands
+    #[arg(long)]
+    gm_role: Option<u64>,
+
+    /// Refuse token moves that pass through walls
+    #[arg(long)]
+    enforce_walls: bool,
 }
 
-fn to_one_json(payload: Payload) -> serde_json::Value {
-    // if let Payload::Text(v) = payload;
-    match payload {
-        Payload::Text(mut items) => items.remove(0),
-        _ => panic!("Not json!"),
-    }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check a world against our models and summarize everything that doesn't fit
+    Diagnose {
+        /// A world dump to check. If omitted, the world is fetched from the server
+        file: Option<PathBuf>,
+        /// Save the fetched world here, to share or check again later
+        #[arg(long)]
+        save: Option<PathBuf>,
+    },
 }
 
+/// How many times to try connecting to foundry before giving up
+const CONNECT_ATTEMPTS: u32 = 5;
+/// Skipped documents already logged by get_world
+static REPORTED_INCOMPATIBILITIES: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
 // Our poise types
 struct DiscordState {
     foundry: FoundryClient,
-    store: tokio::sync::Mutex<PickleDb>
+    store: tokio::sync::Mutex<PickleDb>,
+    /// Members with this role may use GM commands
+    gm_role: Option<serenity::RoleId>,
+    /// Indexes of the world's compendium packs
+    compendium: compendium::CompendiumCache,
+    /// Whether walls block /move
+    enforce_walls: bool,
 } // User data, which is stored and accessible in all command invocations
 type DiscordError = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, DiscordState, DiscordError>;
@@ -57,7 +112,10 @@ type Context<'a> = poise::Context<'a, DiscordState, DiscordError>;
 #[poise::command(slash_command)]
 async fn assoc(
     ctx: Context<'_>,
-    #[description = "Actor Name"] name: String,
+    #[description = "Actor Name"]
+    #[autocomplete = "autocomplete_actor"]
+    name: String,
+    #[description = "Nickname to refer to this actor by. Defaults to the actor name"] nickname: Option<String>,
 ) -> Result<(), DiscordError> {
     let foundry = &ctx.data().foundry;
     let world = get_world(foundry).await?;
@@ -65,18 +123,22 @@ async fn assoc(
     // Figure out who their user id
     let user_id = ctx.author().id.get();
 
-    match world.actors.iter().find_map(|actor| {
-        if let DND5EActor::character { base, .. } = actor {
-            if base.document.name == name && base.document.id.is_some() {
-                return Some(base.document.id.clone().unwrap());
-            }
-        }
-        None
-    }) {
-        Some(id) => { // I clearly fucked up the typings here but... ???
+    // Prefer an exact match, but forgive case if that is unambiguous
+    let bases: Vec<_> = world.actors.iter().filter_map(|actor| actor.base()).collect();
+    let exact = bases.iter().find(|base| base.document.name == name);
+    let loose: Vec<_> = bases.iter().copied().filter(|base| base.document.name.eq_ignore_ascii_case(&name)).collect();
+    let found = match (exact, loose.as_slice()) {
+        (Some(base), _) | (None, [base]) => base.document.id.clone(),
+        _ => None,
+    };
+    match found {
+        Some(id) => {
+            let nickname = nickname.unwrap_or(name);
             let mut store = ctx.data().store.lock().await;
-            store.set(&user_id.to_string(), &id)?;
-            ctx.say(format!("Successfully associated user id {} with actor id {}", user_id, id)).await?;
+            let mut actors = UserActors::load(&store, user_id);
+            actors.insert(&nickname, &id);
+            actors.save(&mut store, user_id)?;
+            ctx.say(format!("Successfully associated user id {} with actor id {} as '{}'", user_id, id, nickname)).await?;
         },
         _ => {
             Err(CommandError::CharacterNotFound(name))?;
@@ -86,126 +148,221 @@ async fn assoc(
     Ok(())
 }
 
+/// Suggests the nicknames of the invoking user's associated actors
+async fn autocomplete_nickname(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let store = ctx.data().store.lock().await;
+    let partial = partial.to_lowercase();
+    UserActors::load(&store, ctx.author().id.get())
+        .actors
+        .into_keys()
+        .filter(|nickname| nickname.to_lowercase().contains(&partial))
+        .collect()
+}
+
+/// Changes which of your associated actors commands act as by default
+#[poise::command(slash_command)]
+async fn switch(
+    ctx: Context<'_>,
+    #[description = "Nickname"]
+    #[autocomplete = "autocomplete_nickname"]
+    name: String,
+) -> Result<(), DiscordError> {
+    let user_id = ctx.author().id.get();
+    let mut store = ctx.data().store.lock().await;
+    let mut actors = UserActors::load(&store, user_id);
+    actors.switch(&name)?;
+    actors.save(&mut store, user_id)?;
+    ctx.say(format!("Now acting as '{}'", name)).await?;
+    Ok(())
+}
+
+/// Lists your associated actors
+#[poise::command(slash_command)]
+async fn characters(ctx: Context<'_>) -> Result<(), DiscordError> {
+    let actors = {
+        let store = ctx.data().store.lock().await;
+        UserActors::load(&store, ctx.author().id.get())
+    };
+    if actors.actors.is_empty() {
+        Err(CommandError::MissingAssocChar)?;
+    }
+
+    let lines: Vec<String> = actors.actors.keys().map(|nickname| {
+        if actors.active.as_ref() == Some(nickname) {
+            format!("**{}** (active)", nickname)
+        } else {
+            nickname.clone()
+        }
+    }).collect();
+    ctx.say(lines.join("\n")).await?;
+    Ok(())
+}
+
 /// Rolls a stat
 #[poise::command(slash_command)]
 async fn roll(
     ctx: Context<'_>,
-    #[description = "Attribute"] stat: String,
-    #[description = "adv/dis"] adv_or_dis: Option<String>
+    #[description = "Attribute"]
+    #[autocomplete = "autocomplete_stat"]
+    stat: String,
+    #[description = "Roll with advantage or disadvantage"] mode: Option<RollMode>,
+    #[description = "Nickname of the actor to roll as, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
 ) -> Result<(), DiscordError> {
     let foundry = &ctx.data().foundry;
     let world = get_world(foundry).await?;
 
-    // Figure out who they should be
-    let user_id = ctx.author().id.get();
-    let store = ctx.data().store.lock().await;
-    let actor_id: Option<String> = store.get(&user_id.to_string());
-
-    // See if it works
-    let actor_id = actor_id.ok_or(CommandError::MissingAssocChar)?;
-
-    // Attempt to find character system data
-    let actor =  world.actors.iter().find(|actor| {
-        if let DND5EActor::character { base, .. } = actor {
-            return base.document.id == Some(actor_id.clone());
-        }
-        false
-    }).ok_or(CommandError::InvalidAssocChar)?;
-    let DND5EActor::character { system, base } = actor else { Err(CommandError::InvalidAssocChar)? };
-
-    // We will need proficiency in a lot of cases
-    let mut total_level = 0;
-    for item in &base.items {
-        match item {
-            DND5EItem::class { system, .. } => { total_level += system.levels.unwrap_or(0); }
-            _ => {} // Don't care
-        }
+    // Figure out who they should be. Only characters have checks we can work out
+    let (actor, _) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    if !matches!(actor, DND5EActor::character { .. }) {
+        Err(CommandError::InvalidAssocChar)?;
     }
-    let proficiency = match total_level {
-        0..=4 => 2,
-        5..=8 => 3,
-        9..=12 => 4,
-        13..=16 => 5,
-        17..=20 => 6,
-        _ => 7
-    };
+    let data = actor.roll_data();
 
-    // Get the appropriate stats
-    let (ability_score, proficiency_factor) = match stat.as_str() {
-        "str" | "strength"      => (system.abilities.str.value, 0f32),
-        "dex" | "dexterity"     => (system.abilities.str.value, 0f32),
-        "cha" | "charisma"      => (system.abilities.str.value, 0f32),
-        "int" | "intelligence"  => (system.abilities.str.value, 0f32),
-        "wis" | "wisdom"        => (system.abilities.str.value, 0f32),
-        "con" | "constitution"  => (system.abilities.str.value, 0f32),
-        "acr" | "acrobatics" => (system.abilities.dex.value, system.skills.acrobatics.value.unwrap_or(0f32)),
-        "ani" | "animal" | "animals" => (system.abilities.wis.value, system.skills.animal_handling.value.unwrap_or(0f32)),
-        "arc" | "arcana" => (system.abilities.int.value, system.skills.arcana.value.unwrap_or(0f32)),
-        "ath" | "athletics" => (system.abilities.str.value, system.skills.athletics.value.unwrap_or(0f32)),
-        "dec" | "deception" => (system.abilities.cha.value, system.skills.deception.value.unwrap_or(0f32)),
-        "his" | "history" => (system.abilities.int.value, system.skills.history.value.unwrap_or(0f32)),
-        "ins" | "insight" => (system.abilities.wis.value, system.skills.insight.value.unwrap_or(0f32)),
-        "inv" | "investigation" => (system.abilities.int.value, system.skills.investigation.value.unwrap_or(0f32)),
-        "itm" | "intimidation" => (system.abilities.cha.value, system.skills.intimidation.value.unwrap_or(0f32)),
-        "med" | "medicine" => (system.abilities.wis.value, system.skills.medicine.value.unwrap_or(0f32)),
-        "nat" | "nature" => (system.abilities.int.value, system.skills.nature.value.unwrap_or(0f32)),
-        "per" | "persuasion" => (system.abilities.cha.value, system.skills.persuasion.value.unwrap_or(0f32)),
-        "prc" | "perception" => (system.abilities.wis.value, system.skills.perception.value.unwrap_or(0f32)),
-        "prf" | "performance" => (system.abilities.cha.value, system.skills.performance.value.unwrap_or(0f32)),
-        "rel" | "religion" => (system.abilities.int.value, system.skills.religion.value.unwrap_or(0f32)),
-        "slt" | "sleight" | "sleight of hand" => (system.abilities.dex.value, system.skills.sleight_of_hand.value.unwrap_or(0f32)),
-        "ste" | "stealth" => (system.abilities.dex.value, system.skills.stealth.value.unwrap_or(0f32)),
-        "sur" | "survival" => (system.abilities.wis.value, system.skills.survival.value.unwrap_or(0f32)),
-        _ => return Err(InvalidAttribute(stat).into())
+    // Passive scores are asked for as e.g. "passive:prc"
+    let (stat, passive) = match stat.strip_prefix("passive:") {
+        Some(stat) => (stat.to_owned(), true),
+        None => (stat, false),
     };
+    let check = actor.check(&stat).ok_or(InvalidAttribute(stat))?;
 
-    // Now coerce the proficiency to an integer, use a small rounding factor to ensure its more reliable
-    let proficiency = ((proficiency as f32) * proficiency_factor + 0.25f32).floor() as i32;
-
-    // Decide base roll based on adv/disadv
-    let mut d20 = "1d20";
-    if let Some(adv_or_dis) = adv_or_dis {
-        d20 = match adv_or_dis.as_str() {
-            "adv" | "advantage" => "2d20K1",
-            "dis" | "disadvantage" => "2d20k1",
-            _ => "2d20kl1",
+    if passive {
+        // Passive scores don't roll, so dice in the bonuses are left out
+        let bonus = flat_bonus(check.bonuses.iter().chain(&check.passive_bonuses), &data)?;
+        let adjustment = match mode.unwrap_or_default() {
+            RollMode::Normal => 0,
+            RollMode::Advantage => 5,
+            RollMode::Disadvantage => -5,
         };
+        let score = check.passive(bonus) + adjustment;
+        let name = actor.base().map_or("", |base| base.document.name.as_str());
+        ctx.say(format!("{}'s passive {}: **{}**", name, check.label, score)).await?;
+        return Ok(());
     }
 
-    // While we're at it, convert the stat to a bonus
-    let ability_mod = (((ability_score as f32) - 10f32) / 2f32).floor() as i32;
-    let formula = format!("{} + {}", d20, proficiency + ability_mod);
-    let result = Roller::new(&formula)?.roll()?;
-    ctx.say(format!("Rolling {}: {} → {}", stat, formula, result)).await?;
+    let mut bonuses = Roll::default();
+    for bonus in &check.bonuses {
+        add_bonus(&mut bonuses, Some(bonus.clone()), &data)?;
+    }
+    let check = D20Check {
+        label: check.label,
+        modifiers: check.modifiers,
+        bonuses: bonuses.terms,
+        minimum: check.minimum,
+        reroll_ones: check.reroll_ones,
+    };
+    reply_with_rerolls(ctx, check, mode.unwrap_or_default()).await
+}
 
+/// Rolls an arbitrary formula, using Foundry's dice syntax
+#[poise::command(slash_command, rename = "r")]
+async fn roll_formula(
+    ctx: Context<'_>,
+    #[description = "Formula, e.g. 4d6kh3 or 1d20 + @abilities.dex.mod"] formula: String,
+    #[description = "Nickname of the actor whose data @ references use, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    // Only bother fetching the world if the formula refers to the actor
+    let roll_data = if formula.contains('@') {
+        let world = get_world(&ctx.data().foundry).await?;
+        let (actor, _) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+        Some(actor.roll_data())
+    } else {
+        None
+    };
+
+    let roll = Roll::parse(&formula, roll_data.as_ref())?.evaluate();
+    ctx.send(poise::CreateReply::default().embed(render_roll(&formula, &roll))).await?;
     Ok(())
 }
 
-async fn get_world(client: &FoundryClient) -> Result<DND5EWorld, Box<dyn std::error::Error + Send + Sync>> {
-    let payload = client.emit("world", Payload::Text(vec![])).await.unwrap();
-    let json = to_one_json(payload);
-    let raw_world = json.get(0).unwrap();
-    let raw_world_debug = serde_json::to_string_pretty(json.get(0).unwrap()).unwrap();
-    let mut file = File::create("foo.json").await?;
-    file.write_all(raw_world_debug.as_bytes()).await?;
-    let deser = &mut serde_json::Deserializer::from_str(&raw_world_debug);
-    let debug_world: Result<DND5EWorld, _> = serde_path_to_error::deserialize(deser);
-    match debug_world {
-        Ok(_) => (),
-        Err(err) => {
-            let path = err.path().to_string();
-            println!("PATH: {}\nERR: {}\n", path, err.inner());
+/// Shows how the connection to foundry is performing
+#[poise::command(slash_command)]
+async fn status(ctx: Context<'_>) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let versions = foundry.versions();
+    let mut lines = vec![format!("Foundry {} running {}", versions.core, versions.system)];
+    lines.extend(foundry.metrics().iter().map(|(event, m)| {
+        format!("`{}`: {} requests, {} failed, {} timed out, mean {}ms, max {}ms",
+            event, m.requests, m.failures, m.timeouts, m.mean_latency().as_millis(), m.max_latency.as_millis())
+    }));
+    ctx.say(lines.join("\n")).await?;
+    Ok(())
+}
+
+async fn get_raw_world(client: &FoundryClient) -> Result<serde_json::Value, FoundryClientError> {
+    client.request("world", Payload::Text(vec![])).await
+}
+
+async fn get_world(client: &FoundryClient) -> Result<DND5EWorld, FoundryClientError> {
+    let mut raw_world = get_raw_world(client).await?;
+    synthesize_token_actors(&mut raw_world);
+    adapt_world(client.versions(), &mut raw_world);
+    let world: DND5EWorld = serde_path_to_error::deserialize(&raw_world)
+        .map_err(|err| FoundryClientError::malformed(err, &raw_world))?;
+
+    // Documents that don't fit our models are left out rather than failing everything. Say so, since that may surprise,
+    // but only the first time each is seen: the world is fetched on every command and autocomplete keystroke
+    let mut reported = REPORTED_INCOMPATIBILITIES.lock().unwrap();
+    for skipped in world.incompatibilities() {
+        let key = format!("{:?}/{}/{}", skipped.id, skipped.path, skipped.error);
+        if reported.insert(key) {
+            eprintln!("Skipped {} ({}): {} at {}. Run `diagnose` for details",
+                skipped.name.as_deref().unwrap_or("<unnamed>"), skipped.id.as_deref().unwrap_or("<no id>"), skipped.error, skipped.path);
         }
     }
-    let world: DND5EWorld = serde_json::from_value(raw_world.clone())?;
     Ok(world)
 }
 
+/// The host, user and password to log in to foundry with
+fn credentials(args: &Args) -> Result<(&str, &str, String), FoundryClientError> {
+    let host = args.host.as_deref().ok_or(FoundryClientError::FailedInit("--host is required".into()))?;
+    let user = args.user.as_deref().ok_or(FoundryClientError::FailedInit("--user is required".into()))?;
+    Ok((host, user, args.password.clone().unwrap_or_default()))
+}
+
+/// Connect to foundry, retrying for as long as the failure looks temporary
+async fn connect(args: &Args) -> Result<FoundryClient, FoundryClientError> {
+    let (host, user, password) = credentials(args)?;
+    let mut attempt = 1;
+    loop {
+        match FoundryClient::new(host, user, &password).await {
+            Ok(client) => return Ok(client),
+            // Whatever socket a failed attempt opened is disconnected as it is dropped
+            Err(err) if err.is_retryable() && attempt < CONNECT_ATTEMPTS => {
+                println!("Failed to connect to foundry (attempt {}/{}): {}", attempt, CONNECT_ATTEMPTS, err);
+                tokio::time::sleep(Duration::from_secs(5 * attempt as u64)).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Set up foundry client
     let args = Args::parse();
-    let foundry = FoundryClient::new(&args.host, &args.user, &args.password.unwrap_or("".to_owned())).await?;
+    if let Some(Command::Diagnose { file, save }) = &args.command {
+        let raw_world = match file {
+            Some(file) => serde_json::from_str(&tokio::fs::read_to_string(file).await?)?,
+            // Diagnosing is most useful for versions we refuse, so don't check them here
+            None => {
+                let (host, user, password) = credentials(&args)?;
+                FoundryClient::fetch_raw_world(host, user, &password).await?
+            }
+        };
+        if let Some(save) = save {
+            tokio::fs::write(save, serde_json::to_string_pretty(&raw_world)?).await?;
+        }
+        diagnose(&raw_world);
+        return Ok(());
+    }
+    let foundry = connect(&args).await?;
 
     // Set up discord client
     let token = env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN");
@@ -215,26 +372,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let store = PickleDb::load("janusdb", PickleDbDumpPolicy::AutoDump, SerializationMethod::Json)
         .unwrap_or_else(|_| PickleDb::new("janusdb", PickleDbDumpPolicy::AutoDump, SerializationMethod::Json));
 
+    let gm_role = args.gm_role.map(serenity::RoleId::new);
+    let enforce_walls = args.enforce_walls;
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![roll(), assoc()],
+            commands: vec![roll(), roll_formula(), assoc(), switch(), characters(), status(), combat::attack(), inventory::inventory(), inventory::use_item(), inventory::equip(), inventory::give(), resources::resource(), resources::rest(), saves::deathsave(), gm::gm(), journal::journal(), compendium::lookup(), tables::table(), macros::macros(), map::map(), movement::movement()],
             ..Default::default()
         })
-        .setup(|ctx, _ready, framework| {
+        .setup(move |ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
                 Ok(DiscordState {
                     foundry,
-                    store: tokio::sync::Mutex::new(store)
+                    store: tokio::sync::Mutex::new(store),
+                    gm_role,
+                    compendium: Default::default(),
+                    enforce_walls,
                 })
             })
         })
         .build();
 
-    let client = serenity::ClientBuilder::new(token, intents)
+    let mut client = serenity::ClientBuilder::new(token, intents)
         .framework(framework)
-        .await;
-    client.unwrap().start().await.unwrap();
+        .await?;
+    client.start().await?;
 
     println!("Finished");
     // socket.disconnect().expect("Disconnect failed");
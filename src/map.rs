@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use futures_util::future::join_all;
+use image::imageops::{self, FilterType};
+use image::{ImageFormat, Pixel, Rgba, RgbaImage};
+use poise::serenity_prelude as serenity;
+use crate::autocomplete::autocomplete_scene;
+use crate::dnd5e::DND5EToken;
+use crate::error::CommandError;
+use crate::gm::has_gm_role;
+use crate::grid::Geometry;
+use crate::world::{Disposition, GridType, Scene};
+use crate::{get_world, Context, DiscordError};
+
+/// Maps are scaled down to be no wider or taller than this
+const MAX_DIMENSION: u32 = 2048;
+
+/// Drawn where there is no background image, or it couldn't be read
+const EMPTY: Rgba<u8> = Rgba([40, 40, 40, 255]);
+
+/// Drawn for tokens whose image couldn't be read, coloured the way foundry colours token borders
+fn placeholder(disposition: Disposition) -> Rgba<u8> {
+    match disposition {
+        Disposition::Friendly => Rgba([67, 223, 223, 255]),
+        Disposition::Neutral => Rgba([241, 214, 16, 255]),
+        Disposition::Hostile => Rgba([231, 43, 0, 255]),
+        Disposition::Secret => Rgba([163, 73, 164, 255]),
+    }
+}
+
+/// A token to draw, in pixels relative to the top left of the map
+struct Sprite {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    src: Option<String>,
+    disposition: Disposition,
+}
+
+/// How a scene's grid is drawn
+enum GridLines {
+    /// Lines every so many pixels
+    Square(f64),
+    /// The edges of every hex, in pixels relative to the top left of the map, and the hexes' size
+    Hex { size: f64, edges: Vec<((f64, f64), (f64, f64))> },
+}
+
+/// Everything needed to draw a scene, gathered so that drawing needs no network access
+struct MapSource {
+    width: u32,
+    height: u32,
+    /// The grid and its line colour, if the grid is drawn
+    grid: Option<(GridLines, Rgba<u8>)>,
+    background: Option<String>,
+    sprites: Vec<Sprite>,
+    /// Downloaded files by path
+    files: HashMap<String, Vec<u8>>,
+}
+
+/// Read a colour like #aabbcc
+fn parse_color(color: &str, alpha: f64) -> Option<Rgba<u8>> {
+    let hex = color.strip_prefix('#')?;
+    let value = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)?;
+    let [_, r, g, b] = value.to_be_bytes();
+    Some(Rgba([r, g, b, (alpha.clamp(0.0, 1.0) * 255.0).round() as u8]))
+}
+
+/// The edges of every hex over the map, each once, relative to the top left of the map
+fn hex_edges(geometry: &Geometry, scene: &Scene<DND5EToken>) -> Vec<((f64, f64), (f64, f64))> {
+    let (offset_x, offset_y) = scene.offset();
+    let (first_column, first_row) = geometry.cell_at((offset_x, offset_y));
+    let (last_column, last_row) = geometry.cell_at((offset_x + scene.width as f64, offset_y + scene.height as f64));
+    // Neighbouring hexes share edges, which would come out twice as dark if drawn twice
+    let key = |(x, y): (f64, f64)| ((x * 10.0).round() as i64, (y * 10.0).round() as i64);
+    let mut seen = HashSet::new();
+    let mut edges = vec![];
+    for column in first_column - 1..=last_column + 1 {
+        for row in first_row - 1..=last_row + 1 {
+            let corners = geometry.outline((column, row));
+            for (index, &from) in corners.iter().enumerate() {
+                let to = corners[(index + 1) % corners.len()];
+                let (from, to) = ((from.0 - offset_x, from.1 - offset_y), (to.0 - offset_x, to.1 - offset_y));
+                let (a, b) = (key(from), key(to));
+                if seen.insert(if a < b { (a, b) } else { (b, a) }) {
+                    edges.push((from, to));
+                }
+            }
+        }
+    }
+    edges
+}
+
+/// Blend a straight line onto an image, leaving out its last pixel so that lines joined end to end don't overlap
+fn draw_line(canvas: &mut RgbaImage, (x0, y0): (f64, f64), (x1, y1): (f64, f64), color: &Rgba<u8>) {
+    let steps = (x1 - x0).abs().max((y1 - y0).abs()).ceil().max(1.0) as u32;
+    for step in 0..steps {
+        let t = step as f64 / steps as f64;
+        let (x, y) = ((x0 + (x1 - x0) * t).round(), (y0 + (y1 - y0) * t).round());
+        if x >= 0.0 && y >= 0.0 && (x as u32) < canvas.width() && (y as u32) < canvas.height() {
+            canvas.get_pixel_mut(x as u32, y as u32).blend(color);
+        }
+    }
+}
+
+/// Gather what's needed to draw a scene, downloading its background and token images
+async fn gather(ctx: Context<'_>, scene: &Scene<DND5EToken>, show_hidden: bool) -> MapSource {
+    let foundry = &ctx.data().foundry;
+    let (offset_x, offset_y) = scene.offset();
+    let sprites: Vec<Sprite> = scene.tokens.iter()
+        .map(|token| &token.base)
+        .filter(|token| show_hidden || !token.hidden)
+        .map(|token| {
+            // Textures are scaled about the centre of the token's space. Negative scales mirror them, which we ignore
+            let width = token.width * scene.grid.size;
+            let height = token.height * scene.grid.size;
+            let scaled_width = width * token.texture.scale_x.abs();
+            let scaled_height = height * token.texture.scale_y.abs();
+            Sprite {
+                x: token.x - offset_x + (width - scaled_width) / 2.0,
+                y: token.y - offset_y + (height - scaled_height) / 2.0,
+                width: scaled_width,
+                height: scaled_height,
+                src: token.texture.src.clone(),
+                disposition: token.disposition,
+            }
+        })
+        .collect();
+
+    let mut paths: Vec<&str> = sprites.iter()
+        .filter_map(|sprite| sprite.src.as_deref())
+        .chain(scene.background.src.as_deref())
+        .collect();
+    paths.sort();
+    paths.dedup();
+    // Missing images are drawn as placeholders rather than failing the whole map
+    let downloads = join_all(paths.iter().map(|path| foundry.download(path))).await;
+    let files = paths.into_iter()
+        .zip(downloads)
+        .filter_map(|(path, download)| Some((path.to_owned(), download.ok()?)))
+        .collect();
+
+    let color = scene.grid.color.as_deref().and_then(|color| parse_color(color, scene.grid.alpha));
+    let grid = match (scene.grid.kind, color) {
+        (GridType::Gridless, _) | (_, None) => None,
+        (GridType::Square, Some(color)) => Some((GridLines::Square(scene.grid.size), color)),
+        (_, Some(color)) => Geometry::new(scene).ok()
+            .map(|geometry| (GridLines::Hex { size: scene.grid.size, edges: hex_edges(&geometry, scene) }, color)),
+    };
+    MapSource {
+        width: scene.width.max(1),
+        height: scene.height.max(1),
+        grid,
+        background: scene.background.src.clone(),
+        sprites,
+        files,
+    }
+}
+
+/// Draw a scene as a png
+fn render(source: &MapSource) -> Result<Vec<u8>, CommandError> {
+    let scale = (MAX_DIMENSION as f64 / source.width.max(source.height) as f64).min(1.0);
+    let width = ((source.width as f64 * scale).round() as u32).max(1);
+    let height = ((source.height as f64 * scale).round() as u32).max(1);
+    let decode = |path: &Option<String>| path.as_ref()
+        .and_then(|path| source.files.get(path))
+        .and_then(|bytes| image::load_from_memory(bytes).ok());
+
+    let mut canvas = match decode(&source.background) {
+        Some(background) => background.resize_exact(width, height, FilterType::Triangle).to_rgba8(),
+        None => RgbaImage::from_pixel(width, height, EMPTY),
+    };
+
+    // Grids too fine to make out are left off
+    match &source.grid {
+        Some((GridLines::Square(size), color)) if size * scale >= 2.0 => {
+            let step = size * scale;
+            let mut line = 0.0;
+            while line < width.max(height) as f64 {
+                let at = line.round() as u32;
+                for y in 0..height {
+                    if at < width {
+                        canvas.get_pixel_mut(at, y).blend(color);
+                    }
+                }
+                for x in 0..width {
+                    if at < height {
+                        canvas.get_pixel_mut(x, at).blend(color);
+                    }
+                }
+                line += step;
+            }
+        }
+        Some((GridLines::Hex { size, edges }, color)) if size * scale >= 2.0 => {
+            for &((x0, y0), (x1, y1)) in edges {
+                draw_line(&mut canvas, (x0 * scale, y0 * scale), (x1 * scale, y1 * scale), color);
+            }
+        }
+        _ => {}
+    }
+
+    for sprite in &source.sprites {
+        let sprite_width = ((sprite.width * scale).round() as u32).max(1);
+        let sprite_height = ((sprite.height * scale).round() as u32).max(1);
+        let image = match decode(&sprite.src) {
+            Some(image) => image.resize_exact(sprite_width, sprite_height, FilterType::Triangle).to_rgba8(),
+            None => RgbaImage::from_pixel(sprite_width, sprite_height, placeholder(sprite.disposition)),
+        };
+        let x = (sprite.x * scale).round() as i64;
+        let y = (sprite.y * scale).round() as i64;
+        imageops::overlay(&mut canvas, &image, x, y);
+    }
+
+    let mut png = vec![];
+    canvas.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|err| CommandError::RenderFailed(err.to_string()))?;
+    Ok(png)
+}
+
+/// Shows a map of the active scene and where everyone stands
+#[poise::command(slash_command)]
+pub async fn map(
+    ctx: Context<'_>,
+    #[description = "Scene to show instead of the active one (GMs only)"]
+    #[autocomplete = "autocomplete_scene"]
+    scene: Option<String>,
+) -> Result<(), DiscordError> {
+    ctx.defer().await?;
+    let world = get_world(&ctx.data().foundry).await?;
+    let is_gm = has_gm_role(ctx).await;
+    let found = match &scene {
+        Some(_) if !is_gm => Err(CommandError::NotGm)?,
+        Some(name) => world.scenes.iter().find(|candidate| &candidate.document.name == name),
+        None => world.scenes.iter().find(|candidate| candidate.active),
+    }.ok_or(CommandError::SceneNotFound(scene.unwrap_or("active scene".into())))?;
+
+    // Only GMs get to see hidden tokens
+    let source = gather(ctx, found, is_gm).await;
+    let png = tokio::task::spawn_blocking(move || render(&source)).await??;
+    let attachment = serenity::CreateAttachment::bytes(png, "map.png");
+    let embed = serenity::CreateEmbed::new()
+        .title(&found.document.name)
+        .image("attachment://map.png");
+    ctx.send(poise::CreateReply::default().attachment(attachment).embed(embed)).await?;
+    Ok(())
+}
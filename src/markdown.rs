@@ -0,0 +1,178 @@
+/// Convert foundry's html (journal pages, item descriptions) into discord markdown.
+/// This is deliberately forgiving: tags we don't understand are dropped, keeping their text
+pub fn html_to_markdown(html: &str) -> String {
+    let mut out = String::new();
+    let mut rest = html;
+    // Ordered lists need to count their items. None for unordered lists
+    let mut lists: Vec<Option<u32>> = vec![];
+    // Links are written once their text is known
+    let mut link: Option<(String, usize)> = None;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&decode_entities(&rest[..start]));
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag.trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+        match (name.as_str(), closing) {
+            ("p" | "div", _) => paragraph_break(&mut out),
+            ("br", _) => out.push('\n'),
+            ("h1" | "h2" | "h3", false) => {
+                paragraph_break(&mut out);
+                out.push_str("## ");
+            }
+            ("h4" | "h5" | "h6", false) => {
+                paragraph_break(&mut out);
+                out.push_str("### ");
+            }
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", true) => paragraph_break(&mut out),
+            ("strong" | "b", _) => out.push_str("**"),
+            ("em" | "i", _) => out.push('*'),
+            ("u", _) => out.push_str("__"),
+            ("s" | "del", _) => out.push_str("~~"),
+            ("code", _) => out.push('`'),
+            ("blockquote", false) => {
+                paragraph_break(&mut out);
+                out.push_str("> ");
+            }
+            ("blockquote", true) => paragraph_break(&mut out),
+            ("hr", _) => {
+                paragraph_break(&mut out);
+                out.push_str("---\n");
+            }
+            ("ul", false) => lists.push(None),
+            ("ol", false) => lists.push(Some(0)),
+            ("ul" | "ol", true) => {
+                lists.pop();
+                paragraph_break(&mut out);
+            }
+            ("li", false) => {
+                if !out.is_empty() && !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str(&"  ".repeat(lists.len().saturating_sub(1)));
+                match lists.last_mut() {
+                    Some(Some(count)) => {
+                        *count += 1;
+                        out.push_str(&format!("{}. ", count));
+                    }
+                    _ => out.push_str("- "),
+                }
+            }
+            ("a", false) => {
+                link = attribute(tag, "href").map(|href| (href, out.len()));
+            }
+            ("a", true) => {
+                if let Some((href, text_start)) = link.take() {
+                    let text = out.split_off(text_start);
+                    out.push_str(&format!("[{}]({})", text, href));
+                }
+            }
+            _ => {}
+        }
+    }
+    out.push_str(&decode_entities(rest));
+    strip_enrichers(out.trim())
+}
+
+/// Ensure the output ends with a blank line, unless it is empty
+fn paragraph_break(out: &mut String) {
+    if out.is_empty() {
+        return;
+    }
+    while !out.ends_with("\n\n") {
+        out.push('\n');
+    }
+}
+
+/// Read a quoted attribute from the inside of a tag
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&format!("{}=", name))? + name.len() + 1;
+    let quote = tag[start..].chars().next()?;
+    if quote != '"' && quote != '\'' {
+        return None;
+    }
+    let value = &tag[start + 1..];
+    let end = value.find(quote)?;
+    Some(decode_entities(&value[..end]))
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Foundry's text enrichers, e.g. @UUID[JournalEntry.abc]{The Tavern} or [[/r 1d6]], shown as their labels
+fn strip_enrichers(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(at) = rest.find('@') {
+        out.push_str(&rest[..at]);
+        let candidate = &rest[at + 1..];
+        let word_end = candidate.find(|c: char| !c.is_alphanumeric()).unwrap_or(candidate.len());
+        let after_word = &candidate[word_end..];
+        match (word_end > 0, after_word.strip_prefix('[')) {
+            (true, Some(target)) => {
+                let Some(close) = target.find(']') else {
+                    out.push('@');
+                    rest = candidate;
+                    continue;
+                };
+                let after = &target[close + 1..];
+                match after.strip_prefix('{').and_then(|label| label.find('}').map(|end| (label, end))) {
+                    Some((label, end)) => {
+                        out.push_str(&format!("**{}**", &label[..end]));
+                        rest = &label[end + 1..];
+                    }
+                    None => {
+                        // No label, so fall back on the last part of the target
+                        let target = &target[..close];
+                        out.push_str(&format!("**{}**", target.rsplit('.').next().unwrap_or(target)));
+                        rest = after;
+                    }
+                }
+            }
+            _ => {
+                out.push('@');
+                rest = candidate;
+            }
+        }
+    }
+    out.push_str(rest);
+    out.replace("[[", "`").replace("]]", "`")
+}
+
+/// Split text into pages no longer than limit, preferring to break between paragraphs, then lines, then words
+pub fn paginate(text: &str, limit: usize) -> Vec<String> {
+    let mut pages = vec![];
+    let mut rest = text.trim();
+    while rest.chars().count() > limit {
+        let window_end = rest.char_indices().nth(limit).map_or(rest.len(), |(index, _)| index);
+        let window = &rest[..window_end];
+        let split = window.rfind("\n\n")
+            .or_else(|| window.rfind('\n'))
+            .or_else(|| window.rfind(' '))
+            .filter(|&split| split > 0)
+            .unwrap_or(window_end);
+        pages.push(rest[..split].trim().to_owned());
+        rest = rest[split..].trim_start();
+    }
+    if !rest.is_empty() || pages.is_empty() {
+        pages.push(rest.to_owned());
+    }
+    pages
+}
@@ -0,0 +1,110 @@
+use serde_json::json;
+use crate::dnd5e::{DND5EToken, DND5EWorld};
+use crate::documents::uuid;
+use crate::error::CommandError;
+use crate::grid::{parse_cell, segments_cross, Direction, Geometry};
+use crate::store::own_actor_id;
+use crate::tokens::active_scene;
+use crate::world::Scene;
+use crate::{autocomplete_nickname, get_world, Context, DiscordError};
+
+/// Most squares a single step command moves
+const MAX_SQUARES: u32 = 20;
+
+/// Whether a wall that blocks movement lies between two points. Open doors let tokens through
+fn crosses_wall(scene: &Scene<DND5EToken>, from: (f64, f64), to: (f64, f64)) -> bool {
+    scene.walls.iter()
+        .filter(|wall| wall.movement != 0)
+        .filter(|wall| !(wall.door != 0 && wall.ds == 1))
+        .any(|wall| segments_cross(from, to, (wall.c[0], wall.c[1]), (wall.c[2], wall.c[3])))
+}
+
+/// The invoking user's token on the active scene
+async fn own_token<'a>(ctx: Context<'_>, world: &'a DND5EWorld, as_actor: Option<&str>) -> Result<(&'a Scene<DND5EToken>, &'a DND5EToken), DiscordError> {
+    let actor_id = own_actor_id(ctx, as_actor).await?;
+    let scene = active_scene(world)?;
+    let token = scene.tokens.iter()
+        .find(|token| token.base.actor_id.as_deref() == Some(actor_id.as_str()))
+        .ok_or(CommandError::NoOwnToken)?;
+    Ok((scene, token))
+}
+
+/// Move the invoking user's token along a path of cells, checking each leg for walls if the bot was asked to
+async fn move_along(
+    ctx: Context<'_>,
+    as_actor: Option<&str>,
+    route: impl FnOnce(&Geometry, (i64, i64)) -> Result<Vec<(i64, i64)>, CommandError>,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let (scene, token) = own_token(ctx, &world, as_actor).await?;
+    let geometry = Geometry::new(scene)?;
+
+    let start = geometry.cell_at((token.base.x, token.base.y));
+    let path = route(&geometry, start)?;
+    let Some(&end) = path.last() else { return Ok(()) };
+    if let Some(&(column, row)) = path.iter().find(|&&cell| !geometry.contains(cell)) {
+        Err(CommandError::CellOffMap(format!("{}, {}", column, row)))?;
+    }
+    if ctx.data().enforce_walls {
+        let mut from = start;
+        for &cell in &path {
+            if crosses_wall(scene, geometry.center(from), geometry.center(cell)) {
+                Err(CommandError::BlockedByWall)?;
+            }
+            from = cell;
+        }
+    }
+
+    let (x, y) = geometry.top_left(end);
+    let scene_id = scene.document.id.as_deref().unwrap_or_default();
+    foundry.update_documents("Token", Some(&uuid("Scene", scene_id)), vec![json!({"_id": token.base.id, "x": x, "y": y})]).await?;
+    ctx.say(format!("Moved {} to ({}, {})", token.base.name, end.0, end.1)).await?;
+    Ok(())
+}
+
+/// Moves your token on the active scene
+#[poise::command(slash_command, rename = "move", subcommands("step", "to"), subcommand_required)]
+pub async fn movement(_ctx: Context<'_>) -> Result<(), DiscordError> {
+    Ok(())
+}
+
+/// Moves your token some squares in a direction
+#[poise::command(slash_command)]
+async fn step(
+    ctx: Context<'_>,
+    #[description = "Direction"] direction: Direction,
+    #[description = "How many squares. Defaults to 1"]
+    #[min = 1]
+    #[max = 20]
+    squares: Option<u32>,
+    #[description = "Nickname of the actor to move, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let squares = squares.unwrap_or(1).min(MAX_SQUARES);
+    move_along(ctx, as_actor.as_deref(), |geometry, start| {
+        let mut path = vec![];
+        let mut cell = start;
+        for _ in 0..squares {
+            cell = geometry.neighbour(cell, direction)?;
+            path.push(cell);
+        }
+        Ok(path)
+    }).await
+}
+
+/// Moves your token to a cell
+#[poise::command(slash_command)]
+async fn to(
+    ctx: Context<'_>,
+    #[description = "Cell, as column,row or battle map style, e.g. C5"] cell: String,
+    #[description = "Nickname of the actor to move, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let target = parse_cell(&cell).ok_or(CommandError::InvalidCell(cell.clone()))?;
+    move_along(ctx, as_actor.as_deref(), |_, _| Ok(vec![target])).await
+}
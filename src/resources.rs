@@ -0,0 +1,235 @@
+use poise::serenity_prelude as serenity;
+use serde_json::{json, Map, Value};
+use crate::autocomplete::fuzzy_filter;
+use crate::connection::FoundryClient;
+use crate::dnd5e::{DND5EActor, DND5EItem};
+use crate::documents::uuid;
+use crate::error::{CommandError, FoundryClientError};
+use crate::store::own_actor;
+use crate::version::uses_change;
+use crate::{autocomplete_nickname, get_world, Context, DiscordError};
+
+/// Where a pool's value is kept
+enum Source {
+    /// One of the actor's resources, by key
+    Resource(String),
+    /// An item's uses, by item id
+    Item(String),
+}
+
+/// Something with limited uses: one of an actor's resources, or a feature's uses
+struct Pool {
+    name: String,
+    value: i64,
+    max: i64,
+    /// When it comes back, as in Uses::per
+    per: Option<String>,
+    source: Source,
+}
+
+/// A rest, and the recovery periods it counts as
+#[derive(poise::ChoiceParameter, Clone, Copy, PartialEq, Debug)]
+pub enum RestKind {
+    Short,
+    Long,
+}
+
+impl RestKind {
+    /// Long rests count as a new day too
+    fn recovers(self, period: &str) -> bool {
+        match self {
+            RestKind::Short => period == "sr",
+            RestKind::Long => matches!(period, "sr" | "lr" | "day" | "dawn" | "dusk"),
+        }
+    }
+}
+
+fn describe_period(period: &str) -> Option<&'static str> {
+    match period {
+        "sr" => Some("short rest"),
+        "lr" => Some("long rest"),
+        "day" => Some("day"),
+        "dawn" => Some("dawn"),
+        "dusk" => Some("dusk"),
+        _ => None,
+    }
+}
+
+/// An actor's resources, its features' uses and the uses of anything else it has that recovers
+fn pools(actor: &DND5EActor) -> Vec<Pool> {
+    let mut pools = vec![];
+    if let DND5EActor::character { system, .. } = actor {
+        for (key, resource) in &system.resources {
+            let Some(max) = resource.max.filter(|max| *max > 0) else { continue };
+            let per = if resource.sr { Some("sr") } else if resource.lr { Some("lr") } else { None };
+            pools.push(Pool {
+                name: resource.label.clone().filter(|label| !label.is_empty()).unwrap_or_else(|| key.clone()),
+                value: resource.value.unwrap_or(0),
+                max,
+                per: per.map(str::to_owned),
+                source: Source::Resource(key.clone()),
+            });
+        }
+    }
+
+    let data = actor.roll_data();
+    for item in actor.base().into_iter().flat_map(|base| &base.items) {
+        let (Some(base), Some(uses)) = (item.base(), item.uses()) else { continue };
+        let Some(max) = uses.maximum(&data) else { continue };
+        let recovers = uses.per.as_deref().and_then(describe_period).is_some();
+        if !recovers && !matches!(item, DND5EItem::feat { .. }) {
+            continue;
+        }
+        pools.push(Pool {
+            name: base.document.name.clone(),
+            value: uses.value.unwrap_or(max),
+            max,
+            per: uses.per.clone(),
+            source: Source::Item(base.document.id.clone().unwrap_or_default()),
+        });
+    }
+    pools
+}
+
+/// Write new values for pools, along with any other changes to the actor
+async fn write_pools(foundry: &FoundryClient, actor_id: &str, mut actor_changes: Map<String, Value>, pools: &[(&Pool, i64)]) -> Result<(), FoundryClientError> {
+    let adapter = foundry.versions().adapter;
+    let mut item_updates = vec![];
+    for (pool, value) in pools {
+        match &pool.source {
+            Source::Resource(key) => {
+                actor_changes.insert(format!("system.resources.{}.value", key), json!(value));
+            }
+            Source::Item(id) => {
+                let (key, stored) = uses_change(adapter, pool.max, *value);
+                item_updates.push(json!({"_id": id, key: stored}));
+            }
+        }
+    }
+
+    if !actor_changes.is_empty() {
+        actor_changes.insert("_id".into(), json!(actor_id));
+        foundry.update_documents("Actor", None, vec![Value::Object(actor_changes)]).await?;
+    }
+    if !item_updates.is_empty() {
+        foundry.update_documents("Item", Some(&uuid("Actor", actor_id)), item_updates).await?;
+    }
+    Ok(())
+}
+
+/// Suggests the resources and limited-use features of the invoking user's active actor
+async fn autocomplete_pool(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let Ok(world) = get_world(&ctx.data().foundry).await else { return vec![] };
+    let Ok((actor, _)) = own_actor(ctx, &world, None).await else { return vec![] };
+    let names = pools(actor).into_iter().map(|pool| pool.name);
+    fuzzy_filter(names, partial, |name| name.as_str())
+}
+
+/// Resources and limited-use features
+#[poise::command(slash_command, subcommands("list", "spend"), subcommand_required)]
+pub async fn resource(_ctx: Context<'_>) -> Result<(), DiscordError> {
+    Ok(())
+}
+
+/// Lists your resources and limited-use features
+#[poise::command(slash_command)]
+async fn list(
+    ctx: Context<'_>,
+    #[description = "Nickname of the actor to show, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let world = get_world(&ctx.data().foundry).await?;
+    let (actor, _) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    let lines: Vec<String> = pools(actor).iter().map(|pool| {
+        match pool.per.as_deref().and_then(describe_period) {
+            Some(period) => format!("**{}** {}/{} (per {})", pool.name, pool.value, pool.max, period),
+            None => format!("**{}** {}/{}", pool.name, pool.value, pool.max),
+        }
+    }).collect();
+    let name = actor.base().map_or("", |base| base.document.name.as_str());
+    let embed = serenity::CreateEmbed::new()
+        .title(format!("{}'s resources", name))
+        .description(if lines.is_empty() { "Nothing with limited uses".into() } else { lines.join("\n") });
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// Spends uses of a resource or feature
+#[poise::command(slash_command)]
+async fn spend(
+    ctx: Context<'_>,
+    #[description = "Resource or feature"]
+    #[autocomplete = "autocomplete_pool"]
+    name: String,
+    #[description = "How many uses to spend. Defaults to 1"]
+    #[min = 1]
+    amount: Option<i64>,
+    #[description = "Nickname of the actor spending them, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let (actor, actor_id) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    let pools = pools(actor);
+    let pool = pools.iter()
+        .find(|pool| pool.name.eq_ignore_ascii_case(&name))
+        .ok_or(CommandError::PoolNotFound(name.clone()))?;
+    let amount = amount.unwrap_or(1);
+    if pool.value < amount {
+        Err(CommandError::NotEnoughUses(pool.name.clone(), pool.value))?;
+    }
+
+    let remaining = pool.value - amount;
+    write_pools(foundry, &actor_id, Map::new(), &[(pool, remaining)]).await?;
+    ctx.say(format!("Spent {} of {} ({}/{} left)", amount, pool.name, remaining, pool.max)).await?;
+    Ok(())
+}
+
+/// Takes a rest, recovering resources and limited uses. Long rests also restore hit points
+#[poise::command(slash_command)]
+pub async fn rest(
+    ctx: Context<'_>,
+    #[description = "Short or long rest"] kind: RestKind,
+    #[description = "Nickname of the actor resting, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let (actor, actor_id) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    let pools = pools(actor);
+    let recovered: Vec<(&Pool, i64)> = pools.iter()
+        .filter(|pool| pool.value < pool.max && pool.per.as_deref().is_some_and(|per| kind.recovers(per)))
+        .map(|pool| (pool, pool.max))
+        .collect();
+
+    let mut lines: Vec<String> = recovered.iter()
+        .map(|(pool, _)| format!("{} recovered ({}/{})", pool.name, pool.max, pool.max))
+        .collect();
+    let mut changes = Map::new();
+    // Hit dice and spell slots are left to foundry's own rest
+    if kind == RestKind::Long {
+        if let Some(max) = actor.max_hp() {
+            changes.insert("system.attributes.hp.value".into(), json!(max));
+            changes.insert("system.attributes.hp.temp".into(), json!(0));
+            lines.push(format!("Hit points restored to {}", max));
+        }
+    }
+    write_pools(foundry, &actor_id, changes, &recovered).await?;
+
+    let name = actor.base().map_or("", |base| base.document.name.as_str());
+    let title = match kind {
+        RestKind::Short => format!("{} takes a short rest", name),
+        RestKind::Long => format!("{} takes a long rest", name),
+    };
+    let embed = serenity::CreateEmbed::new()
+        .title(title)
+        .description(if lines.is_empty() { "Nothing to recover".into() } else { lines.join("\n") });
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use std::time::Duration;
+use poise::serenity_prelude as serenity;
+use poise::CreateReply;
+use serde_json::Value;
+use crate::dice::{Condition, DiceTerm, Keep, Modifier, Roll, Term};
+use crate::error::DiceError;
+use crate::{Context, DiscordError};
+
+/// How long roll results keep listening for button presses
+const REROLL_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Whether a d20 is rolled straight, or twice keeping the better/worse
+#[derive(poise::ChoiceParameter, Clone, Copy, PartialEq, Debug, Default)]
+pub enum RollMode {
+    #[default]
+    Normal,
+    Advantage,
+    Disadvantage,
+}
+
+impl RollMode {
+    /// The d20 portion of a roll for this mode
+    pub fn d20(self) -> DiceTerm {
+        match self {
+            RollMode::Normal => DiceTerm::new(1, 20),
+            RollMode::Advantage => DiceTerm::new(2, 20).keep(Keep::Highest(1)),
+            RollMode::Disadvantage => DiceTerm::new(2, 20).keep(Keep::Lowest(1)),
+        }
+    }
+}
+
+/// The parts of a d20 check that stay fixed no matter how often it is rerolled
+pub struct D20Check {
+    /// What is being rolled, e.g. "Stealth"
+    pub label: String,
+    /// The flat bonuses added to the d20, each named by its source
+    pub modifiers: Vec<Modifier>,
+    /// Bonus formulas from the sheet, such as a 1d4 from bless, whose dice are rolled afresh every time
+    pub bonuses: Vec<Term>,
+    /// The lowest the d20 can count as, e.g. 10 with Reliable Talent
+    pub minimum: Option<u32>,
+    /// Whether a 1 on the d20 is rerolled once, as with Halfling Lucky
+    pub reroll_ones: bool,
+}
+
+impl D20Check {
+    /// Roll the check once
+    pub fn roll(&self, mode: RollMode, guidance: bool) -> Roll {
+        let mut d20 = mode.d20();
+        d20.min = self.minimum;
+        if self.reroll_ones {
+            d20.reroll = Some((Condition::Equal(1), false));
+        }
+        let mut roll = Roll::default().dice(d20);
+        roll.terms.extend(self.modifiers.iter().cloned().map(Term::Flat));
+        roll.terms.extend(self.bonuses.iter().cloned());
+        if guidance {
+            roll = roll.dice(DiceTerm::new(1, 4).flavor("Guidance"));
+        }
+        roll.evaluate()
+    }
+}
+
+/// A formula kept in roll data, which may have been saved as a plain number. Blank formulas count as absent
+pub fn formula_at(data: &Value, pointer: &str) -> Option<String> {
+    match data.pointer(pointer)? {
+        Value::String(formula) if !formula.trim().is_empty() => Some(formula.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+/// Add the terms of a bonus formula, such as the 1d4 of bless, to a roll
+pub fn add_bonus(roll: &mut Roll, formula: Option<String>, data: &Value) -> Result<(), DiceError> {
+    if let Some(formula) = formula {
+        roll.terms.extend(Roll::parse(&formula, Some(data))?.terms);
+    }
+    Ok(())
+}
+
+/// What bonus formulas always come to, as dnd5e's simplifyBonus: flat terms count and dice are left out, so a
+/// 1d4 + 1 bonus gives 1
+pub fn flat_bonus<'a>(formulas: impl IntoIterator<Item = &'a String>, data: &Value) -> Result<i64, DiceError> {
+    let mut total = 0;
+    for formula in formulas {
+        total += Roll::parse(formula, Some(data))?.flat_total();
+    }
+    Ok(total)
+}
+
+/// Shows each die of a term, striking through dropped dice and bolding maximums and ones
+fn render_dice(dice: &DiceTerm) -> String {
+    let rendered: Vec<String> = dice.results.iter().map(|die| {
+        if !die.kept {
+            format!("~~{}~~", die.value)
+        } else if die.value == dice.faces || die.value == 1 {
+            format!("**{}**", die.value)
+        } else {
+            die.value.to_string()
+        }
+    }).collect();
+    format!("[{}] = {}", rendered.join(", "), dice.total())
+}
+
+/// Lays out a roll as an embed, with a field for every dice pool and modifier that went into it.
+/// Every command that rolls dice should reply through this, so results look alike
+pub fn render_roll(title: &str, roll: &Roll) -> serenity::CreateEmbed {
+    let mut embed = serenity::CreateEmbed::new()
+        .title(title)
+        .description(format!("`{}`", roll.formula()));
+    for term in &roll.terms {
+        embed = match term {
+            Term::Dice(dice) => {
+                let name = match &dice.flavor {
+                    Some(flavor) => format!("{} ({})", dice.formula(), flavor),
+                    None => dice.formula(),
+                };
+                embed.field(name, render_dice(dice), true)
+            }
+            Term::Flat(modifier) => embed.field(&modifier.source, format!("{:+}", modifier.value), true),
+        };
+    }
+
+    let (total, colour) = match roll.natural_d20() {
+        Some(20) => (format!("**{}** — natural 20!", roll.total()), serenity::Colour::DARK_GREEN),
+        Some(1) => (format!("**{}** — natural 1!", roll.total()), serenity::Colour::RED),
+        _ => (format!("**{}**", roll.total()), serenity::Colour::BLURPLE),
+    };
+    embed.field("Total", total, false).colour(colour)
+}
+
+/// The buttons offered beneath a roll result
+fn reroll_buttons(inspiration_used: bool) -> Vec<serenity::CreateActionRow> {
+    vec![serenity::CreateActionRow::Buttons(vec![
+        serenity::CreateButton::new("again").label("Roll again"),
+        serenity::CreateButton::new("adv").label("Advantage"),
+        serenity::CreateButton::new("dis").label("Disadvantage"),
+        serenity::CreateButton::new("guidance").label("Add Guidance (+1d4)"),
+        serenity::CreateButton::new("inspiration")
+            .label("Inspiration reroll")
+            .disabled(inspiration_used),
+    ])]
+}
+
+/// Reply with a rolled check, then keep rerolling it as the invoking user presses buttons until they go quiet
+pub async fn reply_with_rerolls(ctx: Context<'_>, check: D20Check, mode: RollMode) -> Result<(), DiscordError> {
+    let title = format!("Rolling {}", check.label);
+    // Edits replace the message's embeds, so the original result has to go along with them
+    let result = render_roll(&title, &check.roll(mode, false));
+    let reply = ctx.send(CreateReply::default()
+        .embed(result.clone())
+        .components(reroll_buttons(false))
+    ).await?;
+    let message_id = reply.message().await?.id;
+
+    let mut inspiration_used = false;
+    while let Some(press) = serenity::ComponentInteractionCollector::new(ctx.serenity_context())
+        .message_id(message_id)
+        .timeout(REROLL_TIMEOUT)
+        .await
+    {
+        if press.user.id != ctx.author().id {
+            press.create_response(ctx.serenity_context(), serenity::CreateInteractionResponse::Message(
+                serenity::CreateInteractionResponseMessage::new()
+                    .content("Only the person who rolled can reroll this")
+                    .ephemeral(true)
+            )).await?;
+            continue;
+        }
+
+        let embed = match press.data.custom_id.as_str() {
+            "adv" => render_roll(&format!("{} (advantage)", title), &check.roll(RollMode::Advantage, false)),
+            "dis" => render_roll(&format!("{} (disadvantage)", title), &check.roll(RollMode::Disadvantage, false)),
+            "guidance" => render_roll(&title, &check.roll(mode, true)),
+            "inspiration" if !inspiration_used => {
+                inspiration_used = true;
+                reply.edit(ctx, CreateReply::default().embed(result.clone()).components(reroll_buttons(true))).await?;
+                render_roll(&format!("{} (inspiration)", title), &check.roll(mode, false))
+            }
+            _ => render_roll(&title, &check.roll(mode, false)),
+        };
+        press.create_response(ctx.serenity_context(), serenity::CreateInteractionResponse::Message(
+            serenity::CreateInteractionResponseMessage::new().embed(embed)
+        )).await?;
+    }
+
+    // Stop offering buttons that no longer do anything
+    reply.edit(ctx, CreateReply::default().embed(result).components(vec![])).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_features_apply_to_the_d20() {
+        let check = D20Check { label: "Stealth".into(), modifiers: vec![], bonuses: vec![], minimum: Some(10), reroll_ones: true };
+        for mode in [RollMode::Normal, RollMode::Advantage, RollMode::Disadvantage] {
+            let roll = check.roll(mode, false);
+            let Some(Term::Dice(d20)) = roll.terms.first() else { panic!("no d20") };
+            assert_eq!(d20.min, Some(10));
+            assert_eq!(d20.reroll, Some((Condition::Equal(1), false)));
+            assert!(roll.total() >= 10);
+        }
+    }
+
+    #[test]
+    fn flat_bonus_leaves_out_dice() {
+        let data = serde_json::json!({"prof": 3});
+        let formulas = ["1d4 + 1".to_owned(), "@prof".to_owned(), "-2".to_owned()];
+        assert_eq!(flat_bonus(&formulas, &data).unwrap(), 2);
+        assert_eq!(flat_bonus(&[], &data).unwrap(), 0);
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+use poise::serenity_prelude as serenity;
+use poise::CreateReply;
+use serde_json::{json, Value};
+use crate::dice::Roll;
+use crate::dnd5e::DND5EActor;
+use crate::error::{CommandError, DiceError};
+use crate::rolls::{add_bonus, formula_at, render_roll, RollMode};
+use crate::store::own_actor;
+use crate::{autocomplete_nickname, get_world, Context, DiscordError};
+
+/// How long a concentration prompt waits for someone to roll it
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Roll a concentration save: a constitution save with any bonuses to saves and to concentration
+fn concentration_roll(data: &Value) -> Result<Roll, DiceError> {
+    let modifier = data.pointer("/abilities/con/mod").and_then(Value::as_i64).unwrap_or(0);
+    // Features like War Caster set the mode concentration is always rolled with
+    let mode = match data.pointer("/attributes/concentration/roll/mode").and_then(Value::as_i64) {
+        Some(mode) if mode > 0 => RollMode::Advantage,
+        Some(mode) if mode < 0 => RollMode::Disadvantage,
+        _ => RollMode::Normal,
+    };
+    let mut roll = Roll::default()
+        .dice(mode.d20())
+        .modifier("Constitution modifier", modifier);
+    let proficiency = data.pointer("/abilities/con/proficient").and_then(Value::as_f64).unwrap_or(0.0)
+        * data["prof"].as_f64().unwrap_or(0.0);
+    if proficiency >= 1.0 {
+        roll = roll.modifier("Proficiency", proficiency.floor() as i64);
+    }
+    add_bonus(&mut roll, formula_at(data, "/abilities/con/bonuses/save"), data)?;
+    add_bonus(&mut roll, formula_at(data, "/bonuses/abilities/save"), data)?;
+    add_bonus(&mut roll, formula_at(data, "/attributes/concentration/bonuses/save"), data)?;
+    Ok(roll.evaluate())
+}
+
+/// When an actor that is concentrating takes damage through the bot and survives it, post a prompt to roll the
+/// concentration save. Anyone can press it, since the player may not be the one who dealt the damage
+pub async fn concentration_prompt(ctx: Context<'_>, name: &str, actor: &DND5EActor, damage: i64, hp: i64) -> Result<(), DiscordError> {
+    if damage <= 0 || hp <= 0 || !actor.concentrating() {
+        return Ok(());
+    }
+    let dc = (damage / 2).max(10);
+    let data = actor.roll_data();
+    let reply = ctx.send(CreateReply::default()
+        .content(format!("{} is concentrating and took {} damage: DC {} Constitution save", name, damage, dc))
+        .components(vec![serenity::CreateActionRow::Buttons(vec![
+            serenity::CreateButton::new("concentration").label("Roll concentration save"),
+        ])])
+    ).await?;
+    let message_id = reply.message().await?.id;
+
+    if let Some(press) = serenity::ComponentInteractionCollector::new(ctx.serenity_context())
+        .message_id(message_id)
+        .timeout(PROMPT_TIMEOUT)
+        .await
+    {
+        let roll = concentration_roll(&data)?;
+        let result = if roll.total() >= dc { "Concentration held" } else { "Concentration lost" };
+        let embed = render_roll(&format!("{}: concentration (DC {})", name, dc), &roll).field("Result", result, false);
+        press.create_response(ctx.serenity_context(), serenity::CreateInteractionResponse::Message(
+            serenity::CreateInteractionResponseMessage::new().embed(embed)
+        )).await?;
+    }
+    reply.edit(ctx, CreateReply::default().components(vec![])).await?;
+    Ok(())
+}
+
+/// Rolls a death saving throw for a character at 0 hit points
+#[poise::command(slash_command)]
+pub async fn deathsave(
+    ctx: Context<'_>,
+    #[description = "Roll with advantage or disadvantage"] mode: Option<RollMode>,
+    #[description = "Nickname of the actor to roll as, if not your active one"]
+    #[autocomplete = "autocomplete_nickname"]
+    #[rename = "as"]
+    as_actor: Option<String>,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let (actor, actor_id) = own_actor(ctx, &world, as_actor.as_deref()).await?;
+    // Only characters make death saves
+    let DND5EActor::character { base, system } = actor else { Err(CommandError::InvalidAssocChar)? };
+    let name = base.document.name.as_str();
+    let death = &system.attributes.death;
+    if system.attributes.hp.value > 0 {
+        Err(CommandError::NotDying(name.into()))?;
+    }
+    if death.failure >= 3 {
+        Err(CommandError::AlreadyDead(name.into()))?;
+    }
+
+    let data = actor.roll_data();
+    let mut roll = Roll::default().dice(mode.unwrap_or_default().d20());
+    add_bonus(&mut roll, formula_at(&data, "/bonuses/abilities/save"), &data)?;
+    add_bonus(&mut roll, formula_at(&data, "/attributes/death/bonuses/save"), &data)?;
+    let roll = roll.evaluate();
+
+    // As the dnd5e system does: three successes stabilise and reset the count, a natural 20 brings them back with 1 hp
+    let mut update = json!({"_id": actor_id});
+    let (success, failure) = (death.success, death.failure);
+    let result = match roll.natural_d20() {
+        Some(20) => {
+            update["system.attributes.hp.value"] = json!(1);
+            update["system.attributes.death.success"] = json!(0);
+            update["system.attributes.death.failure"] = json!(0);
+            format!("Natural 20! {} regains 1 hit point", name)
+        }
+        Some(1) => {
+            let failure = (failure + 2).min(3);
+            update["system.attributes.death.failure"] = json!(failure);
+            if failure >= 3 { format!("Natural 1: two failures. {} has died", name) } else { format!("Natural 1: two failures ({}/3)", failure) }
+        }
+        _ if roll.total() >= 10 => {
+            let success = success + 1;
+            if success >= 3 {
+                update["system.attributes.death.success"] = json!(0);
+                update["system.attributes.death.failure"] = json!(0);
+                format!("Success! {} is stable", name)
+            } else {
+                update["system.attributes.death.success"] = json!(success);
+                format!("Success ({}/3)", success)
+            }
+        }
+        _ => {
+            let failure = failure + 1;
+            update["system.attributes.death.failure"] = json!(failure);
+            if failure >= 3 { format!("Failure. {} has died", name) } else { format!("Failure ({}/3)", failure) }
+        }
+    };
+    foundry.update_documents("Actor", None, vec![update]).await?;
+
+    let embed = render_roll(&format!("{}: death saving throw", name), &roll).field("Result", result, false);
+    ctx.send(CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice::{Keep, Term};
+
+    #[test]
+    fn concentration_counts_constitution_bonuses_and_roll_mode() {
+        let data = json!({
+            "prof": 3,
+            "abilities": {"con": {"mod": 2, "proficient": 1, "bonuses": {"save": "1"}}},
+            "bonuses": {"abilities": {"save": "2"}},
+            "attributes": {"concentration": {"bonuses": {"save": "4"}, "roll": {"mode": 1}}},
+        });
+        let roll = concentration_roll(&data).unwrap();
+        let Some(Term::Dice(d20)) = roll.terms.first() else { panic!("no d20") };
+        assert_eq!((d20.count, d20.keep), (2, Some(Keep::Highest(1))));
+        assert_eq!(roll.total() - d20.total(), 2 + 3 + 1 + 2 + 4);
+
+        let data = json!({"abilities": {"con": {"mod": 0}}, "attributes": {"concentration": {"roll": {"mode": -1}}}});
+        let Some(Term::Dice(d20)) = concentration_roll(&data).unwrap().terms.first().cloned() else { panic!("no d20") };
+        assert_eq!(d20.keep, Some(Keep::Lowest(1)));
+    }
+}
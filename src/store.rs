@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use pickledb::PickleDb;
+use serde::{Deserialize, Serialize};
+use crate::dnd5e::{DND5EActor, DND5EWorld};
+use crate::error::CommandError;
+use crate::Context;
+
+/// The actors a discord user has associated themselves with
+#[derive(Serialize, Deserialize, Default)]
+pub struct UserActors {
+    /// Nickname of the actor used when a command does not specify one
+    pub active: Option<String>,
+    /// Maps nicknames to foundry actor ids
+    pub actors: BTreeMap<String, String>,
+}
+
+impl UserActors {
+    /// Load the associations for a user. Older stores kept a bare actor id per user, which we treat as a single active actor
+    pub fn load(store: &PickleDb, user_id: u64) -> UserActors {
+        let key = user_id.to_string();
+        if let Some(actors) = store.get::<UserActors>(&key) {
+            return actors;
+        }
+        match store.get::<String>(&key) {
+            Some(id) => UserActors {
+                active: Some("default".into()),
+                actors: BTreeMap::from([("default".into(), id)]),
+            },
+            None => UserActors::default(),
+        }
+    }
+
+    /// Persist the associations for a user
+    pub fn save(&self, store: &mut PickleDb, user_id: u64) -> Result<(), pickledb::error::Error> {
+        store.set(&user_id.to_string(), self)
+    }
+
+    /// Add (or replace) an actor under the given nickname and make it the active one
+    pub fn insert(&mut self, nickname: &str, actor_id: &str) {
+        self.actors.insert(nickname.to_owned(), actor_id.to_owned());
+        self.active = Some(nickname.to_owned());
+    }
+
+    /// Make the actor with the given nickname the active one
+    pub fn switch(&mut self, nickname: &str) -> Result<(), CommandError> {
+        if !self.actors.contains_key(nickname) {
+            return Err(CommandError::UnknownNickname(nickname.to_owned()));
+        }
+        self.active = Some(nickname.to_owned());
+        Ok(())
+    }
+
+    /// Resolve the actor id to act as - either the provided nickname, or the active actor
+    pub fn resolve(&self, nickname: Option<&str>) -> Result<String, CommandError> {
+        match nickname {
+            Some(nickname) => self.actors.get(nickname)
+                .cloned()
+                .ok_or(CommandError::UnknownNickname(nickname.to_owned())),
+            None => self.active.as_ref()
+                .and_then(|active| self.actors.get(active))
+                .cloned()
+                .ok_or(CommandError::MissingAssocChar),
+        }
+    }
+}
+
+/// The id of the actor the invoking user acts as: the one they nicknamed, or their active one
+pub async fn own_actor_id(ctx: Context<'_>, nickname: Option<&str>) -> Result<String, CommandError> {
+    let store = ctx.data().store.lock().await;
+    UserActors::load(&store, ctx.author().id.get()).resolve(nickname)
+}
+
+/// The actor the invoking user acts as, and its id
+pub async fn own_actor<'a>(ctx: Context<'_>, world: &'a DND5EWorld, nickname: Option<&str>) -> Result<(&'a DND5EActor, String), CommandError> {
+    let actor_id = own_actor_id(ctx, nickname).await?;
+    let actor = world.actors.iter()
+        .find(|actor| actor.base().is_some_and(|base| base.document.id.as_ref() == Some(&actor_id)))
+        .ok_or(CommandError::InvalidAssocChar)?;
+    Ok((actor, actor_id))
+}
+
+/// Table-wide options that GMs set from discord
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Settings {
+    /// Whether damage from hits made with /attack is applied to the target
+    pub auto_apply_damage: bool,
+}
+
+impl Settings {
+    /// Where settings live in the store. User ids are numeric, so this can't collide with them
+    const KEY: &'static str = "settings";
+
+    pub fn load(store: &PickleDb) -> Settings {
+        store.get(Self::KEY).unwrap_or_default()
+    }
+
+    pub fn save(&self, store: &mut PickleDb) -> Result<(), pickledb::error::Error> {
+        store.set(Self::KEY, self)
+    }
+}
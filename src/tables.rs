@@ -0,0 +1,210 @@
+use std::collections::HashSet;
+use poise::serenity_prelude as serenity;
+use serde_json::json;
+use crate::autocomplete::fuzzy_filter;
+use crate::connection::FoundryClient;
+use crate::dice::Roll;
+use crate::dnd5e::DND5EWorld;
+use crate::documents::uuid;
+use crate::error::CommandError;
+use crate::markdown::html_to_markdown;
+use crate::world::{Permissions, ResultType, RollTable, TableResult, User};
+use crate::{get_world, Context, DiscordError};
+
+/// Most draws a single command may make
+const MAX_COUNT: u32 = 20;
+/// Results that are themselves tables are drawn from in turn, but only this deep
+const MAX_DEPTH: usize = 4;
+/// Without replacement, a roll can land only on results that were already drawn. Roll again this many times before giving up
+const MAX_REROLLS: usize = 10;
+
+/// A result that was drawn, and what was rolled to draw it
+struct Draw<'a> {
+    table: &'a RollTable,
+    result: &'a TableResult,
+    total: i64,
+    /// How many tables deep this result was drawn
+    depth: usize,
+}
+
+/// Draws from tables, remembering what was drawn so that a table without replacement never gives the same result twice
+struct Drawer<'a> {
+    world: &'a DND5EWorld,
+    drawn: HashSet<&'a str>,
+    draws: Vec<Draw<'a>>,
+}
+
+impl<'a> Drawer<'a> {
+    fn new(world: &'a DND5EWorld) -> Self {
+        Drawer { world, drawn: HashSet::new(), draws: vec![] }
+    }
+
+    /// The results of a table that may still be drawn
+    fn available(&self, table: &'a RollTable) -> Vec<&'a TableResult> {
+        table.results.iter()
+            .filter(|result| table.replacement || !(result.drawn || self.drawn.contains(result.id.as_str())))
+            .collect()
+    }
+
+    fn draw(&mut self, table: &'a RollTable, depth: usize) -> Result<(), DiscordError> {
+        let available = self.available(table);
+        if available.is_empty() {
+            return Err(CommandError::TableExhausted(table.document.name.clone()).into());
+        }
+        let formula = if table.formula.trim().is_empty() {
+            let highest = table.results.iter().map(|result| result.range[1]).max().unwrap_or(1);
+            format!("1d{}", highest)
+        } else {
+            table.formula.clone()
+        };
+
+        for _ in 0..MAX_REROLLS {
+            let total = Roll::parse(&formula, None)?.evaluate().total();
+            let hits: Vec<&'a TableResult> = available.iter()
+                .copied()
+                .filter(|result| result.range[0] <= total && total <= result.range[1])
+                .collect();
+            if hits.is_empty() {
+                continue;
+            }
+            for result in hits {
+                if !table.replacement {
+                    self.drawn.insert(&result.id);
+                }
+                self.draws.push(Draw { table, result, total, depth });
+                if let Some(inner) = self.inner_table(result).filter(|_| depth < MAX_DEPTH) {
+                    self.draw(inner, depth + 1)?;
+                }
+            }
+            return Ok(());
+        }
+        Err(CommandError::TableNoResult(table.document.name.clone()).into())
+    }
+
+    /// The table a result links to, if it links to one
+    fn inner_table(&self, result: &TableResult) -> Option<&'a RollTable> {
+        if result.kind != ResultType::Document || result.document_collection.as_deref() != Some("RollTable") {
+            return None;
+        }
+        self.world.tables.iter().find(|table| table.document.id == result.document_id)
+    }
+}
+
+/// The tables our foundry user may see
+fn readable_tables<'a>(world: &'a DND5EWorld, user: &'a User) -> impl Iterator<Item = &'a RollTable> {
+    world.tables.iter().filter(move |table| table.document.ownership.level(user) >= Permissions::Observer)
+}
+
+/// A result as discord markdown
+fn render_result(world: &DND5EWorld, result: &TableResult) -> String {
+    match result.kind {
+        ResultType::Text => html_to_markdown(&result.text),
+        ResultType::Document => format!("**{}** ({})", result.text, result.document_collection.as_deref().unwrap_or("document")),
+        ResultType::Pack => {
+            let collection = result.document_collection.as_deref().unwrap_or("");
+            let label = world.packs.iter()
+                .find(|pack| pack.collection() == collection)
+                .map_or(collection, |pack| pack.label.as_str());
+            format!("**{}** (from {})", result.text, label)
+        }
+    }
+}
+
+/// A result as foundry chat html, linking documents so they can be opened from the chat log
+fn result_html(foundry: &FoundryClient, world: &DND5EWorld, result: &TableResult) -> String {
+    let (Some(collection), Some(id)) = (&result.document_collection, &result.document_id) else {
+        return result.text.clone();
+    };
+    match result.kind {
+        ResultType::Text => result.text.clone(),
+        ResultType::Document => format!("@UUID[{}]{{{}}}", uuid(collection, id), result.text),
+        ResultType::Pack => {
+            let document_type = world.packs.iter()
+                .find(|pack| &pack.collection() == collection)
+                .map(|pack| pack.document_type.as_str());
+            // Core 12 added the document type to compendium uuids
+            match document_type {
+                Some(document_type) if foundry.versions().core.generation >= 12 => {
+                    format!("@UUID[Compendium.{}.{}.{}]{{{}}}", collection, document_type, id, result.text)
+                }
+                _ => format!("@UUID[Compendium.{}.{}]{{{}}}", collection, id, result.text),
+            }
+        }
+    }
+}
+
+/// Roll on random tables
+#[poise::command(slash_command, subcommands("draw"), subcommand_required)]
+pub async fn table(_ctx: Context<'_>) -> Result<(), DiscordError> {
+    Ok(())
+}
+
+/// Suggests the names of tables our foundry user can see
+async fn autocomplete_table(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let foundry = &ctx.data().foundry;
+    let Ok(world) = get_world(foundry).await else { return vec![] };
+    let Some(user) = world.user(foundry.user_id()) else { return vec![] };
+    let names = readable_tables(&world, user).map(|table| table.document.name.clone());
+    fuzzy_filter(names, partial, |name| name.as_str())
+}
+
+/// Draws results from a table
+#[poise::command(slash_command)]
+async fn draw(
+    ctx: Context<'_>,
+    #[description = "Table name"]
+    #[autocomplete = "autocomplete_table"]
+    name: String,
+    #[description = "How many results to draw"]
+    #[min = 1]
+    #[max = 20]
+    count: Option<u32>,
+    #[description = "Also post the draw to the foundry chat"] post: Option<bool>,
+) -> Result<(), DiscordError> {
+    let foundry = &ctx.data().foundry;
+    let world = get_world(foundry).await?;
+    let user = world.user(foundry.user_id()).ok_or(CommandError::InvalidFoundryUser)?;
+    let table = readable_tables(&world, user)
+        .find(|table| table.document.name == name)
+        .ok_or(CommandError::TableNotFound(name.clone()))?;
+
+    let mut drawer = Drawer::new(&world);
+    for _ in 0..count.unwrap_or(1).min(MAX_COUNT) {
+        drawer.draw(table, 0)?;
+    }
+
+    // Mark what was drawn from tables without replacement, so foundry won't draw it again either
+    let mut drawn_tables: Vec<&RollTable> = vec![];
+    for draw in drawer.draws.iter().filter(|draw| !draw.table.replacement) {
+        if !drawn_tables.iter().any(|table| table.document.id == draw.table.document.id) {
+            drawn_tables.push(draw.table);
+        }
+    }
+    for drawn_table in drawn_tables {
+        let Some(table_id) = &drawn_table.document.id else { continue };
+        let updates = drawer.draws.iter()
+            .filter(|draw| draw.table.document.id.as_ref() == Some(table_id))
+            .map(|draw| json!({"_id": draw.result.id, "drawn": true}))
+            .collect();
+        foundry.update_documents("TableResult", Some(&uuid("RollTable", table_id)), updates).await?;
+    }
+
+    let lines: Vec<String> = drawer.draws.iter()
+        .map(|draw| format!("{}`{}` {}", "↳ ".repeat(draw.depth), draw.total, render_result(&world, draw.result)))
+        .collect();
+    let mut embed = serenity::CreateEmbed::new()
+        .title(format!("Drew from {}", table.document.name))
+        .description(lines.join("\n"));
+    if let Some(img) = drawer.draws.first().and_then(|draw| draw.result.img.as_deref()) {
+        embed = embed.thumbnail(foundry.url_for(img));
+    }
+
+    if post.unwrap_or(false) {
+        let content: Vec<String> = drawer.draws.iter()
+            .map(|draw| result_html(foundry, &world, draw.result))
+            .collect();
+        foundry.create_chat_message(&content.join("<br>"), Some(&format!("Draws from {}", table.document.name))).await?;
+    }
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
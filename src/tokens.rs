@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use poise::serenity_prelude as serenity;
+use serde_json::{json, Map, Value};
+use crate::dnd5e::{DND5EActor, DND5EToken, DND5EWorld};
+use crate::error::CommandError;
+use crate::grid::Geometry;
+use crate::world::Scene;
+
+/// Where an unlinked token's actor is put in the raw world. Not a foundry field
+pub const SYNTHETIC_ACTOR: &str = "syntheticActor";
+
+/// Merge a delta onto a document, recursing into objects. Anything else in the delta replaces what was there
+fn merge_into(target: &mut Value, delta: &Value) {
+    match (target.as_object_mut(), delta.as_object()) {
+        (Some(target), Some(delta)) => {
+            for (key, value) in delta {
+                merge_into(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        _ => *target = delta.clone(),
+    }
+}
+
+/// Merge embedded documents by id. Tombstones in the delta mark documents deleted from the token's copy
+fn merge_embedded(target: &mut Value, delta: &Value) {
+    let Some(delta) = delta.as_array() else { return };
+    if !target.is_array() {
+        *target = json!([]);
+    }
+    let Some(target) = target.as_array_mut() else { return };
+    for document in delta {
+        let id = document.get("_id").and_then(Value::as_str);
+        let existing = target.iter().position(|candidate| candidate.get("_id").and_then(Value::as_str) == id);
+        let tombstone = document.get("_tombstone").and_then(Value::as_bool).unwrap_or(false);
+        match (existing, tombstone) {
+            (Some(index), true) => {
+                target.remove(index);
+            }
+            (None, true) => {}
+            (Some(index), false) => merge_into(&mut target[index], document),
+            (None, false) => target.push(document.clone()),
+        }
+    }
+}
+
+/// Build an unlinked token's actor from its base actor and the token's delta, as foundry does
+pub fn apply_delta(base: &Value, delta: &Value) -> Value {
+    let mut actor = base.clone();
+    if let (Some(target), Some(delta)) = (actor.as_object_mut(), delta.as_object()) {
+        for (key, value) in delta {
+            match key.as_str() {
+                // The delta's own bookkeeping, not overrides
+                "_id" | "_stats" => {}
+                "items" | "effects" => merge_embedded(target.entry(key.clone()).or_insert(json!([])), value),
+                // Null means the base actor's value is kept
+                _ if value.is_null() => {}
+                _ => merge_into(target.entry(key.clone()).or_insert(Value::Null), value),
+            }
+        }
+    }
+    actor
+}
+
+/// Give every unlinked token in the raw world the actor it stands for, so that it is read along with the token
+pub fn synthesize_token_actors(raw_world: &mut Value) {
+    let actors: HashMap<String, Value> = raw_world.get("actors")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|actor| Some((actor.get("_id")?.as_str()?.to_owned(), actor.clone())))
+        .collect();
+
+    let tokens = raw_world.get_mut("scenes")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|scene| scene.get_mut("tokens").and_then(Value::as_array_mut))
+        .flatten();
+    for token in tokens {
+        if token.get("actorLink").and_then(Value::as_bool).unwrap_or(false) {
+            continue;
+        }
+        let Some(base) = token.get("actorId").and_then(Value::as_str).and_then(|id| actors.get(id)) else { continue };
+        let delta = token.get("delta").or(token.get("actorData")).cloned().unwrap_or_default();
+        token[SYNTHETIC_ACTOR] = apply_delta(base, &delta);
+    }
+}
+
+/// The actor a token stands for: its own copy if unlinked, otherwise the world's actor
+pub fn token_actor<'a>(world: &'a DND5EWorld, token: &'a DND5EToken) -> Option<&'a DND5EActor> {
+    if !token.base.actor_link {
+        if let Some(actor) = token.actor.valid() {
+            return Some(actor);
+        }
+    }
+    let actor_id = token.base.actor_id.as_deref()?;
+    world.actors.iter().find(|actor| actor.base().is_some_and(|base| base.document.id.as_deref() == Some(actor_id)))
+}
+
+/// The scene tokens are picked from: the active one
+pub fn active_scene(world: &DND5EWorld) -> Result<&Scene<DND5EToken>, CommandError> {
+    world.scenes.iter()
+        .find(|scene| scene.active)
+        .ok_or(CommandError::SceneNotFound("active scene".into()))
+}
+
+/// The grid cell a token's top left corner is in, counting from the top left of the map
+pub fn token_cell(scene: &Scene<DND5EToken>, token: &DND5EToken) -> (i64, i64) {
+    match Geometry::new(scene) {
+        Ok(geometry) => geometry.cell_at((token.base.x, token.base.y)),
+        // Gridless scenes are measured in grid sized squares
+        Err(_) => {
+            let (offset_x, offset_y) = scene.offset();
+            let size = scene.grid.size.max(1.0);
+            (((token.base.x - offset_x) / size).floor() as i64, ((token.base.y - offset_y) / size).floor() as i64)
+        }
+    }
+}
+
+/// How a token is told apart in suggestions. Tokens sharing a name, like a pack of goblins, are labelled by position
+pub fn token_label(scene: &Scene<DND5EToken>, token: &DND5EToken) -> String {
+    let shared = scene.tokens.iter().filter(|other| other.base.name == token.base.name).count() > 1;
+    if shared {
+        let (column, row) = token_cell(scene, token);
+        format!("{} ({}, {})", token.base.name, column, row)
+    } else {
+        token.base.name.clone()
+    }
+}
+
+/// Find a token by id, as sent by autocomplete, or else by label or name
+pub fn find_token<'a>(scene: &'a Scene<DND5EToken>, query: &str) -> Result<&'a DND5EToken, CommandError> {
+    scene.tokens.iter()
+        .find(|token| token.base.id.as_deref() == Some(query))
+        .or_else(|| scene.tokens.iter().find(|token| token_label(scene, token) == query))
+        .or_else(|| scene.tokens.iter().find(|token| token.base.name.eq_ignore_ascii_case(query)))
+        .ok_or(CommandError::TokenNotFound(query.into()))
+}
+
+/// Read a number from actor data by dotted path
+fn number_at(data: &Value, path: &str) -> Option<i64> {
+    path.split('.').try_fold(data, |value, key| value.get(key))?.as_i64()
+}
+
+/// The changes to an actor that take damage, or with a negative amount, healing. Temporary hit points are lost first.
+/// Returns the changes, as dotted paths, and the resulting hit points
+pub fn damage_changes(actor: &DND5EActor, amount: i64) -> (Map<String, Value>, i64) {
+    let data = actor.roll_data();
+    let value = number_at(&data, "attributes.hp.value").unwrap_or(0);
+    let max = actor.max_hp();
+    let temp = number_at(&data, "attributes.hp.temp").unwrap_or(0);
+
+    let mut changes = Map::new();
+    let hp = if amount >= 0 {
+        let absorbed = amount.min(temp);
+        if absorbed > 0 {
+            changes.insert("system.attributes.hp.temp".into(), json!(temp - absorbed));
+        }
+        (value - (amount - absorbed)).max(0)
+    } else {
+        // Without a maximum, healing isn't capped rather than not happening at all
+        max.map_or(value - amount, |max| (value - amount).min(max))
+    };
+    changes.insert("system.attributes.hp.value".into(), json!(hp));
+    (changes, hp)
+}
+
+/// A summary of an actor's sheet
+pub fn sheet_embed(name: &str, actor: &DND5EActor) -> serenity::CreateEmbed {
+    let data = actor.roll_data();
+    let hp = match (number_at(&data, "attributes.hp.value"), actor.max_hp()) {
+        (Some(value), Some(max)) => format!("{}/{}", value, max),
+        (Some(value), None) => value.to_string(),
+        _ => "?".into(),
+    };
+    let ac = actor.armor_class().map_or("?".into(), |ac| ac.to_string());
+
+    let mut embed = serenity::CreateEmbed::new()
+        .title(name)
+        .field("HP", hp, true)
+        .field("AC", ac, true);
+    let abilities: Vec<String> = ["str", "dex", "con", "int", "wis", "cha"].iter()
+        .filter_map(|ability| {
+            let score = number_at(&data, &format!("abilities.{}.value", ability))?;
+            let modifier = number_at(&data, &format!("abilities.{}.mod", ability))?;
+            Some(format!("**{}** {} ({:+})", ability.to_uppercase(), score, modifier))
+        })
+        .collect();
+    if !abilities.is_empty() {
+        embed = embed.field("Abilities", abilities.join("  "), false);
+    }
+    embed
+}
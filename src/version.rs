@@ -0,0 +1,316 @@
+use std::fmt::{Display, Formatter};
+use serde_json::Value;
+use crate::dnd5e::{uses_maximum, DND5EActor};
+use crate::error::FoundryClientError;
+use crate::tokens::SYNTHETIC_ACTOR;
+
+/// The foundry core release, e.g. generation 12 build 331
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoreVersion {
+    pub generation: u32,
+    pub build: u32,
+}
+
+/// The game system running in the world, e.g. dnd5e 3.3.1
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemVersion {
+    pub id: String,
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+/// The data model generations we know how to read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Adapter {
+    /// dnd5e 3.x, on core 11 or 12
+    DND5E3,
+    /// dnd5e 4.x and 5.x, on core 12 or 13. Item uses track what was spent rather than what remains
+    DND5E4,
+}
+
+/// Everything we detected about the server's versions
+#[derive(Debug, Clone)]
+pub struct Versions {
+    pub core: CoreVersion,
+    pub system: SystemVersion,
+    pub adapter: Adapter,
+}
+
+impl Display for CoreVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.generation, self.build)
+    }
+}
+
+impl Display for SystemVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}.{}.{}", self.id, self.major, self.minor, self.patch)
+    }
+}
+
+/// Split a dotted version into numbers, treating anything missing or unreadable as 0
+fn parse_dotted(version: &str) -> Vec<u32> {
+    version.split('.').map(|part| {
+        part.chars().take_while(char::is_ascii_digit).collect::<String>().parse().unwrap_or(0)
+    }).collect()
+}
+
+fn detect_core(raw_world: &Value) -> Option<CoreVersion> {
+    let release = raw_world.get("release");
+    let generation = release.and_then(|r| r.get("generation")).and_then(Value::as_u64);
+    let build = release.and_then(|r| r.get("build")).and_then(Value::as_u64);
+    if let (Some(generation), Some(build)) = (generation, build) {
+        return Some(CoreVersion { generation: generation as u32, build: build as u32 });
+    }
+
+    // Older payloads only have the combined version string, e.g. "11.315"
+    let version = raw_world.get("version")
+        .or(raw_world.get("world").and_then(|w| w.get("coreVersion")))
+        .and_then(Value::as_str)?;
+    let parts = parse_dotted(version);
+    Some(CoreVersion { generation: *parts.first()?, build: parts.get(1).copied().unwrap_or(0) })
+}
+
+fn detect_system(raw_world: &Value) -> Option<SystemVersion> {
+    let system = raw_world.get("system");
+    let world = raw_world.get("world");
+    let id = system.and_then(|s| s.get("id"))
+        .or(world.and_then(|w| w.get("system")))
+        .and_then(Value::as_str)?;
+    let version = system.and_then(|s| s.get("version"))
+        .or(world.and_then(|w| w.get("systemVersion")))
+        .and_then(Value::as_str)?;
+    let parts = parse_dotted(version);
+    Some(SystemVersion {
+        id: id.to_owned(),
+        major: parts.first().copied().unwrap_or(0),
+        minor: parts.get(1).copied().unwrap_or(0),
+        patch: parts.get(2).copied().unwrap_or(0),
+    })
+}
+
+/// Work out which versions the world payload came from, and whether we can read it
+pub fn detect(raw_world: &Value) -> Result<Versions, FoundryClientError> {
+    let core = detect_core(raw_world).ok_or(FoundryClientError::UnknownVersion("core".into()))?;
+    let system = detect_system(raw_world).ok_or(FoundryClientError::UnknownVersion("game system".into()))?;
+
+    let adapter = match (system.id.as_str(), core.generation, system.major) {
+        ("dnd5e", 11..=12, 3) => Adapter::DND5E3,
+        ("dnd5e", 12..=13, 4..=5) => Adapter::DND5E4,
+        _ => return Err(FoundryClientError::Unsupported {
+            core: core.to_string(),
+            system: system.to_string(),
+        }),
+    };
+    Ok(Versions { core, system, adapter })
+}
+
+/// Reshape a raw world so our models can read it regardless of which version produced it
+pub fn adapt_world(versions: &Versions, raw_world: &mut Value) {
+    let table_results = raw_world.get_mut("tables")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|table| table.get_mut("results").and_then(Value::as_array_mut))
+        .flatten();
+    for result in table_results {
+        adapt_table_result(versions.core, result);
+    }
+
+    let actors = raw_world.get_mut("actors").and_then(Value::as_array_mut).into_iter().flatten();
+    for actor in actors {
+        adapt_armor_class(actor);
+    }
+    let token_actors = raw_world.get_mut("scenes")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|scene| scene.get_mut("tokens").and_then(Value::as_array_mut))
+        .flatten()
+        .filter_map(|token| token.get_mut(SYNTHETIC_ACTOR));
+    for actor in token_actors {
+        adapt_armor_class(actor);
+    }
+
+    match versions.adapter {
+        Adapter::DND5E3 => {}
+        Adapter::DND5E4 => {
+            for actor in raw_world.get_mut("actors").and_then(Value::as_array_mut).into_iter().flatten() {
+                adapt_actor_uses_v4(actor);
+            }
+            // Unlinked tokens carry their own copy of their actor
+            let token_actors = raw_world.get_mut("scenes")
+                .and_then(Value::as_array_mut)
+                .into_iter()
+                .flatten()
+                .filter_map(|scene| scene.get_mut("tokens").and_then(Value::as_array_mut))
+                .flatten()
+                .filter_map(|token| token.get_mut(SYNTHETIC_ACTOR));
+            for actor in token_actors {
+                adapt_actor_uses_v4(actor);
+            }
+            // Items outside an actor have no roll data for formulas to refer to
+            for item in raw_world.get_mut("items").and_then(Value::as_array_mut).into_iter().flatten() {
+                adapt_uses_v4(item, &Value::Null);
+            }
+        }
+    }
+}
+
+/// Which armor class calculation an actor uses is only stored once it differs from the default, and actors from
+/// before calculations existed only stored a value. Give every actor the calculation foundry would prepare them with
+fn adapt_armor_class(actor: &mut Value) {
+    let Some(ac) = actor.pointer_mut("/system/attributes/ac").and_then(Value::as_object_mut) else { return };
+    if ac.get("calc").and_then(Value::as_str).is_some_and(|calc| !calc.is_empty()) {
+        return;
+    }
+    let flat = [ac.get("flat"), ac.get("value")].into_iter()
+        .flatten()
+        .find(|value| value.is_number())
+        .cloned();
+    match flat {
+        Some(flat) => {
+            ac.insert("calc".into(), "flat".into());
+            ac.insert("flat".into(), flat);
+        }
+        None => {
+            ac.insert("calc".into(), "default".into());
+        }
+    }
+}
+
+/// Adapt the uses of an actor's items, working out formula maximums from the actor's roll data
+fn adapt_actor_uses_v4(actor: &mut Value) {
+    // Uses are all that 4.x changes here, so the actor reads fine before they are adapted
+    let data = serde_json::from_value::<DND5EActor>(actor.clone())
+        .map(|actor| actor.roll_data())
+        .unwrap_or_default();
+    for item in actor.get_mut("items").and_then(Value::as_array_mut).into_iter().flatten() {
+        adapt_uses_v4(item, &data);
+    }
+}
+
+/// dnd5e 4.x records uses spent, where 3.x recorded uses remaining. Fill in what remains where we can
+fn adapt_uses_v4(item: &mut Value, data: &Value) {
+    let Some(uses) = item.get_mut("system").and_then(|system| system.get_mut("uses")) else { return };
+    let spent = uses.get("spent").and_then(Value::as_i64);
+    let max = uses.get("max").and_then(|max| uses_maximum(max, data));
+    let has_value = uses.get("value").is_some();
+    if let (Some(spent), Some(max), false) = (spent, max, has_value) {
+        uses["value"] = Value::from(max - spent);
+    }
+    // Recovery became a list of rules. The first one's period is the closest to 3.x's single period
+    if uses.get("per").is_none() {
+        if let Some(period) = uses.pointer("/recovery/0/period").cloned() {
+            uses["per"] = period;
+        }
+    }
+}
+
+/// The field, and its value, that records an item having some uses left. 3.x records what remains, 4.x what was spent
+pub fn uses_change(adapter: Adapter, max: i64, remaining: i64) -> (&'static str, i64) {
+    match adapter {
+        Adapter::DND5E3 => ("system.uses.value", remaining),
+        Adapter::DND5E4 => ("system.uses.spent", max - remaining),
+    }
+}
+
+/// Table results changed shape with each core generation. Bring them to the core 12 shape:
+/// core 11 numbered the result types, and core 13 replaced text and document ids with a name, description and uuid
+fn adapt_table_result(core: CoreVersion, result: &mut Value) {
+    let Some(result) = result.as_object_mut() else { return };
+    match core.generation {
+        ..=11 => {
+            let kind = match result.get("type").and_then(Value::as_u64) {
+                Some(1) => "document",
+                Some(2) => "pack",
+                _ => "text",
+            };
+            result.insert("type".into(), kind.into());
+        }
+        12 => {}
+        _ => {
+            let name = result.get("name").and_then(Value::as_str).unwrap_or("").to_owned();
+            let description = result.get("description").and_then(Value::as_str).unwrap_or("").to_owned();
+            // e.g. Item.abc, or Compendium.dnd5e.items.Item.abc
+            let uuid = result.get("documentUuid").and_then(Value::as_str).map(|uuid| uuid.split('.').map(str::to_owned).collect::<Vec<_>>());
+            match uuid.as_deref() {
+                Some([compendium, package, pack, _, id]) if compendium == "Compendium" => {
+                    result.insert("type".into(), "pack".into());
+                    result.insert("documentCollection".into(), format!("{}.{}", package, pack).into());
+                    result.insert("documentId".into(), id.clone().into());
+                    result.insert("text".into(), name.into());
+                }
+                Some([document_type, id]) => {
+                    result.insert("type".into(), "document".into());
+                    result.insert("documentCollection".into(), document_type.clone().into());
+                    result.insert("documentId".into(), id.clone().into());
+                    result.insert("text".into(), name.into());
+                }
+                _ => {
+                    result.insert("type".into(), "text".into());
+                    let text = if description.is_empty() { name } else { description };
+                    result.insert("text".into(), text.into());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn formula_maximums_are_worked_out_from_the_actor() {
+        let ability = |value: i64| json!({"value": value});
+        let mut actor = json!({
+            "type": "character",
+            "_id": "bard",
+            "name": "Bard",
+            "flags": {},
+            "ownership": {"default": 0},
+            "prototypeToken": {},
+            "items": [{
+                "type": "feat",
+                "_id": "inspiration",
+                "name": "Bardic Inspiration",
+                "flags": {},
+                "ownership": {"default": 0},
+                "system": {"uses": {"max": "@abilities.cha.mod", "spent": 1, "recovery": [{"period": "lr"}]}},
+            }],
+            "system": {
+                "attributes": {"ac": {"flat": null, "formula": null}, "hp": {"value": 10, "max": 10}},
+                "abilities": {"str": ability(10), "dex": ability(10), "con": ability(10), "int": ability(10), "wis": ability(10), "cha": ability(16)},
+                "skills": {},
+            },
+        });
+        let mut rolled = actor.clone();
+        adapt_actor_uses_v4(&mut actor);
+        let uses = &actor["items"][0]["system"]["uses"];
+        assert_eq!(uses["value"], json!(2));
+        assert_eq!(uses["per"], json!("lr"));
+
+        // Dice would give a different maximum every time, so only the flat part counts
+        rolled["items"][0]["system"]["uses"]["max"] = json!("1d4 + @abilities.cha.mod");
+        adapt_actor_uses_v4(&mut rolled);
+        assert_eq!(rolled["items"][0]["system"]["uses"]["value"], json!(2));
+    }
+
+    #[test]
+    fn armor_class_calculation_is_filled_in() {
+        let mut legacy = json!({"system": {"attributes": {"ac": {"value": 15}}}});
+        adapt_armor_class(&mut legacy);
+        assert_eq!(legacy["system"]["attributes"]["ac"], json!({"value": 15, "calc": "flat", "flat": 15}));
+
+        let mut unset = json!({"system": {"attributes": {"ac": {"flat": null}}}});
+        adapt_armor_class(&mut unset);
+        assert_eq!(unset["system"]["attributes"]["ac"]["calc"], json!("default"));
+
+        let mut set = json!({"system": {"attributes": {"ac": {"calc": "natural", "flat": 17}}}});
+        adapt_armor_class(&mut set);
+        assert_eq!(set["system"]["attributes"]["ac"]["calc"], json!("natural"));
+    }
+}
@@ -1,25 +1,344 @@
 use std::collections::HashMap;
+use std::ops::Deref;
 use serde_json::Value;
 use serde_repr::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::DeserializeOwned;
 
 #[derive(Serialize, Deserialize)]
+#[serde(bound(deserialize = "ActorType: DeserializeOwned, ItemType: DeserializeOwned, TokenType: DeserializeOwned"))]
 pub struct World<ActorType, ItemType, TokenType> {
     #[serde(rename="activeUsers")]
     pub active_users: Vec<String>,
-    pub actors: Vec<ActorType>,
-    pub items: Vec<ItemType>,
-    pub scenes: Vec<Scene<TokenType>>,
+    pub actors: Documents<ActorType>,
+    pub items: Documents<ItemType>,
+    pub scenes: Documents<Scene<TokenType>>,
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub journal: Documents<JournalEntry>,
+    #[serde(default)]
+    pub packs: Vec<CompendiumPack>,
+    #[serde(default)]
+    pub tables: Documents<RollTable>,
+    #[serde(default)]
+    pub macros: Documents<Macro>,
+}
+
+impl<ActorType, ItemType, TokenType> World<ActorType, ItemType, TokenType> {
+    /// Find a user by their id
+    pub fn user(&self, user_id: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.id == user_id)
+    }
+
+    /// Every document that was skipped because it did not fit our models
+    pub fn incompatibilities(&self) -> Vec<&Incompatibility> where TokenType: TokenActor {
+        self.actors.skipped.iter()
+            .chain(self.items.skipped.iter())
+            .chain(self.scenes.skipped.iter())
+            .chain(self.token_actor_incompatibilities())
+            .chain(self.journal.skipped.iter())
+            .chain(self.tables.skipped.iter())
+            .chain(self.macros.skipped.iter())
+            .collect()
+    }
+
+    /// Actors of unlinked tokens that were skipped, leaving the token without one
+    pub fn token_actor_incompatibilities(&self) -> impl Iterator<Item=&Incompatibility> where TokenType: TokenActor {
+        self.scenes.iter()
+            .flat_map(|scene| scene.tokens.iter())
+            .filter_map(TokenActor::skipped_actor)
+    }
+}
+
+/// Tokens that may carry their own copy of an actor
+pub trait TokenActor {
+    /// The token's actor, if it was skipped because it did not fit our models
+    fn skipped_actor(&self) -> Option<&Incompatibility>;
+}
+
+/// A document that could not be deserialized, and why
+#[derive(Debug, Clone)]
+pub struct Incompatibility {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    /// Where within the document deserialization failed
+    pub path: String,
+    pub error: String,
+}
+
+impl Incompatibility {
+    fn new(value: &Value, err: serde_path_to_error::Error<serde_json::Error>) -> Self {
+        Incompatibility {
+            id: value.get("_id").and_then(Value::as_str).map(str::to_owned),
+            name: value.get("name").and_then(Value::as_str).map(str::to_owned),
+            path: err.path().to_string(),
+            error: err.inner().to_string(),
+        }
+    }
+}
+
+/// A collection of documents, tolerant of individual documents that don't match our models.
+/// Those are skipped and recorded rather than failing the whole collection. Derefs to the documents that did parse
+pub struct Documents<T> {
+    pub valid: Vec<T>,
+    pub skipped: Vec<Incompatibility>,
+}
+
+impl<T> Deref for Documents<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.valid
+    }
+}
+
+impl<T> Default for Documents<T> {
+    fn default() -> Self {
+        Documents { valid: vec![], skipped: vec![] }
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Documents<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Vec::<Value>::deserialize(deserializer)?;
+        let mut documents = Documents::default();
+        for value in raw {
+            match serde_path_to_error::deserialize::<_, T>(&value) {
+                Ok(document) => documents.valid.push(document),
+                Err(err) => documents.skipped.push(Incompatibility::new(&value, err)),
+            }
+        }
+        Ok(documents)
+    }
+}
+
+impl<T: Serialize> Serialize for Documents<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.valid.serialize(serializer)
+    }
+}
+
+/// A document nested within another, such as an unlinked token's actor. If it doesn't match our models it is recorded
+/// as skipped rather than failing the document it's nested in
+#[derive(Default)]
+pub enum Nested<T> {
+    #[default]
+    Absent,
+    Valid(T),
+    Skipped(Incompatibility),
+}
+
+impl<T> Nested<T> {
+    pub fn valid(&self) -> Option<&T> {
+        match self {
+            Nested::Valid(document) => Some(document),
+            _ => None,
+        }
+    }
+
+    pub fn skipped(&self) -> Option<&Incompatibility> {
+        match self {
+            Nested::Skipped(incompatibility) => Some(incompatibility),
+            _ => None,
+        }
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Nested<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        if value.is_null() {
+            return Ok(Nested::Absent);
+        }
+        Ok(match serde_path_to_error::deserialize::<_, T>(&value) {
+            Ok(document) => Nested::Valid(document),
+            Err(err) => Nested::Skipped(Incompatibility::new(&value, err)),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct User {
+    #[serde(rename="_id")]
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub role: UserRole,
+    /// The id of the actor this user has selected as their character
+    pub character: Option<String>,
+}
+
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, PartialOrd, Debug, Default, Clone, Copy)]
+#[repr(u8)]
+pub enum UserRole {
+    #[default]
+    None = 0,
+    Player = 1,
+    Trusted = 2,
+    Assistant = 3,
+    Gamemaster = 4,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Scene<TokenType> {
-    // #[serde(flatten)]
-    // pub document: Document,
+    #[serde(flatten)]
+    pub document: Document,
 
     pub active: bool,
-    pub background: Value,
+    #[serde(default)]
+    pub background: TextureData,
+    /// Size of the map in pixels, not counting padding
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    /// Empty space around the map, as a fraction of its size
+    #[serde(default)]
+    pub padding: f64,
+    #[serde(default)]
+    pub grid: Grid,
     pub tokens: Vec<TokenType>,
+    #[serde(default)]
+    pub walls: Vec<Wall>,
+    #[serde(default)]
+    pub lights: Vec<Light>,
+    #[serde(default)]
+    pub notes: Vec<Note>,
+}
+
+impl<TokenType> Scene<TokenType> {
+    /// Where the map starts on the canvas. Padding is rounded up to whole grid spaces
+    pub fn offset(&self) -> (f64, f64) {
+        let size = self.grid.size.max(1.0);
+        let pad_x = (self.width as f64 * self.padding / size).ceil() * size;
+        let pad_y = (self.height as f64 * self.padding / size).ceil() * size;
+        (pad_x, pad_y)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TextureData {
+    pub src: Option<String>,
+    #[serde(rename="scaleX", default = "one")]
+    pub scale_x: f64,
+    #[serde(rename="scaleY", default = "one")]
+    pub scale_y: f64,
+}
+
+impl Default for TextureData {
+    fn default() -> Self {
+        TextureData { src: None, scale_x: 1.0, scale_y: 1.0 }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Wall {
+    #[serde(rename="_id")]
+    pub id: String,
+    /// The wall's end points, as x0, y0, x1, y1
+    pub c: [f64; 4],
+    /// Whether the wall blocks movement: 0 if not, 20 if it does
+    #[serde(rename="move", default)]
+    pub movement: u8,
+    /// 0 for walls, 1 for doors, 2 for secret doors
+    #[serde(default)]
+    pub door: u8,
+    /// 0 closed, 1 open, 2 locked
+    #[serde(default)]
+    pub ds: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Light {
+    #[serde(rename="_id")]
+    pub id: String,
+    pub x: f64,
+    pub y: f64,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub config: LightConfig,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct LightConfig {
+    /// Radii in grid units
+    #[serde(default)]
+    pub bright: f64,
+    #[serde(default)]
+    pub dim: f64,
+    pub color: Option<String>,
+}
+
+/// A pin on the map linking to a journal entry
+#[derive(Serialize, Deserialize)]
+pub struct Note {
+    #[serde(rename="_id")]
+    pub id: String,
+    pub x: f64,
+    pub y: f64,
+    #[serde(rename="entryId")]
+    pub entry_id: Option<String>,
+    #[serde(rename="pageId")]
+    pub page_id: Option<String>,
+    /// Label shown instead of the entry's name
+    pub text: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Grid {
+    #[serde(rename="type", default)]
+    pub kind: GridType,
+    /// Pixels per grid space
+    #[serde(default = "default_grid_size")]
+    pub size: f64,
+    /// e.g. #000000
+    pub color: Option<String>,
+    #[serde(default)]
+    pub alpha: f64,
+    /// How much distance one grid space represents, in units
+    #[serde(default = "default_grid_distance")]
+    pub distance: f64,
+    #[serde(default = "default_grid_units")]
+    pub units: String,
+}
+
+fn default_grid_distance() -> f64 {
+    5.0
+}
+
+fn default_grid_units() -> String {
+    "ft".into()
+}
+
+fn default_grid_size() -> f64 {
+    100.0
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Grid {
+            kind: GridType::default(),
+            size: default_grid_size(),
+            color: None,
+            alpha: 0.0,
+            distance: default_grid_distance(),
+            units: default_grid_units(),
+        }
+    }
+}
+
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Default, Clone, Copy)]
+#[repr(u8)]
+pub enum GridType {
+    Gridless = 0,
+    #[default]
+    Square = 1,
+    HexOddRows = 2,
+    HexEvenRows = 3,
+    HexOddColumns = 4,
+    HexEvenColumns = 5,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -33,7 +352,7 @@ pub struct Document {
     pub ownership: OwnershipMap,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, PartialOrd, Debug, Clone, Copy)]
 #[repr(i8)]
 pub enum Permissions {
     Inherit = -1,
@@ -52,11 +371,25 @@ pub struct OwnershipMap {
     pub players: HashMap<String, Permissions>
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Flags {
-    /// Capture all miscellaneous flag data
-    #[serde(flatten)]
-    pub items: HashMap<String, Value>
+impl OwnershipMap {
+    /// The effective permission level of a user. Assistant GMs and above implicitly own everything
+    pub fn level(&self, user: &User) -> Permissions {
+        if user.role >= UserRole::Assistant {
+            return Permissions::Owner;
+        }
+        match self.players.get(&user.id) {
+            Some(Permissions::Inherit) | None => self.default,
+            Some(level) => *level,
+        }
+    }
+
+    /// As level, but for embedded documents whose levels may defer to their parent's
+    pub fn level_within(&self, user: &User, parent: &OwnershipMap) -> Permissions {
+        match self.level(user) {
+            Permissions::Inherit => parent.level(user),
+            level => level,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -66,10 +399,33 @@ pub struct BaseActor<ItemType, TokenType> {
 
     pub items:  Vec<ItemType>,
 
+    #[serde(default)]
+    pub effects: Vec<ActiveEffect>,
+
     #[serde(rename="prototypeToken")]
     pub prototype_token: TokenType
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ActiveEffect {
+    #[serde(rename="_id")]
+    pub id: Option<String>,
+    /// Called label before core 11
+    #[serde(default, alias="label")]
+    pub name: Option<String>,
+    /// Status conditions the effect applies, e.g. "concentrating"
+    #[serde(default)]
+    pub statuses: Vec<String>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl ActiveEffect {
+    pub fn applies(&self, status: &str) -> bool {
+        !self.disabled && self.statuses.iter().any(|candidate| candidate == status)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct BaseItem {
     #[serde(flatten)]
@@ -78,6 +434,211 @@ pub struct BaseItem {
 
 #[derive(Serialize, Deserialize)]
 pub struct BaseToken {
+    /// Prototype tokens have no id of their own
+    #[serde(rename="_id")]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub name: String,
+    /// Position of the top left corner on the canvas, in pixels
+    #[serde(default)]
+    pub x: f64,
+    #[serde(default)]
+    pub y: f64,
+    /// Size in grid spaces
+    #[serde(default = "one")]
+    pub width: f64,
+    #[serde(default = "one")]
+    pub height: f64,
+    #[serde(default)]
+    pub elevation: f64,
+    /// Hidden tokens are only shown to GMs
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub disposition: Disposition,
+    #[serde(default)]
+    pub texture: TextureData,
+    #[serde(rename="actorId")]
+    pub actor_id: Option<String>,
+    /// Linked tokens share their actor's data. Unlinked ones have their own copy, built from the actor plus delta
+    #[serde(rename="actorLink", default)]
+    pub actor_link: bool,
+    /// Overrides of the actor's data for unlinked tokens. Core 10 called this actorData
+    #[serde(alias="actorData", default)]
+    pub delta: Value,
+    #[serde(default)]
+    pub bar1: TokenBar,
+    #[serde(default)]
+    pub bar2: TokenBar,
+}
+
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Default, Clone, Copy)]
+#[repr(i8)]
+pub enum Disposition {
+    Secret = -2,
+    #[default]
+    Hostile = -1,
+    Neutral = 0,
+    Friendly = 1,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct TokenBar {
+    /// The actor attribute shown, e.g. attributes.hp
+    pub attribute: Option<String>,
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JournalEntry {
     #[serde(flatten)]
     pub document: Document,
+
+    #[serde(default)]
+    pub pages: Vec<JournalEntryPage>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JournalEntryPage {
+    #[serde(rename="_id")]
+    pub id: String,
+    pub name: String,
+    pub ownership: OwnershipMap,
+    /// Pages are displayed in ascending order of this
+    #[serde(default)]
+    pub sort: i64,
+    #[serde(flatten)]
+    pub content: PageContent,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[allow(non_camel_case_types)]
+pub enum PageContent {
+    text {
+        text: PageText,
+    },
+    image {
+        src: Option<String>,
+        #[serde(default)]
+        image: PageImage,
+    },
+    pdf {
+        src: Option<String>,
+    },
+    video {
+        src: Option<String>,
+    },
+    /// Pages added by modules or systems, e.g. dnd5e's class summaries
+    #[serde(other)]
+    other,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PageText {
+    /// The page as html
+    pub content: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PageImage {
+    pub caption: Option<String>,
+}
+
+/// Metadata describing a compendium pack available in the world
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CompendiumPack {
+    /// Foundry builds this from the package and pack names when it isn't given
+    pub id: Option<String>,
+    pub name: String,
+    pub label: String,
+    /// The type of document within, e.g. Item or Actor
+    #[serde(rename="type")]
+    pub document_type: String,
+    #[serde(rename="packageName")]
+    pub package_name: Option<String>,
+}
+
+impl CompendiumPack {
+    /// The collection id used to address this pack, e.g. dnd5e.spells
+    pub fn collection(&self) -> String {
+        match (&self.id, &self.package_name) {
+            (Some(id), _) => id.clone(),
+            (None, Some(package)) => format!("{}.{}", package, self.name),
+            (None, None) => self.name.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RollTable {
+    #[serde(flatten)]
+    pub document: Document,
+
+    pub description: Option<String>,
+    /// Rolled to pick results. Empty means one die covering every result
+    #[serde(default)]
+    pub formula: String,
+    /// Whether results may be drawn again. If not, drawn results are marked and skipped until the table is reset
+    #[serde(default = "yes")]
+    pub replacement: bool,
+    #[serde(default)]
+    pub results: Vec<TableResult>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TableResult {
+    #[serde(rename="_id")]
+    pub id: String,
+    #[serde(rename="type")]
+    pub kind: ResultType,
+    /// The result's text, or for document results the document's name
+    #[serde(default)]
+    pub text: String,
+    pub img: Option<String>,
+    /// For document results the document type, e.g. Item. For pack results the pack's collection id
+    #[serde(rename="documentCollection")]
+    pub document_collection: Option<String>,
+    #[serde(rename="documentId")]
+    pub document_id: Option<String>,
+    /// The lowest and highest rolls that give this result
+    pub range: [i64; 2],
+    #[serde(default)]
+    pub drawn: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all="lowercase")]
+pub enum ResultType {
+    Text,
+    /// A document in the world
+    Document,
+    /// A document in a compendium pack
+    Pack,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Macro {
+    #[serde(flatten)]
+    pub document: Document,
+
+    #[serde(rename="type")]
+    pub kind: MacroType,
+    /// Chat text for chat macros, javascript for script macros
+    #[serde(default)]
+    pub command: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all="lowercase")]
+pub enum MacroType {
+    Chat,
+    Script,
 }